@@ -7,6 +7,7 @@ fn main() -> Result<(), Box<dyn Error>> {
         "proto/log.proto",
         "proto/span.proto",
         "proto/process.proto",
+        "proto/peer.proto",
     ];
 
     let dirs = &["proto"];
@@ -14,7 +15,7 @@ fn main() -> Result<(), Box<dyn Error>> {
         .build_client(true)
         .build_server(true)
         .protoc_arg("--experimental_allow_proto3_optional")
-        .compile(proto_files, dirs)?;
+        .compile_protos(proto_files, dirs)?;
 
     // recompile protobufs only if any of the proto files changes.
     for file in proto_files {
@@ -14,6 +14,7 @@ impl Value {
                 ValueEnum::U64Val(_) => "u64",
                 ValueEnum::I64Val(_) => "i64",
                 ValueEnum::BoolVal(_) => "bool",
+                ValueEnum::F64Val(_) => "f64",
             }
         } else {
             ""
@@ -29,6 +30,7 @@ impl Display for Value {
                 ValueEnum::U64Val(v) => write!(f, "{v}"),
                 ValueEnum::I64Val(v) => write!(f, "{v}"),
                 ValueEnum::BoolVal(v) => write!(f, "{v}"),
+                ValueEnum::F64Val(v) => write!(f, "{v}"),
             }
         } else {
             write!(f, "")
@@ -89,6 +91,12 @@ impl From<u64> for Value {
     }
 }
 
+impl From<f64> for Value {
+    fn from(val: f64) -> Self {
+        value::Inner::F64Val(val).into()
+    }
+}
+
 impl From<bool> for Value {
     fn from(val: bool) -> Self {
         value::Inner::BoolVal(val).into()
@@ -121,6 +129,9 @@ impl From<Value> for JsonValue {
                 ValueEnum::U64Val(v) => JsonValue::Number(Number::from(v)),
                 ValueEnum::I64Val(v) => JsonValue::Number(Number::from(v)),
                 ValueEnum::BoolVal(v) => JsonValue::Bool(v),
+                ValueEnum::F64Val(v) => Number::from_f64(v)
+                    .map(JsonValue::Number)
+                    .unwrap_or(JsonValue::Null),
             }
         } else {
             JsonValue::Null
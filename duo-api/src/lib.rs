@@ -1,6 +1,7 @@
 pub mod common;
 pub mod instrument;
 pub mod log;
+pub mod peer;
 pub mod process;
 pub mod span;
 
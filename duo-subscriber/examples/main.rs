@@ -1,5 +1,3 @@
-use std::time::Duration;
-
 use duo_subscriber::DuoLayer;
 use tonic::transport::Uri;
 use tracing::{debug, error, info, warn, Level};
@@ -35,7 +33,13 @@ fn foz() {
 async fn main() {
     let fmt_layer = fmt::layer();
     let uri = Uri::from_static("http://127.0.0.1:6000");
-    let duo_layer = DuoLayer::new("example", uri).await;
+    let (duo_layer, guard) = DuoLayer::builder()
+        .service_name("example")
+        .uri(uri)
+        .version(env!("CARGO_PKG_VERSION"))
+        .environment("development")
+        .build()
+        .unwrap();
     tracing_subscriber::registry()
         .with(fmt_layer)
         .with(duo_layer)
@@ -48,5 +52,6 @@ async fn main() {
 
     tracing::info!("Bootstrap...");
     foo();
-    tokio::time::sleep(Duration::from_secs(1)).await;
+    // Send the remaining spans and events before exiting.
+    guard.shutdown().await;
 }
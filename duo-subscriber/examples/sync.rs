@@ -0,0 +1,33 @@
+// The layer runs on its own thread, no async runtime is required.
+
+use duo_subscriber::DuoLayer;
+use tonic::transport::Uri;
+use tracing::{debug, info, Level};
+use tracing_subscriber::{self, layer::SubscriberExt, util::SubscriberInitExt};
+
+#[tracing::instrument]
+fn foo() {
+    info!("hello foo!");
+    bar();
+}
+
+#[tracing::instrument]
+fn bar() {
+    debug!("hello bar!");
+}
+
+fn main() {
+    let uri = Uri::from_static("http://127.0.0.1:6000");
+    let (duo_layer, guard) = DuoLayer::builder()
+        .service_name("sync")
+        .uri(uri)
+        .max_level(Level::DEBUG)
+        .build_with_thread()
+        .unwrap();
+    tracing_subscriber::registry().with(duo_layer).init();
+
+    info!("Bootstrap...");
+    foo();
+    // Send the remaining spans and events before exiting.
+    guard.shutdown_blocking();
+}
@@ -0,0 +1,448 @@
+use std::{collections::HashMap, fmt, io, path::PathBuf, sync::Arc, thread, time::Duration};
+
+use tokio::task::JoinHandle;
+use tonic::transport::Uri;
+#[cfg(feature = "tls")]
+use tonic::transport::{Certificate, ClientTlsConfig, Identity};
+use tracing::Level;
+use tracing_subscriber::filter::{LevelFilter, Targets};
+
+use crate::{
+    client::Auth,
+    conn::ConnectOptions,
+    guard::{DuoGuard, Task, DEFAULT_SHUTDOWN_TIMEOUT},
+    proto,
+    queue::{DropPolicy, MessageQueue, DEFAULT_BUFFER_SIZE},
+    spool::{Spool, DEFAULT_SPOOL_LIMIT},
+    worker::Worker,
+    DuoLayer,
+};
+
+/// The default maximum messages sent in one request.
+pub const DEFAULT_BATCH_SIZE: usize = 64;
+/// The default interval to flush a partial batch.
+pub const DEFAULT_FLUSH_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Errors occurred when building the [`DuoLayer`].
+#[derive(Debug)]
+pub enum BuildError {
+    /// Open the spool file failed.
+    Spool(io::Error),
+    /// The auth token isn't a valid header value.
+    InvalidToken,
+    /// The tenant isn't a valid header value.
+    InvalidTenant,
+    /// Start the background thread or its runtime failed.
+    Thread(io::Error),
+    /// Read the TLS certificate files failed.
+    Tls(io::Error),
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildError::Spool(err) => write!(f, "open spool failed: {}", err),
+            BuildError::InvalidToken => write!(f, "invalid auth token"),
+            BuildError::InvalidTenant => write!(f, "invalid tenant"),
+            BuildError::Thread(err) => write!(f, "start background thread failed: {}", err),
+            BuildError::Tls(err) => write!(f, "read TLS certificate failed: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for BuildError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BuildError::Spool(err) | BuildError::Thread(err) | BuildError::Tls(err) => Some(err),
+            BuildError::InvalidToken | BuildError::InvalidTenant => None,
+        }
+    }
+}
+
+/// Configure and build a [`DuoLayer`].
+///
+/// ```no_run
+/// # async fn run() -> Result<(), duo_subscriber::BuildError> {
+/// use duo_subscriber::DuoLayer;
+/// use tracing::Level;
+///
+/// let (duo_layer, guard) = DuoLayer::builder()
+///     .service_name("example")
+///     .uri("http://127.0.0.1:6000".parse().unwrap())
+///     .version(env!("CARGO_PKG_VERSION"))
+///     .environment("staging")
+///     .max_level(Level::INFO)
+///     .with_target("hyper", Level::WARN)
+///     .build()?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct DuoLayerBuilder {
+    service_name: String,
+    uri: Uri,
+    tags: HashMap<String, proto::Value>,
+    filter: Targets,
+    buffer_size: usize,
+    drop_policy: DropPolicy,
+    batch_size: usize,
+    flush_interval: Duration,
+    sample_rate: f64,
+    spool: Option<PathBuf>,
+    spool_limit: u64,
+    auth_token: Option<String>,
+    tenant: Option<String>,
+    shutdown_timeout: Duration,
+    #[cfg(feature = "tls")]
+    tls: Option<ClientTlsConfig>,
+    #[cfg(feature = "tls")]
+    tls_ca: Option<PathBuf>,
+    #[cfg(feature = "tls")]
+    tls_identity: Option<(PathBuf, PathBuf)>,
+}
+
+impl Default for DuoLayerBuilder {
+    fn default() -> Self {
+        DuoLayerBuilder {
+            service_name: default_service_name(),
+            uri: Uri::from_static("http://127.0.0.1:6000"),
+            tags: crate::grasp_process_info(),
+            filter: Targets::new().with_default(LevelFilter::TRACE),
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            drop_policy: DropPolicy::default(),
+            batch_size: DEFAULT_BATCH_SIZE,
+            flush_interval: DEFAULT_FLUSH_INTERVAL,
+            sample_rate: 1.0,
+            spool: None,
+            spool_limit: DEFAULT_SPOOL_LIMIT,
+            auth_token: None,
+            tenant: None,
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            #[cfg(feature = "tls")]
+            tls: None,
+            #[cfg(feature = "tls")]
+            tls_ca: None,
+            #[cfg(feature = "tls")]
+            tls_identity: None,
+        }
+    }
+}
+
+impl DuoLayerBuilder {
+    /// The service name of this process, defaults to the executable name.
+    pub fn service_name(self, name: impl Into<String>) -> Self {
+        Self {
+            service_name: name.into(),
+            ..self
+        }
+    }
+
+    /// The gRPC address of duo server, defaults to `http://127.0.0.1:6000`.
+    pub fn uri(self, uri: Uri) -> Self {
+        Self { uri, ..self }
+    }
+
+    /// Add a process tag.
+    pub fn tag(mut self, key: impl Into<String>, value: impl Into<proto::Value>) -> Self {
+        self.tags.insert(key.into(), value.into());
+        self
+    }
+
+    fn str_tag(self, key: &str, value: String) -> Self {
+        self.tag(key, value)
+    }
+
+    /// The version of the service.
+    pub fn version(self, version: impl Into<String>) -> Self {
+        self.str_tag("version", version.into())
+    }
+
+    /// The deployment environment, e.g. `production`.
+    pub fn environment(self, environment: impl Into<String>) -> Self {
+        self.str_tag("environment", environment.into())
+    }
+
+    /// Override the hostname detected from the `HOSTNAME` variable.
+    pub fn hostname(self, hostname: impl Into<String>) -> Self {
+        self.str_tag("hostname", hostname.into())
+    }
+
+    /// Override the pod name detected from the `POD_NAME` variable.
+    pub fn pod(self, pod: impl Into<String>) -> Self {
+        self.str_tag("pod", pod.into())
+    }
+
+    /// The most verbose level shipped to the server, defaults to `TRACE`.
+    pub fn max_level(self, level: Level) -> Self {
+        Self {
+            filter: self.filter.with_default(level),
+            ..self
+        }
+    }
+
+    /// The most verbose level shipped for spans and events
+    /// whose target starts with `target`.
+    pub fn with_target(self, target: impl Into<String>, level: impl Into<LevelFilter>) -> Self {
+        Self {
+            filter: self.filter.with_target(target, level),
+            ..self
+        }
+    }
+
+    /// The capacity of the queue between the layer and the background task.
+    pub fn buffer_size(self, buffer_size: usize) -> Self {
+        Self {
+            buffer_size,
+            ..self
+        }
+    }
+
+    /// What to do when the queue is full.
+    pub fn drop_policy(self, drop_policy: DropPolicy) -> Self {
+        Self {
+            drop_policy,
+            ..self
+        }
+    }
+
+    /// The maximum spans and events sent in one request.
+    pub fn batch_size(self, batch_size: usize) -> Self {
+        Self {
+            batch_size: batch_size.max(1),
+            ..self
+        }
+    }
+
+    /// How long a partial batch waits before being sent.
+    pub fn flush_interval(self, flush_interval: Duration) -> Self {
+        Self {
+            flush_interval: flush_interval.max(Duration::from_millis(1)),
+            ..self
+        }
+    }
+
+    /// The probability to keep a trace, decided at its root span.
+    /// Defaults to `1.0` which keeps everything.
+    pub fn sample_rate(self, sample_rate: f64) -> Self {
+        Self {
+            sample_rate: sample_rate.clamp(0.0, 1.0),
+            ..self
+        }
+    }
+
+    /// Spool messages to the file while the server is unreachable,
+    /// they are replayed in order once reconnected, even after a restart.
+    pub fn spool(self, path: impl Into<PathBuf>) -> Self {
+        Self {
+            spool: Some(path.into()),
+            ..self
+        }
+    }
+
+    /// The size limit of the spool file in bytes.
+    pub fn spool_limit(self, spool_limit: u64) -> Self {
+        Self {
+            spool_limit,
+            ..self
+        }
+    }
+
+    /// Send the token as `authorization: Bearer <token>` in every request.
+    pub fn auth_token(self, token: impl Into<String>) -> Self {
+        Self {
+            auth_token: Some(token.into()),
+            ..self
+        }
+    }
+
+    /// Send the spans and events to the tenant, instead of the default one.
+    pub fn tenant(self, tenant: impl Into<String>) -> Self {
+        Self {
+            tenant: Some(tenant.into()),
+            ..self
+        }
+    }
+
+    /// How long the [`DuoGuard`] waits for the queued messages
+    /// to be sent on shutdown.
+    pub fn shutdown_timeout(self, shutdown_timeout: Duration) -> Self {
+        Self {
+            shutdown_timeout,
+            ..self
+        }
+    }
+
+    /// Connect to the server over TLS.
+    ///
+    /// TLS is also enabled for `https` URIs, trusting the root certificates
+    /// of the `tls-roots` or `tls-webpki-roots` feature.
+    #[cfg(feature = "tls")]
+    pub fn tls_config(self, tls: ClientTlsConfig) -> Self {
+        Self {
+            tls: Some(tls),
+            ..self
+        }
+    }
+
+    /// Trust the server certificates signed by the PEM encoded CA.
+    #[cfg(feature = "tls")]
+    pub fn tls_ca_file(self, path: impl Into<PathBuf>) -> Self {
+        Self {
+            tls_ca: Some(path.into()),
+            ..self
+        }
+    }
+
+    /// Present the PEM encoded client certificate and key,
+    /// for the server which verifies clients (mTLS).
+    #[cfg(feature = "tls")]
+    pub fn tls_identity_files(self, cert: impl Into<PathBuf>, key: impl Into<PathBuf>) -> Self {
+        Self {
+            tls_identity: Some((cert.into(), key.into())),
+            ..self
+        }
+    }
+
+    /// Build the layer and spawn the background task,
+    /// this must be called within a tokio runtime.
+    pub fn build(mut self) -> Result<(DuoLayer, DuoGuard), BuildError> {
+        let (auth, spool) = self.prepare()?;
+        let shutdown_timeout = self.shutdown_timeout;
+        let (layer, task) = self.spawn(auth, spool);
+        let guard = DuoGuard::new(
+            Arc::clone(&layer.queue),
+            Task::Tokio(task),
+            shutdown_timeout,
+        );
+        Ok((layer, guard))
+    }
+
+    /// Build the layer and run the background task on a dedicated thread
+    /// with its own runtime.
+    ///
+    /// Unlike [`build`](Self::build), this doesn't require a tokio runtime,
+    /// so the layer can be installed at the start of `main` before any runtime
+    /// exists, or in programs which are synchronous or use other runtimes.
+    pub fn build_with_thread(mut self) -> Result<(DuoLayer, DuoGuard), BuildError> {
+        let (auth, spool) = self.prepare()?;
+        let shutdown_timeout = self.shutdown_timeout;
+        let (layer, worker) = self.into_parts(auth, spool);
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(BuildError::Thread)?;
+        let thread = thread::Builder::new()
+            .name(String::from("duo-subscriber"))
+            .spawn(move || runtime.block_on(worker.run()))
+            .map_err(BuildError::Thread)?;
+        let guard = DuoGuard::new(
+            Arc::clone(&layer.queue),
+            Task::Thread(thread),
+            shutdown_timeout,
+        );
+        Ok((layer, guard))
+    }
+
+    fn prepare(&mut self) -> Result<(Auth, Option<Spool>), BuildError> {
+        #[cfg(feature = "tls")]
+        {
+            self.tls = self.resolve_tls()?;
+        }
+        let token = match &self.auth_token {
+            Some(token) => Some(
+                format!("Bearer {token}")
+                    .parse()
+                    .map_err(|_| BuildError::InvalidToken)?,
+            ),
+            None => None,
+        };
+        let tenant = match &self.tenant {
+            Some(tenant) => Some(tenant.parse().map_err(|_| BuildError::InvalidTenant)?),
+            None => None,
+        };
+        let spool = match &self.spool {
+            Some(path) => {
+                Some(Spool::open(path.clone(), self.spool_limit).map_err(BuildError::Spool)?)
+            }
+            None => None,
+        };
+        Ok((Auth { token, tenant }, spool))
+    }
+
+    #[cfg(feature = "tls")]
+    fn resolve_tls(&self) -> Result<Option<ClientTlsConfig>, BuildError> {
+        let https = self.uri.scheme_str() == Some("https");
+        if self.tls.is_none() && self.tls_ca.is_none() && self.tls_identity.is_none() && !https {
+            return Ok(None);
+        }
+
+        let read = |path: &PathBuf| std::fs::read(path).map_err(BuildError::Tls);
+        let mut tls = self
+            .tls
+            .clone()
+            .unwrap_or_else(|| ClientTlsConfig::new().with_enabled_roots());
+        if let Some(ca) = &self.tls_ca {
+            tls = tls.ca_certificate(Certificate::from_pem(read(ca)?));
+        }
+        if let Some((cert, key)) = &self.tls_identity {
+            tls = tls.identity(Identity::from_pem(read(cert)?, read(key)?));
+        }
+        Ok(Some(tls))
+    }
+
+    pub(crate) fn spawn(self, auth: Auth, spool: Option<Spool>) -> (DuoLayer, JoinHandle<()>) {
+        let (layer, worker) = self.into_parts(auth, spool);
+        (layer, tokio::spawn(worker.run()))
+    }
+
+    fn into_parts(self, auth: Auth, spool: Option<Spool>) -> (DuoLayer, Worker) {
+        let queue = Arc::new(MessageQueue::new(self.buffer_size, self.drop_policy));
+        let options = ConnectOptions {
+            uri: self.uri,
+            auth,
+            #[cfg(feature = "tls")]
+            tls: self.tls,
+        };
+        let process = proto::Process {
+            name: self.service_name,
+            tags: self.tags,
+        };
+        let worker = Worker::new(
+            process,
+            options,
+            Arc::clone(&queue),
+            spool,
+            self.batch_size,
+            self.flush_interval,
+        );
+        let layer = DuoLayer {
+            queue,
+            filter: self.filter,
+            sample_rate: self.sample_rate,
+        };
+        (layer, worker)
+    }
+}
+
+fn default_service_name() -> String {
+    std::env::current_exe()
+        .ok()
+        .and_then(|path| Some(path.file_stem()?.to_string_lossy().into_owned()))
+        .unwrap_or_else(|| String::from("unknown_service"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_with_thread_without_runtime() {
+        assert!(tokio::runtime::Handle::try_current().is_err());
+        let (_layer, guard) = DuoLayerBuilder::default()
+            .uri(Uri::from_static("http://127.0.0.1:1"))
+            .shutdown_timeout(Duration::from_secs(10))
+            .build_with_thread()
+            .unwrap();
+        // Nothing queued, the thread exits though the server is unreachable.
+        assert!(guard.shutdown_blocking());
+    }
+}
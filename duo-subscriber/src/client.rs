@@ -1,54 +1,184 @@
 use duo_api as proto;
 use proto::instrument::{
-    instrument_client::InstrumentClient, RecordEventRequest, RecordSpanRequest,
-    RegisterProcessRequest,
+    instrument_client::InstrumentClient, HeartbeatRequest, RecordBatchRequest, RecordEventRequest,
+    RecordSpanRequest, RegisterProcessRequest,
 };
 use proto::process::Process;
-use tonic::{transport::Channel, Request};
+use tonic::{
+    metadata::{Ascii, MetadataValue},
+    service::{interceptor::InterceptedService, Interceptor},
+    transport::Channel,
+    Code, Request, Status,
+};
+
+use crate::queue::Message;
+
+/// Attach the bearer token and the tenant to every request.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Auth {
+    pub(crate) token: Option<MetadataValue<Ascii>>,
+    pub(crate) tenant: Option<MetadataValue<Ascii>>,
+}
+
+impl Interceptor for Auth {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        if let Some(token) = &self.token {
+            request
+                .metadata_mut()
+                .insert("authorization", token.clone());
+        }
+        if let Some(tenant) = &self.tenant {
+            request
+                .metadata_mut()
+                .insert("x-duo-tenant", tenant.clone());
+        }
+        Ok(request)
+    }
+}
+
+/// A batch failed part way: the first `sent` messages were accepted,
+/// the next `rejected` ones won't be, and the rest were not sent.
+#[derive(Debug)]
+pub(crate) struct BatchError {
+    pub(crate) sent: usize,
+    pub(crate) rejected: usize,
+    pub(crate) status: Status,
+}
 
 pub struct DuoClient {
-    name: &'static str,
+    process: Process,
     process_id: String,
-    inner: InstrumentClient<Channel>,
+    // The server predates `record_batch`, send the messages one by one.
+    unbatched: bool,
+    inner: InstrumentClient<InterceptedService<Channel, Auth>>,
 }
 
 impl DuoClient {
+    /// The `process_id` of the previous registration is kept if the server
+    /// still knows it, empty to register a new process.
     #[must_use]
-    pub fn new(name: &'static str, client: InstrumentClient<Channel>) -> DuoClient {
+    pub(crate) fn new(
+        process: Process,
+        process_id: String,
+        client: InstrumentClient<InterceptedService<Channel, Auth>>,
+    ) -> DuoClient {
         DuoClient {
-            name,
-            process_id: String::new(),
+            process,
+            process_id,
+            unbatched: false,
             inner: client,
         }
     }
 
-    pub(crate) async fn registry_process(&mut self) {
+    pub(crate) async fn registry_process(&mut self) -> Result<(), Status> {
         let response = self
             .inner
             .register_process(Request::new(RegisterProcessRequest {
-                process: Some(Process {
-                    name: String::from(self.name),
-                    tags: super::grasp_process_info(),
-                }),
+                process: Some(self.process.clone()),
+                process_id: self.process_id.clone(),
             }))
-            .await
-            .unwrap();
+            .await?;
         self.process_id = response.into_inner().process_id;
+        Ok(())
     }
 
-    pub async fn record_span(&mut self, mut span: proto::Span) {
-        span.process_id = self.process_id.clone();
-        self.inner
-            .record_span(Request::new(RecordSpanRequest { span: Some(span) }))
-            .await
-            .unwrap();
+    pub(crate) fn process_id(&self) -> &str {
+        &self.process_id
     }
 
-    pub async fn record_event(&mut self, mut log: proto::Log) {
-        log.process_id = self.process_id.clone();
+    /// Tell the server the process is alive, or stopped.
+    pub(crate) async fn heartbeat(&mut self, stopped: bool) -> Result<(), Status> {
         self.inner
-            .record_event(Request::new(RecordEventRequest { log: Some(log) }))
-            .await
-            .unwrap();
+            .heartbeat(Request::new(HeartbeatRequest {
+                process_id: self.process_id.clone(),
+                stopped,
+            }))
+            .await?;
+        Ok(())
+    }
+
+    /// Send the messages in one request. The messages recorded before
+    /// a reconnection keep the process id they were recorded with.
+    pub(crate) async fn record_batch<'a>(
+        &mut self,
+        messages: impl Iterator<Item = &'a Message>,
+    ) -> Result<(), BatchError> {
+        let messages = messages
+            .map(|message| {
+                let mut message = message.clone();
+                message.stamp(&self.process_id);
+                message
+            })
+            .collect::<Vec<_>>();
+        if !self.unbatched {
+            let mut request = RecordBatchRequest::default();
+            for message in &messages {
+                match message {
+                    Message::NewSpan(span) | Message::CloseSpan(span) => {
+                        request.spans.push(span.clone())
+                    }
+                    Message::Event(log) => request.logs.push(log.clone()),
+                }
+            }
+            match self.inner.record_batch(Request::new(request)).await {
+                Ok(_) => return Ok(()),
+                Err(status) if status.code() == Code::Unimplemented => {
+                    tracing::warn!(%status, "server without batches, send messages one by one");
+                    self.unbatched = true;
+                }
+                Err(status) => {
+                    let rejected = if is_retryable(&status) {
+                        0
+                    } else {
+                        messages.len()
+                    };
+                    return Err(BatchError {
+                        sent: 0,
+                        rejected,
+                        status,
+                    });
+                }
+            }
+        }
+
+        // Stop at the first failure, only the failed message is rejected.
+        for (sent, message) in messages.into_iter().enumerate() {
+            let result = match message {
+                Message::NewSpan(span) | Message::CloseSpan(span) => self
+                    .inner
+                    .record_span(Request::new(RecordSpanRequest { span: Some(span) }))
+                    .await
+                    .map(drop),
+                Message::Event(log) => self
+                    .inner
+                    .record_event(Request::new(RecordEventRequest { log: Some(log) }))
+                    .await
+                    .map(drop),
+            };
+            if let Err(status) = result {
+                let rejected = usize::from(!is_retryable(&status));
+                return Err(BatchError {
+                    sent,
+                    rejected,
+                    status,
+                });
+            }
+        }
+        Ok(())
     }
 }
+
+/// Whether the failed request is worth to retry after reconnecting,
+/// otherwise the server would never accept it.
+pub(crate) fn is_retryable(status: &Status) -> bool {
+    matches!(
+        status.code(),
+        Code::Unavailable
+            | Code::Unknown
+            | Code::Cancelled
+            | Code::DeadlineExceeded
+            | Code::ResourceExhausted
+            | Code::Aborted
+            | Code::Internal
+    )
+}
@@ -1,45 +1,107 @@
 use std::time::Duration;
 
-use duo_api::instrument::instrument_client::InstrumentClient;
-use tonic::transport::Uri;
+use duo_api::{instrument::instrument_client::InstrumentClient, Process};
+#[cfg(feature = "tls")]
+use tonic::transport::ClientTlsConfig;
+use tonic::transport::{Endpoint, Uri};
 
-use crate::client::DuoClient;
+use crate::client::{Auth, DuoClient};
+
+/// How to reach the server.
+#[derive(Debug, Clone)]
+pub(crate) struct ConnectOptions {
+    pub(crate) uri: Uri,
+    pub(crate) auth: Auth,
+    #[cfg(feature = "tls")]
+    pub(crate) tls: Option<ClientTlsConfig>,
+}
 
 pub struct Connection;
 
 impl Connection {
+    const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Connect to the server and register the process, pass the id of
+    /// the previous registration to keep it across reconnections.
+    pub(crate) async fn connect(
+        process: &Process,
+        process_id: &str,
+        options: &ConnectOptions,
+    ) -> Result<DuoClient, String> {
+        tracing::debug!(to = %options.uri, "connecting");
+        #[allow(unused_mut)]
+        let mut endpoint =
+            Endpoint::from(options.uri.clone()).connect_timeout(Self::CONNECT_TIMEOUT);
+        #[cfg(feature = "tls")]
+        if let Some(tls) = &options.tls {
+            endpoint = endpoint
+                .tls_config(tls.clone())
+                .map_err(|err| format!("Invalid TLS config: {}", err))?;
+        }
+        let channel = endpoint
+            .connect()
+            .await
+            .map_err(|err| format!("InstrumentClient connect error: {}", err))?;
+
+        let mut client = DuoClient::new(
+            process.clone(),
+            process_id.to_string(),
+            InstrumentClient::with_interceptor(channel, options.auth.clone()),
+        );
+        client
+            .registry_process()
+            .await
+            .map_err(|status| format!("Register process error: {}", status))?;
+        tracing::debug!("connected successfully!");
+        Ok(client)
+    }
+}
+
+/// Bounded exponential backoff between reconnections.
+#[derive(Debug, Default)]
+pub(crate) struct Backoff {
+    current: Duration,
+}
+
+impl Backoff {
     const BACKOFF: Duration = Duration::from_millis(500);
     const MAX_BACKOFF: Duration = Duration::from_secs(5);
 
-    pub async fn connect(name: &'static str, uri: Uri) -> DuoClient {
-        let mut backoff = Duration::from_secs(0);
-        loop {
-            if backoff == Duration::from_secs(0) {
-                tracing::debug!(to = %uri, "connecting");
-            } else {
-                tracing::debug!(reconnect_in = ?backoff, "reconnecting");
-                tokio::time::sleep(backoff).await;
-            }
-
-            let try_connect = async {
-                let client = InstrumentClient::connect(uri.clone())
-                    .await
-                    .map_err(|err| format!("InstrumentClient connect error: {}", err))?;
-                Ok::<InstrumentClient<_>, String>(client)
-            };
-
-            match try_connect.await {
-                Ok(connected_client) => {
-                    tracing::debug!("connected successfully!");
-                    let mut client = DuoClient::new(name, connected_client);
-                    client.registry_process().await;
-                    return client;
-                }
-                Err(error) => {
-                    tracing::warn!(%error, "error connecting");
-                    backoff = std::cmp::max(backoff + Self::BACKOFF, Self::MAX_BACKOFF);
-                }
-            };
-        }
+    /// The delay before the next attempt, zero for the first one.
+    pub(crate) fn delay(&self) -> Duration {
+        self.current
+    }
+
+    pub(crate) fn increase(&mut self) {
+        self.current = std::cmp::min(
+            std::cmp::max(self.current * 2, Self::BACKOFF),
+            Self::MAX_BACKOFF,
+        );
+    }
+
+    pub(crate) fn reset(&mut self) {
+        self.current = Duration::ZERO;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::Backoff;
+
+    #[test]
+    fn test_backoff_is_bounded() {
+        let mut backoff = Backoff::default();
+        assert_eq!(backoff.delay(), Duration::ZERO);
+        let delays = (0..6)
+            .map(|_| {
+                backoff.increase();
+                backoff.delay().as_millis()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(delays, vec![500, 1000, 2000, 4000, 5000, 5000]);
+        backoff.reset();
+        assert_eq!(backoff.delay(), Duration::ZERO);
     }
 }
@@ -0,0 +1,179 @@
+use std::{sync::Arc, thread, time::Duration};
+
+use tokio::{
+    runtime::{Handle, RuntimeFlavor},
+    task::JoinHandle,
+};
+
+use crate::queue::{DroppedStats, MessageQueue};
+
+/// The default time to wait for the queued messages to be sent on shutdown.
+pub const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Where the background task runs.
+pub(crate) enum Task {
+    /// Spawned on the caller's tokio runtime.
+    Tokio(JoinHandle<()>),
+    /// A dedicated thread with its own runtime.
+    Thread(thread::JoinHandle<()>),
+}
+
+/// Flush the queued spans and events of a [`DuoLayer`](crate::DuoLayer)
+/// when the process exits.
+///
+/// On drop or [`shutdown`](DuoGuard::shutdown), the layer stops accepting new
+/// messages, and the background task drains the queue to the server within
+/// the shutdown timeout. Keep the guard alive until the end of `main`.
+///
+/// Dropping the guard blocks the current thread. If the layer was built with
+/// [`build`](crate::DuoLayerBuilder::build) in a `current_thread` runtime, the
+/// background task can't make progress meanwhile, call `shutdown().await`
+/// there instead.
+#[must_use = "dropping the guard shuts down the layer immediately"]
+pub struct DuoGuard {
+    queue: Arc<MessageQueue>,
+    task: Option<Task>,
+    timeout: Duration,
+}
+
+impl DuoGuard {
+    pub(crate) fn new(queue: Arc<MessageQueue>, task: Task, timeout: Duration) -> Self {
+        DuoGuard {
+            queue,
+            task: Some(task),
+            timeout,
+        }
+    }
+
+    /// The number of spans and events dropped because the
+    /// buffer was full or the layer was shut down.
+    pub fn dropped(&self) -> DroppedStats {
+        self.queue.dropped()
+    }
+
+    /// Stop accepting new spans and events, and wait for the
+    /// background task to send the queued ones.
+    ///
+    /// Return `false` if the timeout elapsed before all messages were sent.
+    pub async fn shutdown(mut self) -> bool {
+        self.queue.close();
+        match self.task.take() {
+            Some(Task::Tokio(mut task)) => {
+                match tokio::time::timeout(self.timeout, &mut task).await {
+                    Ok(_) => true,
+                    Err(_) => {
+                        task.abort();
+                        false
+                    }
+                }
+            }
+            // The thread doesn't depend on the current runtime, just wait.
+            Some(task @ Task::Thread(_)) => self.wait(task),
+            None => true,
+        }
+    }
+
+    /// The blocking version of [`shutdown`](Self::shutdown),
+    /// for the programs without an async runtime.
+    pub fn shutdown_blocking(mut self) -> bool {
+        self.queue.close();
+        match self.task.take() {
+            Some(task) => self.wait(task),
+            None => true,
+        }
+    }
+
+    /// Block until the background task finished or timed out.
+    fn wait(&self, task: Task) -> bool {
+        match task {
+            Task::Tokio(task) => {
+                let flushed = match Handle::try_current().map(|handle| handle.runtime_flavor()) {
+                    // Let other worker threads run the background task while blocking.
+                    Ok(RuntimeFlavor::MultiThread) => {
+                        tokio::task::block_in_place(|| self.queue.wait_finished(self.timeout))
+                    }
+                    // The background task can't make progress if we block the only thread.
+                    Ok(_) => false,
+                    Err(_) => self.queue.wait_finished(self.timeout),
+                };
+                if !flushed {
+                    task.abort();
+                }
+                flushed
+            }
+            Task::Thread(thread) => {
+                let flushed = self.queue.wait_finished(self.timeout);
+                if flushed {
+                    let _ = thread.join();
+                }
+                // Otherwise leave the thread detached, it won't outlive the process.
+                flushed
+            }
+        }
+    }
+}
+
+impl Drop for DuoGuard {
+    fn drop(&mut self) {
+        if let Some(task) = self.task.take() {
+            self.queue.close();
+            self.wait(task);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::{
+        proto,
+        queue::{DropPolicy, Message},
+    };
+
+    fn event() -> Message {
+        Message::Event(proto::Log::default())
+    }
+
+    #[test]
+    fn test_drop_flushes_queue() {
+        let queue = Arc::new(MessageQueue::new(16, DropPolicy::DropNewest));
+        let sent = Arc::new(AtomicUsize::new(0));
+        // Consume like the background task, exit once closed and drained.
+        let consumer = thread::spawn({
+            let queue = Arc::clone(&queue);
+            let sent = Arc::clone(&sent);
+            move || loop {
+                sent.fetch_add(queue.drain().len(), Ordering::SeqCst);
+                if queue.is_finished() {
+                    queue.finish();
+                    break;
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+        });
+        for _ in 0..3 {
+            queue.push(event());
+        }
+
+        drop(DuoGuard::new(
+            Arc::clone(&queue),
+            Task::Thread(consumer),
+            DEFAULT_SHUTDOWN_TIMEOUT,
+        ));
+        assert_eq!(sent.load(Ordering::SeqCst), 3);
+        // No more messages accepted.
+        queue.push(event());
+        assert_eq!(queue.dropped().events(), 1);
+    }
+
+    #[test]
+    fn test_shutdown_timeout() {
+        let queue = Arc::new(MessageQueue::new(16, DropPolicy::DropNewest));
+        // The consumer never finishes.
+        let consumer = thread::spawn(|| {});
+        let guard = DuoGuard::new(queue, Task::Thread(consumer), Duration::from_millis(10));
+        assert!(!guard.shutdown_blocking());
+    }
+}
@@ -1,13 +1,22 @@
 //! Duo subscriber for tracing.
 //!
-use std::collections::HashMap;
+use std::{collections::HashMap, env, fs};
 
 use duo_api as proto;
+mod builder;
 mod client;
 mod conn;
+mod guard;
+mod queue;
+mod spool;
 mod subscriber;
 mod visitor;
+mod worker;
 
+pub use builder::{BuildError, DuoLayerBuilder, DEFAULT_BATCH_SIZE, DEFAULT_FLUSH_INTERVAL};
+pub use guard::{DuoGuard, DEFAULT_SHUTDOWN_TIMEOUT};
+pub use queue::{DropPolicy, DroppedStats, DEFAULT_BUFFER_SIZE};
+pub use spool::DEFAULT_SPOOL_LIMIT;
 pub use subscriber::DuoLayer;
 
 // Grasp basic process info, this will collect to server
@@ -15,5 +24,20 @@ pub use subscriber::DuoLayer;
 fn grasp_process_info() -> HashMap<String, proto::Value> {
     let mut tags = HashMap::default();
     tags.insert("duo-version".into(), env!("CARGO_PKG_VERSION").into());
+    tags.insert("pid".into(), std::process::id().into());
+    if let Some(hostname) = hostname() {
+        tags.insert("hostname".into(), hostname.into());
+    }
+    if let Ok(pod) = env::var("POD_NAME") {
+        tags.insert("pod".into(), pod.into());
+    }
     tags
 }
+
+fn hostname() -> Option<String> {
+    env::var("HOSTNAME")
+        .ok()
+        .or_else(|| fs::read_to_string("/etc/hostname").ok())
+        .map(|hostname| hostname.trim().to_string())
+        .filter(|hostname| !hostname.is_empty())
+}
@@ -0,0 +1,291 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Condvar, Mutex, MutexGuard,
+    },
+    time::Duration,
+};
+
+use tokio::sync::Notify;
+
+use crate::proto;
+
+/// The default capacity of the message queue.
+pub const DEFAULT_BUFFER_SIZE: usize = 2048;
+/// How long [`DropPolicy::Block`] waits for room before dropping the message.
+const BLOCK_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone)]
+pub(crate) enum Message {
+    NewSpan(proto::Span),
+    CloseSpan(proto::Span),
+    Event(proto::Log),
+}
+
+impl Message {
+    /// Set the process id of the message unless already set.
+    pub(crate) fn stamp(&mut self, process_id: &str) {
+        let id = match self {
+            Message::NewSpan(span) | Message::CloseSpan(span) => &mut span.process_id,
+            Message::Event(log) => &mut log.process_id,
+        };
+        if id.is_empty() {
+            *id = process_id.to_string();
+        }
+    }
+}
+
+/// What to do with a new message when the queue is full.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DropPolicy {
+    /// Discard the incoming message.
+    #[default]
+    DropNewest,
+    /// Discard the oldest queued message to make room for the incoming one.
+    DropOldest,
+    /// Block the instrumented thread until the queue has room, the message
+    /// is dropped if there is still no room after one second.
+    ///
+    /// A slow or unreachable server will stall the application, so only use
+    /// it for debug builds. It requires the background task to run on another
+    /// thread: a multi-thread runtime, or the dedicated thread of
+    /// [`build_with_thread`](crate::DuoLayerBuilder::build_with_thread).
+    /// On a current_thread runtime nothing drains the queue while blocked.
+    Block,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    spans: AtomicU64,
+    events: AtomicU64,
+}
+
+/// A cheap, cloneable handle to read how many messages were dropped.
+#[derive(Debug, Clone, Default)]
+pub struct DroppedStats(Arc<Counters>);
+
+impl DroppedStats {
+    /// The number of span messages (new or closed) dropped so far.
+    pub fn spans(&self) -> u64 {
+        self.0.spans.load(Ordering::Relaxed)
+    }
+
+    /// The number of events dropped so far.
+    pub fn events(&self) -> u64 {
+        self.0.events.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn record(&self, message: &Message) {
+        let counter = match message {
+            Message::NewSpan(_) | Message::CloseSpan(_) => &self.0.spans,
+            Message::Event(_) => &self.0.events,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+struct State {
+    messages: VecDeque<Message>,
+    closed: bool,
+    // Whether the consumer has sent all messages and exited.
+    finished: bool,
+}
+
+/// A bounded queue between the layer and the background task.
+///
+/// Unlike a plain mpsc channel, the queue can evict its oldest
+/// message and counts everything it drops.
+pub(crate) struct MessageQueue {
+    state: Mutex<State>,
+    capacity: usize,
+    policy: DropPolicy,
+    // Wake up the consumer when new message arrived.
+    notify: Notify,
+    // Wake up the blocked producers when the consumer drained messages.
+    space: Condvar,
+    block_timeout: Duration,
+    // Wake up the threads waiting for the consumer to exit.
+    finish: Condvar,
+    dropped: DroppedStats,
+}
+
+impl MessageQueue {
+    pub(crate) fn new(capacity: usize, policy: DropPolicy) -> Self {
+        let capacity = capacity.max(1);
+        MessageQueue {
+            state: Mutex::new(State {
+                messages: VecDeque::with_capacity(capacity),
+                closed: false,
+                finished: false,
+            }),
+            capacity,
+            policy,
+            notify: Notify::new(),
+            space: Condvar::new(),
+            block_timeout: BLOCK_TIMEOUT,
+            finish: Condvar::new(),
+            dropped: DroppedStats::default(),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        // A panic in the middle of a push can't leave the queue inconsistent,
+        // so just ignore the poison.
+        self.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    pub(crate) fn push(&self, message: Message) {
+        let mut state = self.lock();
+        if state.closed {
+            self.dropped.record(&message);
+            return;
+        }
+
+        if state.messages.len() >= self.capacity {
+            match self.policy {
+                DropPolicy::DropNewest => {
+                    self.dropped.record(&message);
+                    return;
+                }
+                DropPolicy::DropOldest => {
+                    if let Some(oldest) = state.messages.pop_front() {
+                        self.dropped.record(&oldest);
+                    }
+                }
+                DropPolicy::Block => {
+                    (state, _) = self
+                        .space
+                        .wait_timeout_while(state, self.block_timeout, |state| {
+                            state.messages.len() >= self.capacity && !state.closed
+                        })
+                        .unwrap_or_else(|err| err.into_inner());
+                    if state.closed || state.messages.len() >= self.capacity {
+                        self.dropped.record(&message);
+                        return;
+                    }
+                }
+            }
+        }
+
+        state.messages.push_back(message);
+        drop(state);
+        self.notify.notify_one();
+    }
+
+    /// Take all queued messages in order.
+    pub(crate) fn drain(&self) -> Vec<Message> {
+        let messages = self.lock().messages.drain(..).collect();
+        self.space.notify_all();
+        messages
+    }
+
+    /// Wait until new message arrived or the queue closed.
+    pub(crate) async fn notified(&self) {
+        self.notify.notified().await
+    }
+
+    /// Close the queue, all subsequent messages will be dropped.
+    pub(crate) fn close(&self) {
+        self.lock().closed = true;
+        self.space.notify_all();
+        self.notify.notify_one();
+    }
+
+    /// Whether the queue has been closed and fully drained.
+    pub(crate) fn is_finished(&self) -> bool {
+        let state = self.lock();
+        state.closed && state.messages.is_empty()
+    }
+
+    /// Called by the consumer once all messages have been sent.
+    pub(crate) fn finish(&self) {
+        self.lock().finished = true;
+        self.finish.notify_all();
+    }
+
+    /// Block until the consumer finished, return `false` if timed out.
+    pub(crate) fn wait_finished(&self, timeout: Duration) -> bool {
+        let state = self.lock();
+        let (state, _) = self
+            .finish
+            .wait_timeout_while(state, timeout, |state| !state.finished)
+            .unwrap_or_else(|err| err.into_inner());
+        state.finished
+    }
+
+    pub(crate) fn dropped(&self) -> DroppedStats {
+        self.dropped.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, thread, time::Duration};
+
+    use super::*;
+
+    fn event(message: &str) -> Message {
+        let mut log = proto::Log::default();
+        log.fields.insert("message".into(), message.into());
+        Message::Event(log)
+    }
+
+    fn messages(queue: &MessageQueue) -> Vec<String> {
+        queue
+            .drain()
+            .into_iter()
+            .map(|message| match message {
+                Message::Event(log) => log.fields["message"].to_string(),
+                _ => unreachable!(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_drop_newest() {
+        let queue = MessageQueue::new(2, DropPolicy::DropNewest);
+        for message in ["a", "b", "c"] {
+            queue.push(event(message));
+        }
+        assert_eq!(messages(&queue), vec!["a", "b"]);
+        assert_eq!(queue.dropped().events(), 1);
+    }
+
+    #[test]
+    fn test_drop_oldest() {
+        let queue = MessageQueue::new(2, DropPolicy::DropOldest);
+        for message in ["a", "b", "c"] {
+            queue.push(event(message));
+        }
+        assert_eq!(messages(&queue), vec!["b", "c"]);
+        assert_eq!(queue.dropped().events(), 1);
+    }
+
+    #[test]
+    fn test_block() {
+        let queue = Arc::new(MessageQueue::new(1, DropPolicy::Block));
+        queue.push(event("a"));
+        let producer = thread::spawn({
+            let queue = Arc::clone(&queue);
+            move || queue.push(event("b"))
+        });
+        thread::sleep(Duration::from_millis(50));
+        // The producer waits for room.
+        assert!(!producer.is_finished());
+        assert_eq!(messages(&queue), vec!["a"]);
+        producer.join().unwrap();
+        assert_eq!(messages(&queue), vec!["b"]);
+        assert_eq!(queue.dropped().events(), 0);
+    }
+
+    #[test]
+    fn test_block_timeout() {
+        let mut queue = MessageQueue::new(1, DropPolicy::Block);
+        queue.block_timeout = Duration::from_millis(10);
+        queue.push(event("a"));
+        queue.push(event("b"));
+        assert_eq!(messages(&queue), vec!["a"]);
+        assert_eq!(queue.dropped().events(), 1);
+    }
+}
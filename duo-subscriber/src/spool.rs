@@ -0,0 +1,188 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::PathBuf,
+};
+
+use prost::Message as _;
+
+use crate::{proto, queue::Message};
+
+/// The default size limit of the spool file, 64 MiB.
+pub const DEFAULT_SPOOL_LIMIT: u64 = 64 * 1024 * 1024;
+
+const KIND_NEW_SPAN: u8 = 0;
+const KIND_CLOSE_SPAN: u8 = 1;
+const KIND_EVENT: u8 = 2;
+
+/// An append-only file to keep messages while the server is unreachable.
+///
+/// Each record is a one byte message kind followed by the
+/// length-delimited protobuf encoding of the span or log.
+/// The spool survives process restarts, the remaining messages
+/// will be replayed once connected again.
+///
+/// The replayed messages are moved to the `.inflight` file beside,
+/// which is only removed once they were all delivered, so a crash
+/// in the middle of a replay loses nothing.
+pub(crate) struct Spool {
+    path: PathBuf,
+    inflight_path: PathBuf,
+    file: File,
+    len: u64,
+    limit: u64,
+    // The inflight file left by the last run, not read yet.
+    has_inflight: bool,
+    // The inflight messages were read, waiting for the ack.
+    replaying: bool,
+}
+
+impl Spool {
+    pub(crate) fn open(path: PathBuf, limit: u64) -> io::Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut inflight_path = path.clone().into_os_string();
+        inflight_path.push(".inflight");
+        let inflight_path = PathBuf::from(inflight_path);
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let len = file.metadata()?.len();
+        Ok(Spool {
+            has_inflight: inflight_path.exists(),
+            path,
+            inflight_path,
+            file,
+            len,
+            limit,
+            replaying: false,
+        })
+    }
+
+    /// Whether there is no message to replay.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len == 0 && !self.has_inflight
+    }
+
+    /// Append the message, return `false` if the spool is full.
+    pub(crate) fn append(&mut self, message: &Message) -> io::Result<bool> {
+        let mut buf = Vec::new();
+        match message {
+            Message::NewSpan(span) => {
+                buf.push(KIND_NEW_SPAN);
+                span.encode_length_delimited(&mut buf)?;
+            }
+            Message::CloseSpan(span) => {
+                buf.push(KIND_CLOSE_SPAN);
+                span.encode_length_delimited(&mut buf)?;
+            }
+            Message::Event(log) => {
+                buf.push(KIND_EVENT);
+                log.encode_length_delimited(&mut buf)?;
+            }
+        }
+        if self.len + buf.len() as u64 > self.limit {
+            return Ok(false);
+        }
+
+        self.file.write_all(&buf)?;
+        self.len += buf.len() as u64;
+        Ok(true)
+    }
+
+    /// Read all spooled messages in order, they are kept in the inflight
+    /// file until [`ack`](Self::ack).
+    pub(crate) fn take(&mut self) -> io::Result<Vec<Message>> {
+        self.ack()?;
+        if !self.has_inflight {
+            fs::rename(&self.path, &self.inflight_path)?;
+            self.file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?;
+            self.len = 0;
+        }
+        self.has_inflight = false;
+        self.replaying = true;
+
+        let data = fs::read(&self.inflight_path)?;
+        let mut buf = data.as_slice();
+        let mut messages = Vec::new();
+        while let Some((&kind, rest)) = buf.split_first() {
+            buf = rest;
+            let message = match kind {
+                KIND_NEW_SPAN => {
+                    proto::Span::decode_length_delimited(&mut buf).map(Message::NewSpan)
+                }
+                KIND_CLOSE_SPAN => {
+                    proto::Span::decode_length_delimited(&mut buf).map(Message::CloseSpan)
+                }
+                KIND_EVENT => proto::Log::decode_length_delimited(&mut buf).map(Message::Event),
+                _ => break,
+            };
+            match message {
+                Ok(message) => messages.push(message),
+                // A torn write at the tail, ignore the rest.
+                Err(_) => break,
+            }
+        }
+
+        Ok(messages)
+    }
+
+    /// The messages of the last [`take`](Self::take) are delivered.
+    pub(crate) fn ack(&mut self) -> io::Result<()> {
+        if self.replaying {
+            fs::remove_file(&self.inflight_path)?;
+            self.replaying = false;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use super::*;
+
+    fn event(process_id: &str) -> Message {
+        Message::Event(proto::Log {
+            process_id: process_id.into(),
+            ..Default::default()
+        })
+    }
+
+    fn process_ids(messages: Vec<Message>) -> Vec<String> {
+        messages
+            .into_iter()
+            .map(|message| match message {
+                Message::Event(log) => log.process_id,
+                _ => unreachable!(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_take_until_ack() {
+        let dir = env::temp_dir().join(format!("duo-spool-{}", rand::random::<u64>()));
+        let path = dir.join("spool");
+        let mut spool = Spool::open(path.clone(), DEFAULT_SPOOL_LIMIT).unwrap();
+        assert!(spool.is_empty());
+        spool.append(&event("a")).unwrap();
+        spool.append(&event("b")).unwrap();
+        assert_eq!(process_ids(spool.take().unwrap()), vec!["a", "b"]);
+        spool.append(&event("c")).unwrap();
+
+        // Crashed before the ack, the inflight messages are replayed first.
+        drop(spool);
+        let mut spool = Spool::open(path.clone(), DEFAULT_SPOOL_LIMIT).unwrap();
+        assert!(!spool.is_empty());
+        assert_eq!(process_ids(spool.take().unwrap()), vec!["a", "b"]);
+        spool.ack().unwrap();
+        assert_eq!(process_ids(spool.take().unwrap()), vec!["c"]);
+        spool.ack().unwrap();
+        assert!(spool.is_empty());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
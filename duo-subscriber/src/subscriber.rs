@@ -1,35 +1,49 @@
 use std::{
     collections::HashMap,
+    io,
+    path::PathBuf,
+    sync::Arc,
     time::{Instant, SystemTime},
 };
 
 use crate::{
-    conn::Connection,
+    builder::DuoLayerBuilder,
+    client::Auth,
     proto,
+    queue::{DropPolicy, DroppedStats, Message, MessageQueue, DEFAULT_BUFFER_SIZE},
+    spool::{Spool, DEFAULT_SPOOL_LIMIT},
     visitor::{EventAttributeVisitor, SpanAttributeVisitor},
 };
 use rand::rngs::ThreadRng;
 use rand::Rng;
-use tokio::{
-    sync::mpsc::{self, error::TrySendError, Sender},
-    task::JoinHandle,
-};
+use tokio::task::JoinHandle;
 use tonic::transport::Uri;
 use tracing::{
     span::{self, Attributes},
-    Subscriber,
+    Metadata, Subscriber,
+};
+use tracing_subscriber::{
+    filter::Targets,
+    layer::Context,
+    registry::{LookupSpan, SpanRef},
+    Layer,
 };
-use tracing_subscriber::{layer::Context, registry::LookupSpan, Layer};
 
 pub struct DuoLayer {
-    sender: Sender<Message>,
+    pub(crate) queue: Arc<MessageQueue>,
+    pub(crate) filter: Targets,
+    pub(crate) sample_rate: f64,
 }
 
-#[derive(Debug)]
-enum Message {
-    NewSpan(proto::Span),
-    CloseSpan(proto::Span),
-    Event(proto::Log),
+/// The root span tag which records the head sampling rate.
+const SAMPLE_RATE_TAG: &str = "sample_rate";
+
+/// Mark the span (and its whole trace) as sampled out.
+struct Unsampled;
+
+enum Ancestor {
+    Sampled { span_id: u64, trace_id: u64 },
+    Unsampled,
 }
 
 struct Timings {
@@ -51,44 +65,111 @@ impl Timings {
 }
 
 impl DuoLayer {
+    /// Configure the layer with a builder.
+    pub fn builder() -> DuoLayerBuilder {
+        DuoLayerBuilder::default()
+    }
+
     pub async fn new(name: &'static str, uri: Uri) -> Self {
         let (layer, _) = Self::with_handle(name, uri).await;
         layer
     }
 
     pub async fn with_handle(name: &'static str, uri: Uri) -> (Self, JoinHandle<()>) {
-        let (sender, mut receiver) = mpsc::channel(2048);
-        let handler = tokio::spawn(async move {
-            let mut client = Connection::connect(name, uri).await;
-            while let Some(message) = receiver.recv().await {
-                match message {
-                    Message::NewSpan(span) | Message::CloseSpan(span) => {
-                        client.record_span(span).await
-                    }
-                    Message::Event(log) => {
-                        client.record_event(log).await;
-                    }
-                }
-            }
-        });
-        (DuoLayer { sender }, handler)
+        Self::with_buffer(name, uri, DEFAULT_BUFFER_SIZE, DropPolicy::default()).await
+    }
+
+    /// Create the layer with a custom buffer size and the policy
+    /// applied when the buffer is full.
+    pub async fn with_buffer(
+        name: &'static str,
+        uri: Uri,
+        buffer_size: usize,
+        policy: DropPolicy,
+    ) -> (Self, JoinHandle<()>) {
+        Self::builder()
+            .service_name(name)
+            .uri(uri)
+            .buffer_size(buffer_size)
+            .drop_policy(policy)
+            .spawn(Auth::default(), None)
+    }
+
+    /// Create the layer which spools messages to the file at `path`
+    /// while the server is unreachable, the spooled messages are
+    /// replayed in order once reconnected, even after a restart.
+    pub async fn with_spool(
+        name: &'static str,
+        uri: Uri,
+        path: impl Into<PathBuf>,
+    ) -> io::Result<(Self, JoinHandle<()>)> {
+        let spool = Spool::open(path.into(), DEFAULT_SPOOL_LIMIT)?;
+        Ok(Self::builder()
+            .service_name(name)
+            .uri(uri)
+            .spawn(Auth::default(), Some(spool)))
+    }
+
+    /// A handle to read the number of spans and events dropped
+    /// because the buffer was full or the layer was closed.
+    pub fn dropped(&self) -> DroppedStats {
+        self.queue.dropped()
     }
 
     #[inline]
     fn send_message(&self, message: Message) {
-        match self.sender.try_send(message) {
-            Ok(_) => {}
-            Err(TrySendError::Closed(_)) => {}
-            Err(TrySendError::Full(_)) => {}
-        }
+        self.queue.push(message);
+    }
+
+    #[inline]
+    fn is_enabled(&self, metadata: &Metadata<'_>) -> bool {
+        self.filter
+            .would_enable(metadata.target(), metadata.level())
+    }
+
+    /// Decide whether to keep a new trace.
+    #[inline]
+    fn sample(&self) -> bool {
+        self.sample_rate >= 1.0 || ThreadRng::default().gen_bool(self.sample_rate)
     }
 }
 
+impl Drop for DuoLayer {
+    fn drop(&mut self) {
+        self.queue.close();
+    }
+}
+
+/// Find the nearest ancestor which was either shipped or sampled out,
+/// the spans filtered out by level or target are transparent.
+fn find_ancestor<S>(span_ref: Option<SpanRef<'_, S>>) -> Option<Ancestor>
+where
+    S: for<'span> LookupSpan<'span>,
+{
+    span_ref?.scope().find_map(|span_ref| {
+        if span_ref.extensions().get::<Unsampled>().is_some() {
+            return Some(Ancestor::Unsampled);
+        }
+        span_ref
+            .extensions()
+            .get::<proto::Span>()
+            .map(|span| Ancestor::Sampled {
+                span_id: span.id,
+                trace_id: span.trace_id,
+            })
+    })
+}
+
 impl<S> Layer<S> for DuoLayer
 where
     S: Subscriber + for<'span> LookupSpan<'span>,
 {
     fn on_new_span(&self, attrs: &Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        let metadata = attrs.metadata();
+        if !self.is_enabled(metadata) {
+            return;
+        }
+
         if let Some(span) = ctx.span(id) {
             let mut extension = span.extensions_mut();
 
@@ -102,21 +183,29 @@ where
 
             let rand_id = ThreadRng::default().gen::<u32>() as u64;
             // Obtain parent_id and trace_id from parent span.
-            let (parent_id, trace_id) = parent_span
-                .and_then(|span_ref| {
-                    span_ref
-                        .extensions()
-                        .get::<proto::Span>()
-                        .map(|s| (Some(s.id), s.trace_id))
-                })
+            let (parent_id, trace_id) = match find_ancestor(parent_span) {
+                Some(Ancestor::Sampled { span_id, trace_id }) => (Some(span_id), trace_id),
+                // The whole trace shares the decision made at its root span.
+                Some(Ancestor::Unsampled) => {
+                    extension.insert(Unsampled);
+                    return;
+                }
+                None if !self.sample() => {
+                    extension.insert(Unsampled);
+                    return;
+                }
                 // If parent's trace_id not exists, use the newly generated one.
-                .unwrap_or((None, rand_id));
+                None => (None, rand_id),
+            };
 
-            let metadata = attrs.metadata();
             let mut tags = HashMap::with_capacity(3 + metadata.fields().len());
             if let (Some(file), Some(line)) = (metadata.file(), metadata.line()) {
                 tags.insert("line".into(), format!("{}:{}", file, line).into());
             }
+            // Record the rate on the root span, so the server can extrapolate counts.
+            if parent_id.is_none() && self.sample_rate < 1.0 {
+                tags.insert(SAMPLE_RATE_TAG.into(), self.sample_rate.into());
+            }
             let mut span = proto::Span {
                 id: rand_id,
                 trace_id,
@@ -136,6 +225,11 @@ where
     }
 
     fn on_event(&self, event: &tracing::Event<'_>, ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        if !self.is_enabled(metadata) {
+            return;
+        }
+
         let parent_span_ref = if let Some(parent) = event.parent() {
             ctx.span(parent)
         } else if event.is_contextual() {
@@ -144,16 +238,12 @@ where
             None
         };
 
-        let (trace_id, span_id) = parent_span_ref
-            .and_then(|span_ref| {
-                span_ref
-                    .extensions()
-                    .get::<proto::Span>()
-                    .map(|span| (Some(span.trace_id), Some(span.id)))
-            })
-            .unwrap_or_default();
+        let (trace_id, span_id) = match find_ancestor(parent_span_ref) {
+            Some(Ancestor::Sampled { span_id, trace_id }) => (Some(trace_id), Some(span_id)),
+            Some(Ancestor::Unsampled) => return,
+            None => (None, None),
+        };
 
-        let metadata = event.metadata();
         let fields = HashMap::with_capacity(metadata.fields().len());
         let mut log = proto::Log {
             // Set a temporary process id, we'll set a real value in send stage.
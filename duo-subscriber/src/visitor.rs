@@ -15,6 +15,10 @@ impl<'a> Visit for SpanAttributeVisitor<'a> {
         self.0.tags.insert(field.name().into(), value.into());
     }
 
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.tags.insert(field.name().into(), value.into());
+    }
+
     fn record_bool(&mut self, field: &Field, value: bool) {
         self.0.tags.insert(field.name().into(), value.into());
     }
@@ -37,6 +41,10 @@ impl<'a> Visit for EventAttributeVisitor<'a> {
         self.0.fields.insert(field.name().into(), value.into());
     }
 
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.fields.insert(field.name().into(), value.into());
+    }
+
     fn record_bool(&mut self, field: &Field, value: bool) {
         self.0.fields.insert(field.name().into(), value.into());
     }
@@ -0,0 +1,393 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use tokio::time::{Instant, Interval};
+
+use crate::{
+    client::{is_retryable, BatchError, DuoClient},
+    conn::{Backoff, ConnectOptions, Connection},
+    proto,
+    queue::{DroppedStats, Message, MessageQueue},
+    spool::Spool,
+};
+
+/// How often the dropped messages are reported to the server.
+const DROP_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+/// The target of the dropped messages report log.
+const DROP_REPORT_TARGET: &str = "duo_subscriber::dropped";
+/// How often to tell the server the process is alive.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+/// The maximum messages kept in memory while disconnected.
+const DEFAULT_BACKLOG_SIZE: usize = 16384;
+
+/// Turn the growth of dropped counters into a warning log,
+/// which lets the server know the traces may be incomplete.
+struct DropReporter {
+    stats: DroppedStats,
+    // The (spans, events) counters at the last report.
+    reported: (u64, u64),
+}
+
+impl DropReporter {
+    fn new(stats: DroppedStats) -> Self {
+        DropReporter {
+            stats,
+            reported: (0, 0),
+        }
+    }
+
+    fn report(&mut self) -> Option<proto::Log> {
+        let (spans, events) = (self.stats.spans(), self.stats.events());
+        let (dropped_spans, dropped_events) = (spans - self.reported.0, events - self.reported.1);
+        if dropped_spans == 0 && dropped_events == 0 {
+            return None;
+        }
+        self.reported = (spans, events);
+
+        let mut fields = HashMap::with_capacity(3);
+        fields.insert(
+            "message".into(),
+            format!("{dropped_spans} spans and {dropped_events} events dropped").into(),
+        );
+        fields.insert("dropped_spans".into(), dropped_spans.into());
+        fields.insert("dropped_events".into(), dropped_events.into());
+        Some(proto::Log {
+            process_id: String::new(),
+            span_id: None,
+            trace_id: None,
+            level: proto::Level::Warn as i32,
+            target: DROP_REPORT_TARGET.into(),
+            file: None,
+            line: None,
+            time: Some(SystemTime::now().into()),
+            fields,
+        })
+    }
+}
+
+/// Messages waiting to be sent, in order.
+///
+/// The oldest messages stay in memory, once the memory part is full
+/// the newer ones overflow to the spool (if any) until it is replayed.
+struct Backlog {
+    memory: VecDeque<Message>,
+    capacity: usize,
+    spool: Option<Spool>,
+    dropped: DroppedStats,
+}
+
+impl Backlog {
+    fn push(&mut self, message: Message) {
+        match &mut self.spool {
+            Some(spool) if !spool.is_empty() || self.memory.len() >= self.capacity => {
+                match spool.append(&message) {
+                    Ok(true) => {}
+                    Ok(false) => self.dropped.record(&message),
+                    Err(err) => {
+                        tracing::warn!(%err, "write spool failed");
+                        self.dropped.record(&message);
+                    }
+                }
+            }
+            _ if self.memory.len() >= self.capacity => self.dropped.record(&message),
+            _ => self.memory.push_back(message),
+        }
+    }
+
+    /// Whether there are enough messages to fill a batch.
+    fn is_batch_ready(&self, batch_size: usize) -> bool {
+        self.memory.len() >= batch_size || self.spool.iter().any(|spool| !spool.is_empty())
+    }
+
+    /// The oldest messages up to `size`.
+    fn batch(&mut self, size: usize) -> impl Iterator<Item = &Message> {
+        if self.memory.is_empty() {
+            if let Some(spool) = self.spool.as_mut().filter(|spool| !spool.is_empty()) {
+                match spool.take() {
+                    Ok(messages) => self.memory.extend(messages),
+                    Err(err) => tracing::warn!(%err, "read spool failed"),
+                }
+            }
+        }
+        self.memory.iter().take(size)
+    }
+
+    /// Remove the oldest `n` messages, once sent or rejected.
+    fn consume(&mut self, n: usize) {
+        self.memory.drain(..n.min(self.memory.len()));
+        if self.memory.is_empty() {
+            // All the messages read from the spool are done.
+            if let Some(Err(err)) = self.spool.as_mut().map(Spool::ack) {
+                tracing::warn!(%err, "remove spool failed");
+            }
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.memory.is_empty() && self.spool.iter().all(Spool::is_empty)
+    }
+}
+
+enum Wake {
+    Message,
+    Flush,
+    Report,
+    Heartbeat,
+    Reconnect,
+}
+
+/// The timers of the worker loop.
+struct Timers {
+    flush: Interval,
+    report: Interval,
+    heartbeat: Interval,
+}
+
+impl Timers {
+    fn new(flush_interval: Duration) -> Self {
+        Timers {
+            flush: tokio::time::interval(flush_interval),
+            report: tokio::time::interval(DROP_REPORT_INTERVAL),
+            // Registering the process counts as the first heartbeat.
+            heartbeat: tokio::time::interval_at(
+                Instant::now() + HEARTBEAT_INTERVAL,
+                HEARTBEAT_INTERVAL,
+            ),
+        }
+    }
+
+    /// Wait for the next thing to do. The reconnect deadline is fixed by
+    /// the caller, so the other wakes don't postpone the reconnection.
+    async fn wait(&mut self, queue: &MessageQueue, reconnect_at: Option<Instant>) -> Wake {
+        tokio::select! {
+            _ = queue.notified() => Wake::Message,
+            _ = self.flush.tick() => Wake::Flush,
+            _ = self.report.tick() => Wake::Report,
+            _ = self.heartbeat.tick() => Wake::Heartbeat,
+            _ = tokio::time::sleep_until(reconnect_at.unwrap_or_else(Instant::now)),
+                if reconnect_at.is_some() => Wake::Reconnect,
+        }
+    }
+}
+
+/// The background task ships queued messages to the server.
+///
+/// It survives server restarts: once a request failed, messages are kept
+/// in the backlog, and replayed in order after reconnected and
+/// re-registered the process.
+pub(crate) struct Worker {
+    process: proto::Process,
+    options: ConnectOptions,
+    queue: Arc<MessageQueue>,
+    batch_size: usize,
+    flush_interval: Duration,
+    client: Option<DuoClient>,
+    // The id of the last registration, the messages keep the id
+    // of the process they were recorded by. It is sent again when
+    // reconnecting, so the process keeps its id if the server knows it.
+    process_id: String,
+    backoff: Backoff,
+    next_reconnect: Instant,
+    backlog: Backlog,
+    reporter: DropReporter,
+}
+
+impl Worker {
+    pub(crate) fn new(
+        process: proto::Process,
+        options: ConnectOptions,
+        queue: Arc<MessageQueue>,
+        spool: Option<Spool>,
+        batch_size: usize,
+        flush_interval: Duration,
+    ) -> Self {
+        let dropped = queue.dropped();
+        Worker {
+            process,
+            options,
+            queue,
+            batch_size: batch_size.max(1),
+            flush_interval,
+            client: None,
+            process_id: String::new(),
+            backoff: Backoff::default(),
+            next_reconnect: Instant::now(),
+            backlog: Backlog {
+                memory: VecDeque::new(),
+                capacity: DEFAULT_BACKLOG_SIZE,
+                spool,
+                dropped: dropped.clone(),
+            },
+            reporter: DropReporter::new(dropped),
+        }
+    }
+
+    pub(crate) async fn run(mut self) {
+        let queue = Arc::clone(&self.queue);
+        let mut timers = Timers::new(self.flush_interval);
+        loop {
+            let reconnect_at = self.client.is_none().then_some(self.next_reconnect);
+            let wake = timers.wait(&queue, reconnect_at).await;
+            match wake {
+                Wake::Message | Wake::Flush => {}
+                Wake::Report => self.report(),
+                Wake::Heartbeat => self.heartbeat(false).await,
+                Wake::Reconnect => self.reconnect().await,
+            }
+
+            for mut message in queue.drain() {
+                message.stamp(&self.process_id);
+                self.backlog.push(message);
+            }
+            let finished = queue.is_finished();
+            if finished {
+                // Report the drops happened since the last tick.
+                self.report();
+            }
+            // Only flush a partial batch when the flush interval elapsed.
+            self.flush(finished || !matches!(wake, Wake::Message)).await;
+
+            if queue.is_finished() && self.backlog.is_empty() {
+                self.heartbeat(true).await;
+                break;
+            }
+        }
+        queue.finish();
+    }
+
+    async fn heartbeat(&mut self, stopped: bool) {
+        let Some(client) = self.client.as_mut() else {
+            return;
+        };
+        match client.heartbeat(stopped).await {
+            Ok(()) => {}
+            Err(status) if is_retryable(&status) => {
+                tracing::warn!(%status, "server unavailable, reconnecting");
+                self.disconnect();
+            }
+            Err(status) => tracing::debug!(%status, "heartbeat rejected"),
+        }
+    }
+
+    fn report(&mut self) {
+        if let Some(log) = self.reporter.report() {
+            self.backlog.push(Message::Event(log));
+        }
+    }
+
+    async fn reconnect(&mut self) {
+        match Connection::connect(&self.process, &self.process_id, &self.options).await {
+            Ok(client) => {
+                self.process_id = client.process_id().to_string();
+                self.client = Some(client);
+                self.backoff.reset();
+            }
+            Err(error) => {
+                tracing::warn!(%error, "error connecting");
+                self.backoff.increase();
+                tracing::debug!(reconnect_in = ?self.backoff.delay(), "reconnecting");
+            }
+        }
+        self.next_reconnect = Instant::now() + self.backoff.delay();
+    }
+
+    /// Drop the broken connection, reconnect immediately after a successful
+    /// connection, the backoff starts after that failed.
+    fn disconnect(&mut self) {
+        self.client = None;
+        self.next_reconnect = Instant::now() + self.backoff.delay();
+    }
+
+    async fn flush(&mut self, partial: bool) {
+        let Some(client) = self.client.as_mut() else {
+            return;
+        };
+        while !self.backlog.is_empty() && (partial || self.backlog.is_batch_ready(self.batch_size))
+        {
+            let batch = self.backlog.batch(self.batch_size).collect::<Vec<_>>();
+            let len = batch.len();
+            if len == 0 {
+                break;
+            }
+            match client.record_batch(batch.iter().copied()).await {
+                Ok(()) => self.backlog.consume(len),
+                Err(BatchError { sent, status, .. }) if is_retryable(&status) => {
+                    tracing::warn!(%status, "server unavailable, buffer until reconnected");
+                    // Only the unsent messages are replayed.
+                    self.backlog.consume(sent);
+                    self.disconnect();
+                    return;
+                }
+                Err(BatchError {
+                    sent,
+                    rejected,
+                    status,
+                }) => {
+                    tracing::warn!(%status, "messages rejected by the server, dropped");
+                    // Counted in the next drop report.
+                    for message in &batch[sent..sent + rejected] {
+                        self.reporter.stats.record(message);
+                    }
+                    self.backlog.consume(sent + rejected);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queue::DropPolicy;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_reconnect_while_messages_arrive() {
+        let queue = Arc::new(MessageQueue::new(16, DropPolicy::DropNewest));
+        let producer = tokio::spawn({
+            let queue = Arc::clone(&queue);
+            async move {
+                loop {
+                    queue.push(Message::Event(proto::Log::default()));
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                }
+            }
+        });
+
+        let mut timers = Timers::new(Duration::from_millis(200));
+        let start = Instant::now();
+        let reconnect_at = start + Duration::from_secs(5);
+        let mut messages = 0;
+        loop {
+            match timers.wait(&queue, Some(reconnect_at)).await {
+                Wake::Reconnect => break,
+                _ => messages += queue.drain().len(),
+            }
+        }
+        producer.abort();
+        assert_eq!(start.elapsed(), Duration::from_secs(5));
+        assert!(messages >= 90);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_reconnect_after_shortest_backoff() {
+        let queue = MessageQueue::new(16, DropPolicy::DropNewest);
+        let mut backoff = Backoff::default();
+        backoff.increase();
+        // The flush ticks are more frequent than the reconnections.
+        let mut timers = Timers::new(crate::DEFAULT_FLUSH_INTERVAL);
+        let start = Instant::now();
+        let mut flushes = 0;
+        loop {
+            match timers.wait(&queue, Some(start + backoff.delay())).await {
+                Wake::Reconnect => break,
+                Wake::Flush => flushes += 1,
+                _ => {}
+            }
+        }
+        assert_eq!(start.elapsed(), backoff.delay());
+        assert_eq!(flushes, 3);
+    }
+}
@@ -1,40 +1,485 @@
-use std::mem;
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    mem,
+    time::{Duration, Instant},
+};
 
 use duo_api as proto;
+use rand::Rng;
+use serde_json::Value as JsonValue;
 
-use crate::Span;
+use crate::{
+    config::{DuoConfig, SamplingConfig, SamplingRule},
+    Span,
+};
+
+/// The root span tag which records the sampling rate of the trace.
+pub const SAMPLE_RATE_TAG: &str = "sample_rate";
+/// The tag of the spans force emitted before closed.
+pub const INCOMPLETE_TAG: &str = "incomplete";
+/// The tag of the unfinished spans merged into the query results.
+pub const RUNNING_TAG: &str = "running";
+
+// How long to remember a decision for the late spans of the trace.
+const DECISION_TTL: Duration = Duration::from_secs(300);
+
+struct OngoingSpan {
+    span: proto::Span,
+    // The last time the span received an update.
+    updated: Instant,
+}
 
-#[derive(Debug, Default)]
 pub struct SpanAggregator {
-    spans: Vec<proto::Span>,
+    // Unfinished spans grouped by trace id, then by span id.
+    traces: HashMap<u64, HashMap<u64, OngoingSpan>>,
+    // The number of unfinished spans.
+    len: usize,
+    // Closed spans waiting for the next aggregation.
+    closed: Vec<proto::Span>,
+    // The spans emitted as incomplete, keyed by (trace id, span id),
+    // their late closes are dropped.
+    evicted: HashMap<(u64, u64), Instant>,
+    span_timeout: Duration,
+    max_spans: usize,
+    sampler: Option<TailSampler>,
+}
+
+impl Debug for SpanAggregator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SpanAggregator")
+            .field("traces", &self.traces.len())
+            .field("spans", &self.len)
+            .finish()
+    }
 }
 
 impl SpanAggregator {
-    pub fn new() -> Self {
-        SpanAggregator { spans: Vec::new() }
+    pub fn new(config: &DuoConfig) -> Self {
+        let sampling = config.sampling.clone();
+        SpanAggregator {
+            traces: HashMap::new(),
+            len: 0,
+            closed: Vec::new(),
+            evicted: HashMap::new(),
+            span_timeout: Duration::from_secs(config.aggregator.span_timeout_secs),
+            max_spans: config.aggregator.max_spans,
+            sampler: sampling.is_enabled().then(|| TailSampler::new(sampling)),
+        }
     }
 
     pub fn record_span(&mut self, raw: proto::Span) {
-        if let Some(span) = self.spans.iter_mut().find(|s| s.id == raw.id) {
-            if raw.parent_id.is_some() {
-                span.parent_id = raw.parent_id;
+        let trace_id = raw.trace_id;
+        if raw.end.is_some() && self.evicted.remove(&(trace_id, raw.id)).is_some() {
+            // Already stored as incomplete.
+            return;
+        }
+        let trace = self.traces.entry(trace_id).or_default();
+        let span = match trace.remove(&raw.id) {
+            Some(OngoingSpan { mut span, .. }) => {
+                self.len -= 1;
+                if raw.parent_id.is_some() {
+                    span.parent_id = raw.parent_id;
+                }
+
+                if !raw.tags.is_empty() {
+                    span.tags.extend(raw.tags);
+                }
+                span.end = raw.end;
+                span
             }
+            None => raw,
+        };
 
-            if !raw.tags.is_empty() {
-                span.tags.extend(raw.tags);
+        if span.end.is_some() {
+            if trace.is_empty() {
+                self.traces.remove(&trace_id);
             }
-            span.end = raw.end;
+            self.closed.push(span);
         } else {
-            self.spans.push(raw);
+            trace.insert(
+                span.id,
+                OngoingSpan {
+                    span,
+                    updated: Instant::now(),
+                },
+            );
+            self.len += 1;
+        }
+    }
+
+    /// The unfinished spans of the trace, marked as `running`,
+    /// their duration so far is computed when served.
+    pub fn ongoing_spans(&self, trace_id: u64) -> Vec<Span> {
+        self.traces
+            .get(&trace_id)
+            .map(|trace| trace.values().map(running_span).collect())
+            .unwrap_or_default()
+    }
+
+    /// The unfinished root spans which match the predicate.
+    pub fn ongoing_roots(&self, predicate: impl Fn(&proto::Span) -> bool) -> Vec<Span> {
+        self.traces
+            .values()
+            .flat_map(HashMap::values)
+            .filter(|ongoing| ongoing.span.parent_id.is_none() && predicate(&ongoing.span))
+            .map(running_span)
+            .collect()
+    }
+
+    /// Remember the trace has an error, so the tail sampling keeps it.
+    pub fn record_error(&mut self, trace_id: u64) {
+        if let Some(sampler) = &mut self.sampler {
+            sampler.errors.insert(trace_id, Instant::now());
         }
     }
 
-    pub fn aggregate(&mut self) -> Vec<Span> {
-        // Remove all intact spans.
-        let (intact_spans, ongoing_spans): (Vec<_>, Vec<_>) = mem::take(&mut self.spans)
+    /// Take the closed spans, along with the unfinished ones which timed out
+    /// or exceed the memory cap, those are marked as `incomplete`.
+    ///
+    /// `service_of` resolves the service name of a process id.
+    pub fn aggregate(&mut self, service_of: impl Fn(&str) -> Option<String>) -> Vec<Span> {
+        let mut spans: Vec<Span> = mem::take(&mut self.closed)
             .into_iter()
-            .partition(|span| span.end.is_some());
-        self.spans = ongoing_spans;
-        intact_spans.into_iter().map(Span::from).collect()
+            .map(Span::from)
+            .collect();
+        spans.extend(self.evict().into_iter().map(|raw| {
+            let mut span = Span::from(raw);
+            span.tags
+                .insert(INCOMPLETE_TAG.into(), JsonValue::Bool(true));
+            span
+        }));
+
+        match &mut self.sampler {
+            Some(sampler) => sampler.sample(spans, service_of),
+            None => spans,
+        }
+    }
+
+    /// Remove the unfinished spans not updated within the timeout,
+    /// then the least recently updated ones until under the cap.
+    fn evict(&mut self) -> Vec<proto::Span> {
+        if self.len == 0 {
+            return Vec::new();
+        }
+
+        let now = Instant::now();
+        let (mut stale, mut fresh): (Vec<_>, Vec<_>) = self
+            .traces
+            .values()
+            .flat_map(HashMap::values)
+            .map(|ongoing| (ongoing.updated, ongoing.span.trace_id, ongoing.span.id))
+            .partition(|(updated, ..)| now - *updated >= self.span_timeout);
+
+        let excess = fresh.len().saturating_sub(self.max_spans);
+        if excess > 0 {
+            fresh.sort_unstable_by_key(|(updated, ..)| *updated);
+            stale.extend(fresh.into_iter().take(excess));
+        }
+
+        let evicted: Vec<_> = stale
+            .into_iter()
+            .filter_map(|(_, trace_id, span_id)| {
+                let trace = self.traces.get_mut(&trace_id)?;
+                let ongoing = trace.remove(&span_id)?;
+                if trace.is_empty() {
+                    self.traces.remove(&trace_id);
+                }
+                self.len -= 1;
+                Some(ongoing.span)
+            })
+            .collect();
+        self.remember_evicted(&evicted, now);
+        evicted
+    }
+
+    /// Remember the evicted spans for a while, at most as many as the
+    /// unfinished spans, the oldest are forgotten first.
+    fn remember_evicted(&mut self, spans: &[proto::Span], now: Instant) {
+        self.evicted
+            .extend(spans.iter().map(|span| ((span.trace_id, span.id), now)));
+        self.evicted.retain(|_, at| now - *at < DECISION_TTL);
+        let capacity = self.max_spans.max(1);
+        if self.evicted.len() > capacity {
+            let mut times: Vec<_> = self.evicted.values().copied().collect();
+            times.sort_unstable();
+            let oldest = times[times.len() - capacity];
+            self.evicted.retain(|_, at| *at >= oldest);
+        }
+    }
+}
+
+fn running_span(ongoing: &OngoingSpan) -> Span {
+    let mut span = Span::from(ongoing.span.clone());
+    span.tags.insert(RUNNING_TAG.into(), JsonValue::Bool(true));
+    span
+}
+
+struct PendingTrace {
+    spans: Vec<Span>,
+    first_seen: Instant,
+    root_closed: bool,
+}
+
+/// Hold the intact spans of a trace until its root span closed,
+/// then keep or drop the whole trace.
+struct TailSampler {
+    config: SamplingConfig,
+    pending: HashMap<u64, PendingTrace>,
+    // The traces decided recently, the value is whether kept.
+    decisions: HashMap<u64, (bool, Instant)>,
+    // The traces which have an error log.
+    errors: HashMap<u64, Instant>,
+}
+
+impl TailSampler {
+    fn new(config: SamplingConfig) -> Self {
+        TailSampler {
+            config,
+            pending: HashMap::new(),
+            decisions: HashMap::new(),
+            errors: HashMap::new(),
+        }
+    }
+
+    fn sample(
+        &mut self,
+        spans: Vec<Span>,
+        service_of: impl Fn(&str) -> Option<String>,
+    ) -> Vec<Span> {
+        let now = Instant::now();
+        let mut kept = Vec::new();
+        for span in spans {
+            if let Some((keep, _)) = self.decisions.get(&span.trace_id) {
+                if *keep {
+                    kept.push(span);
+                }
+                continue;
+            }
+            let trace = self
+                .pending
+                .entry(span.trace_id)
+                .or_insert_with(|| PendingTrace {
+                    spans: Vec::new(),
+                    first_seen: now,
+                    root_closed: false,
+                });
+            trace.root_closed |= span.parent_id.is_none();
+            trace.spans.push(span);
+        }
+
+        let wait = Duration::from_secs(self.config.decision_wait_secs);
+        let ready: Vec<u64> = self
+            .pending
+            .iter()
+            .filter(|(_, trace)| trace.root_closed || now - trace.first_seen >= wait)
+            .map(|(trace_id, _)| *trace_id)
+            .collect();
+        for trace_id in ready {
+            let Some(mut trace) = self.pending.remove(&trace_id) else {
+                continue;
+            };
+            let rate = self.keep_rate(trace_id, &trace.spans, &service_of);
+            let keep = rate >= 1.0 || (rate > 0.0 && rand::thread_rng().gen_bool(rate));
+            self.decisions.insert(trace_id, (keep, now));
+            self.errors.remove(&trace_id);
+            if keep {
+                if rate < 1.0 {
+                    record_sample_rate(&mut trace.spans, rate);
+                }
+                kept.append(&mut trace.spans);
+            }
+        }
+
+        self.decisions.retain(|_, (_, at)| now - *at < DECISION_TTL);
+        self.errors.retain(|_, at| now - *at < DECISION_TTL);
+        kept
+    }
+
+    /// The probability to keep the trace.
+    fn keep_rate(
+        &self,
+        trace_id: u64,
+        spans: &[Span],
+        service_of: impl Fn(&str) -> Option<String>,
+    ) -> f64 {
+        if self.config.keep_errors
+            && (self.errors.contains_key(&trace_id) || spans.iter().any(is_error))
+        {
+            return 1.0;
+        }
+
+        if let Some(threshold) = self.config.latency_threshold_ms {
+            let threshold = time::Duration::milliseconds(threshold as i64);
+            // Fallback to the longest span if the root span never closed.
+            let latency = spans
+                .iter()
+                .find(|span| span.parent_id.is_none())
+                .or_else(|| spans.iter().max_by_key(|span| span.duration()))
+                .map(Span::duration)
+                .unwrap_or_default();
+            if latency >= threshold {
+                return 1.0;
+            }
+        }
+
+        self.config
+            .rules
+            .iter()
+            .find(|rule| {
+                spans
+                    .iter()
+                    .any(|span| rule_matches(rule, span, &service_of))
+            })
+            .map(|rule| rule.rate)
+            .unwrap_or(self.config.rate)
+    }
+}
+
+fn is_error(span: &Span) -> bool {
+    matches!(span.tags.get("error"), Some(JsonValue::Bool(true)))
+}
+
+fn rule_matches(
+    rule: &SamplingRule,
+    span: &Span,
+    service_of: impl Fn(&str) -> Option<String>,
+) -> bool {
+    if let Some(operation) = &rule.operation {
+        if operation != &span.name {
+            return false;
+        }
+    }
+    match &rule.service {
+        Some(service) => service_of(&span.process_id).as_ref() == Some(service),
+        None => true,
+    }
+}
+
+/// Multiply the head sampling rate recorded on the root span by the tail one.
+fn record_sample_rate(spans: &mut [Span], rate: f64) {
+    if let Some(root) = spans.iter_mut().find(|span| span.parent_id.is_none()) {
+        let head = root
+            .tags
+            .get(SAMPLE_RATE_TAG)
+            .and_then(JsonValue::as_f64)
+            .unwrap_or(1.0);
+        if let Some(rate) = serde_json::Number::from_f64(head * rate) {
+            root.tags
+                .insert(SAMPLE_RATE_TAG.into(), JsonValue::Number(rate));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use super::*;
+
+    fn span(id: u64, trace_id: u64, parent_id: Option<u64>) -> proto::Span {
+        proto::Span {
+            id,
+            trace_id,
+            parent_id,
+            name: String::from("handle"),
+            start: Some(SystemTime::now().into()),
+            end: Some(SystemTime::now().into()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_tail_sampling_keeps_errors() {
+        let mut config = DuoConfig::default();
+        config.sampling.rate = 0.0;
+        let mut aggregator = SpanAggregator::new(&config);
+        aggregator.record_span(span(1, 1, None));
+        aggregator.record_span(span(2, 2, None));
+        aggregator.record_span(span(3, 2, Some(2)));
+        aggregator.record_error(2);
+
+        let spans = aggregator.aggregate(|_| None);
+        assert_eq!(spans.len(), 2);
+        assert!(spans.iter().all(|span| span.trace_id == 2));
+
+        // The late span follows the decision of its trace.
+        aggregator.record_span(span(4, 1, Some(1)));
+        assert!(aggregator.aggregate(|_| None).is_empty());
+    }
+
+    #[test]
+    fn test_evict_unfinished_spans() {
+        let mut config = DuoConfig::default();
+        config.aggregator.max_spans = 1;
+        let mut aggregator = SpanAggregator::new(&config);
+        let mut open = |id| {
+            let mut raw = span(id, 1, None);
+            raw.end = None;
+            aggregator.record_span(raw);
+        };
+        open(1);
+        open(2);
+
+        let spans = aggregator.aggregate(|_| None);
+        assert_eq!(spans.len(), 1);
+        assert_eq!(
+            spans[0].tags.get(INCOMPLETE_TAG),
+            Some(&JsonValue::Bool(true))
+        );
+        // Never closed, so no end.
+        assert_eq!(spans[0].end, None);
+
+        // The remaining span is emitted once closed.
+        let remaining = 3 - spans[0].id;
+        aggregator.record_span(span(remaining, 1, None));
+        let spans = aggregator.aggregate(|_| None);
+        assert_eq!(spans.len(), 1);
+        assert!(!spans[0].tags.contains_key(INCOMPLETE_TAG));
+    }
+
+    #[test]
+    fn test_drop_late_close_of_evicted_span() {
+        let mut config = DuoConfig::default();
+        config.aggregator.span_timeout_secs = 0;
+        let mut aggregator = SpanAggregator::new(&config);
+        let mut raw = span(1, 1, None);
+        raw.end = None;
+        aggregator.record_span(raw);
+        let mut stored = aggregator.aggregate(|_| None);
+        assert_eq!(stored.len(), 1);
+
+        // The real close arrives after emitted as incomplete.
+        aggregator.record_span(span(1, 1, None));
+        stored.extend(aggregator.aggregate(|_| None));
+        assert_eq!(stored.len(), 1);
+        assert!(aggregator.evicted.is_empty());
+    }
+
+    #[test]
+    fn test_ongoing_spans_are_running() {
+        let mut aggregator = SpanAggregator::new(&DuoConfig::default());
+        let mut root = span(1, 1, None);
+        root.end = None;
+        aggregator.record_span(root);
+        aggregator.record_span(span(2, 1, Some(1)));
+
+        let ongoing = aggregator.ongoing_spans(1);
+        assert_eq!(ongoing.len(), 1);
+        assert_eq!(
+            ongoing[0].tags.get(RUNNING_TAG),
+            Some(&JsonValue::Bool(true))
+        );
+        assert!(ongoing[0].is_running());
+        assert_eq!(
+            aggregator.ongoing_roots(|span| span.name == "handle").len(),
+            1
+        );
+        assert!(aggregator
+            .ongoing_roots(|span| span.name == "other")
+            .is_empty());
+        // The closed span isn't ongoing.
+        assert_eq!(aggregator.aggregate(|_| None).len(), 1);
+        assert!(aggregator.ongoing_spans(2).is_empty());
     }
 }
@@ -0,0 +1,434 @@
+use std::collections::HashMap;
+
+use serde::Serialize;
+
+use crate::TraceExt;
+
+/// Where the time of a trace went, the times are in microseconds.
+#[derive(Debug, Serialize)]
+pub struct TraceAnalysis {
+    #[serde(rename = "traceID")]
+    pub trace_id: String,
+    pub duration: i64,
+    pub spans: Vec<SpanTime>,
+    /// The segments of the critical path in chronological order.
+    #[serde(rename = "criticalPath")]
+    pub critical_path: Vec<PathSegment>,
+    /// Sorted by the self time, the largest first.
+    pub operations: Vec<OperationTime>,
+    /// Sorted by the self time, the largest first.
+    pub services: Vec<ServiceTime>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SpanTime {
+    #[serde(rename = "spanID")]
+    pub span_id: String,
+    pub service: String,
+    pub operation: String,
+    #[serde(rename = "startTime")]
+    pub start_time: i64,
+    pub duration: i64,
+    /// The time not covered by the children.
+    #[serde(rename = "selfTime")]
+    pub self_time: i64,
+    /// The time the span is on the critical path by itself.
+    #[serde(rename = "criticalTime")]
+    pub critical_time: i64,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct PathSegment {
+    #[serde(rename = "spanID")]
+    pub span_id: String,
+    pub start: i64,
+    pub end: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct OperationTime {
+    pub service: String,
+    pub operation: String,
+    pub count: usize,
+    #[serde(rename = "totalTime")]
+    pub total_time: i64,
+    #[serde(rename = "selfTime")]
+    pub self_time: i64,
+    #[serde(rename = "criticalTime")]
+    pub critical_time: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ServiceTime {
+    pub service: String,
+    #[serde(rename = "selfTime")]
+    pub self_time: i64,
+    #[serde(rename = "criticalTime")]
+    pub critical_time: i64,
+}
+
+struct Node {
+    id: u64,
+    start: i64,
+    end: i64,
+    children: Vec<usize>,
+}
+
+/// Analyze the trace, the unfinished spans end with their latest child.
+pub fn analyze(trace: &TraceExt) -> TraceAnalysis {
+    let index = trace
+        .spans
+        .iter()
+        .enumerate()
+        .map(|(i, span)| (span.id, i))
+        .collect::<HashMap<_, _>>();
+    let mut nodes = trace
+        .spans
+        .iter()
+        .map(|span| Node {
+            id: span.id,
+            start: span.start_as_micros(),
+            end: span.end_as_micros().unwrap_or(i64::MIN),
+            children: Vec::new(),
+        })
+        .collect::<Vec<_>>();
+    // The spans whose parent is missing are roots too.
+    let mut roots = Vec::new();
+    for (i, span) in trace.spans.iter().enumerate() {
+        match span.parent_id.and_then(|id| index.get(&id)) {
+            Some(&parent) if parent != i => nodes[parent].children.push(i),
+            _ => roots.push(i),
+        }
+    }
+    for &root in &roots {
+        resolve_end(&mut nodes, root);
+    }
+    // Unreachable from the roots, such as in a parent cycle or below one.
+    for node in nodes.iter_mut().filter(|node| node.end == i64::MIN) {
+        node.end = node.start;
+    }
+
+    let self_times = nodes
+        .iter()
+        .map(|node| self_time(&nodes, node))
+        .collect::<Vec<_>>();
+    let start = nodes
+        .iter()
+        .map(|node| node.start)
+        .min()
+        .unwrap_or_default();
+    let end = nodes.iter().map(|node| node.end).max().unwrap_or_default();
+
+    // The path goes through the longest root.
+    let mut critical_path = Vec::new();
+    if let Some(&root) = roots.iter().max_by_key(|&&i| {
+        (
+            nodes[i].end - nodes[i].start,
+            std::cmp::Reverse(nodes[i].start),
+        )
+    }) {
+        walk_critical_path(&nodes, root, nodes[root].end, &mut critical_path);
+        critical_path.reverse();
+    }
+    let mut critical_times = vec![0; nodes.len()];
+    for &(i, seg_start, seg_end) in &critical_path {
+        critical_times[i] += seg_end - seg_start;
+    }
+
+    let spans = trace
+        .spans
+        .iter()
+        .enumerate()
+        .map(|(i, span)| SpanTime {
+            span_id: span.id.to_string(),
+            service: service_of(trace, &span.process_id),
+            operation: span.name.clone(),
+            start_time: nodes[i].start,
+            duration: nodes[i].end - nodes[i].start,
+            self_time: self_times[i],
+            critical_time: critical_times[i],
+        })
+        .collect::<Vec<_>>();
+
+    TraceAnalysis {
+        trace_id: trace.trace_id.to_string(),
+        duration: end - start,
+        critical_path: critical_path
+            .into_iter()
+            .map(|(i, start, end)| PathSegment {
+                span_id: nodes[i].id.to_string(),
+                start,
+                end,
+            })
+            .collect(),
+        operations: operation_times(&spans),
+        services: service_times(&spans),
+        spans,
+    }
+}
+
+fn service_of(trace: &TraceExt, process_id: &str) -> String {
+    trace
+        .processes
+        .get(process_id)
+        .map(|process| process.service_name.clone())
+        .unwrap_or_else(|| process_id.to_string())
+}
+
+/// Fill the end of the unfinished spans below the root, the children are
+/// resolved before their parent. The stack is explicit as a trace may be
+/// deeper than the thread stack allows.
+fn resolve_end(nodes: &mut [Node], root: usize) {
+    let mut stack = vec![(root, false)];
+    while let Some((i, expanded)) = stack.pop() {
+        if !expanded {
+            stack.push((i, true));
+            stack.extend(nodes[i].children.iter().map(|&child| (child, false)));
+            continue;
+        }
+        if nodes[i].end == i64::MIN {
+            nodes[i].end = nodes[i]
+                .children
+                .iter()
+                .map(|&child| nodes[child].end)
+                .fold(nodes[i].start, i64::max);
+        }
+    }
+}
+
+fn self_time(nodes: &[Node], node: &Node) -> i64 {
+    exclusive_time(
+        node.start,
+        node.end,
+        node.children
+            .iter()
+            .map(|&child| (nodes[child].start, nodes[child].end)),
+    )
+}
+
+/// The duration minus the union of the children, clipped to the span.
+pub fn exclusive_time(start: i64, end: i64, children: impl Iterator<Item = (i64, i64)>) -> i64 {
+    let mut intervals = children
+        .map(|(child_start, child_end)| (child_start.max(start), child_end.min(end)))
+        .filter(|(start, end)| start < end)
+        .collect::<Vec<_>>();
+    intervals.sort_unstable();
+
+    let mut covered = 0;
+    let mut cursor = start;
+    for (interval_start, interval_end) in intervals {
+        let interval_start = interval_start.max(cursor);
+        if interval_end > interval_start {
+            covered += interval_end - interval_start;
+            cursor = interval_end;
+        }
+    }
+    end - start - covered
+}
+
+/// Walk backwards from `until`: the child finishing last is on the path,
+/// the gaps between the children belong to the span itself.
+/// The segments are pushed in reverse chronological order.
+fn walk_critical_path(nodes: &[Node], i: usize, until: i64, path: &mut Vec<(usize, i64, i64)>) {
+    // A span being walked, with its children left and the time walked back to.
+    struct Frame {
+        i: usize,
+        children: std::vec::IntoIter<usize>,
+        cursor: i64,
+    }
+    let frame = |i: usize, until: i64| {
+        let mut children = nodes[i].children.clone();
+        // The latest finishing first.
+        children.sort_unstable_by_key(|&child| std::cmp::Reverse(nodes[child].end));
+        Frame {
+            i,
+            children: children.into_iter(),
+            cursor: until.min(nodes[i].end),
+        }
+    };
+
+    // The stack is explicit as a trace may be deeper than the thread stack allows.
+    let mut stack = vec![frame(i, until)];
+    while let Some(top) = stack.last_mut() {
+        let node = &nodes[top.i];
+        let mut next = None;
+        while top.cursor > node.start {
+            let Some(child) = top.children.next() else {
+                break;
+            };
+            let child_start = nodes[child].start.max(node.start);
+            if child_start >= top.cursor {
+                // Overlapped by a later child on the path.
+                continue;
+            }
+            let child_end = nodes[child].end.min(top.cursor);
+            if child_end < top.cursor {
+                path.push((top.i, child_end, top.cursor));
+            }
+            top.cursor = child_start;
+            next = Some((child, child_end));
+            break;
+        }
+        match next {
+            Some((child, until)) => stack.push(frame(child, until)),
+            None => {
+                if top.cursor > node.start {
+                    path.push((top.i, node.start, top.cursor));
+                }
+                stack.pop();
+            }
+        }
+    }
+}
+
+fn operation_times(spans: &[SpanTime]) -> Vec<OperationTime> {
+    let mut operations = HashMap::<(&str, &str), OperationTime>::new();
+    for span in spans {
+        let operation = operations
+            .entry((span.service.as_str(), span.operation.as_str()))
+            .or_insert_with(|| OperationTime {
+                service: span.service.clone(),
+                operation: span.operation.clone(),
+                count: 0,
+                total_time: 0,
+                self_time: 0,
+                critical_time: 0,
+            });
+        operation.count += 1;
+        operation.total_time += span.duration;
+        operation.self_time += span.self_time;
+        operation.critical_time += span.critical_time;
+    }
+    let mut operations = operations.into_values().collect::<Vec<_>>();
+    operations.sort_by(|a, b| {
+        b.self_time
+            .cmp(&a.self_time)
+            .then_with(|| (&a.service, &a.operation).cmp(&(&b.service, &b.operation)))
+    });
+    operations
+}
+
+fn service_times(spans: &[SpanTime]) -> Vec<ServiceTime> {
+    let mut services = HashMap::<&str, ServiceTime>::new();
+    for span in spans {
+        let service = services
+            .entry(&span.service)
+            .or_insert_with(|| ServiceTime {
+                service: span.service.clone(),
+                self_time: 0,
+                critical_time: 0,
+            });
+        service.self_time += span.self_time;
+        service.critical_time += span.critical_time;
+    }
+    let mut services = services.into_values().collect::<Vec<_>>();
+    services.sort_by(|a, b| {
+        b.self_time
+            .cmp(&a.self_time)
+            .then_with(|| a.service.cmp(&b.service))
+    });
+    services
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::testing::{span, trace};
+
+    #[test]
+    fn test_analyze() {
+        // 1: [0, 100] web
+        //   2: [10, 50] api
+        //     4: [20, 30] api
+        //   3: [40, 90] api, overlaps 2
+        let analysis = analyze(&trace(
+            1,
+            vec![
+                span(1, None, 0, Some(100)).by("web"),
+                span(2, Some(1), 10, Some(50)),
+                span(3, Some(1), 40, Some(90)),
+                span(4, Some(2), 20, Some(30)),
+            ],
+        ));
+        assert_eq!(analysis.duration, 100);
+
+        let self_times = analysis
+            .spans
+            .iter()
+            .map(|span| (span.span_id.as_str(), span.self_time))
+            .collect::<HashMap<_, _>>();
+        assert_eq!(
+            self_times,
+            HashMap::from([("1", 20), ("2", 30), ("3", 50), ("4", 10)])
+        );
+
+        let path = analysis
+            .critical_path
+            .iter()
+            .map(|segment| (segment.span_id.as_str(), segment.start, segment.end))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            path,
+            vec![
+                ("1", 0, 10),
+                ("2", 10, 20),
+                ("4", 20, 30),
+                ("2", 30, 40),
+                ("3", 40, 90),
+                ("1", 90, 100)
+            ]
+        );
+
+        assert_eq!(analysis.services[0].service, "api");
+        assert_eq!(analysis.services[0].self_time, 90);
+        assert_eq!(analysis.services[0].critical_time, 80);
+    }
+
+    #[test]
+    fn test_analyze_unfinished() {
+        let analysis = analyze(&trace(
+            1,
+            vec![
+                span(1, None, 0, None).by("web"),
+                span(2, Some(1), 10, Some(50)),
+            ],
+        ));
+        assert_eq!(analysis.duration, 50);
+        assert_eq!(analysis.spans[0].self_time, 10);
+    }
+
+    #[test]
+    fn test_analyze_parent_cycle() {
+        let analysis = analyze(&trace(
+            1,
+            vec![
+                span(1, Some(2), 0, None).by("web"),
+                span(2, Some(1), 10, Some(50)),
+                span(3, None, 0, Some(20)).by("web"),
+            ],
+        ));
+        assert_eq!(analysis.duration, 50);
+        assert_eq!(analysis.spans[0].duration, 0);
+        assert_eq!(analysis.spans[0].self_time, 0);
+        assert_eq!(analysis.spans[1].duration, 40);
+    }
+
+    #[test]
+    fn test_analyze_deep_trace() {
+        // Deeper than the test thread stack allows to recurse.
+        let depth = 100_000;
+        let spans = (1..=depth)
+            .map(|id| {
+                let parent_id = (id > 1).then(|| id - 1);
+                let end = (id == depth).then_some(depth as i64 + 1);
+                span(id, parent_id, id as i64, end)
+            })
+            .collect();
+        let analysis = analyze(&trace(1, spans));
+        assert_eq!(analysis.duration, depth as i64);
+        assert_eq!(analysis.critical_path.len(), depth as usize);
+        assert_eq!(analysis.spans[0].self_time, 1);
+    }
+}
@@ -52,7 +52,7 @@ pub fn convert_span_to_record_batch(spans: Vec<Span>) -> Result<RecordBatch> {
     )?)
 }
 
-pub fn convert_log_to_record_batch(logs: Vec<Log>) -> Result<RecordBatch> {
+pub fn convert_log_to_record_batch(tenant: &str, logs: Vec<Log>) -> Result<RecordBatch> {
     let mut data = vec![];
     let mut fields = vec![];
     for log in logs {
@@ -81,10 +81,9 @@ pub fn convert_log_to_record_batch(logs: Vec<Log>) -> Result<RecordBatch> {
 
     let inferred_field_schema = infer_json_schema_from_iterator(fields.iter().map(Ok))?;
     let schema = Schema::try_merge(vec![
-        (*schema::get_log_schema()).clone(),
+        (*schema::get_log_schema(tenant)).clone(),
         inferred_field_schema,
-    ])
-    .unwrap();
+    ])?;
     let mut decoder = ReaderBuilder::new(Arc::new(schema)).build_decoder()?;
     decoder.serialize(&data)?;
     let batch = decoder.flush()?.expect("Empty record batch");
@@ -0,0 +1,131 @@
+use std::{fmt, fs};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+use crate::config::{ApiKey, AuthConfig, Scope};
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum AuthError {
+    /// No credential or an unknown one.
+    Unauthenticated,
+    /// The key lacks the scope or belongs to another tenant.
+    Forbidden,
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::Unauthenticated => write!(f, "missing or invalid API key"),
+            AuthError::Forbidden => write!(f, "API key not allowed"),
+        }
+    }
+}
+
+/// Validate the API key of the requests.
+#[derive(Debug)]
+pub struct Authenticator {
+    keys: Vec<ApiKey>,
+}
+
+impl Authenticator {
+    pub fn load(config: &AuthConfig) -> Result<Self> {
+        #[derive(Deserialize)]
+        struct KeysFile {
+            keys: Vec<ApiKey>,
+        }
+
+        let mut keys = config.keys.clone();
+        if let Some(path) = &config.keys_file {
+            let content =
+                fs::read_to_string(path).with_context(|| format!("Read `{path}` failed"))?;
+            let file = toml::from_str::<KeysFile>(&content)
+                .with_context(|| format!("Parse `{path}` failed"))?;
+            keys.extend(file.keys);
+        }
+        Ok(Authenticator { keys })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.keys.is_empty()
+    }
+
+    /// A key to ingest into the default tenant, used to collect duo itself.
+    pub fn ingest_key(&self) -> Option<&str> {
+        self.keys
+            .iter()
+            .find(|key| key.scopes.contains(&Scope::Ingest) && key.tenant.is_none())
+            .map(|key| key.key.as_str())
+    }
+
+    /// Check the `authorization` header, either `Bearer <key>` or the bare key,
+    /// grants the scope on the tenant.
+    pub fn authorize(
+        &self,
+        header: Option<&str>,
+        scope: Scope,
+        tenant: &str,
+    ) -> Result<(), AuthError> {
+        if !self.is_enabled() {
+            return Ok(());
+        }
+
+        let header = header.ok_or(AuthError::Unauthenticated)?.trim();
+        let token = header.strip_prefix("Bearer ").unwrap_or(header).trim();
+        let key = self
+            .keys
+            .iter()
+            .find(|key| constant_time_eq(key.key.as_bytes(), token.as_bytes()))
+            .ok_or(AuthError::Unauthenticated)?;
+        if !key.scopes.contains(&scope) {
+            return Err(AuthError::Forbidden);
+        }
+        match &key.tenant {
+            Some(allowed) if allowed != tenant => Err(AuthError::Forbidden),
+            _ => Ok(()),
+        }
+    }
+}
+
+// Don't leak the matched prefix length through timing.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_authorize() {
+        let authenticator = Authenticator {
+            keys: vec![ApiKey {
+                key: String::from("secret"),
+                scopes: vec![Scope::Read],
+                tenant: Some(String::from("team-a")),
+            }],
+        };
+        let authorize = |header, scope, tenant| authenticator.authorize(header, scope, tenant);
+        assert_eq!(
+            authorize(Some("Bearer secret"), Scope::Read, "team-a"),
+            Ok(())
+        );
+        assert_eq!(authorize(Some("secret"), Scope::Read, "team-a"), Ok(()));
+        assert_eq!(
+            authorize(None, Scope::Read, "team-a"),
+            Err(AuthError::Unauthenticated)
+        );
+        assert_eq!(
+            authorize(Some("Bearer secrets"), Scope::Read, "team-a"),
+            Err(AuthError::Unauthenticated)
+        );
+        assert_eq!(
+            authorize(Some("Bearer secret"), Scope::Ingest, "team-a"),
+            Err(AuthError::Forbidden)
+        );
+        assert_eq!(
+            authorize(Some("Bearer secret"), Scope::Read, "team-b"),
+            Err(AuthError::Forbidden)
+        );
+    }
+}
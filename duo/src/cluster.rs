@@ -0,0 +1,176 @@
+use std::{
+    io::Cursor,
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+
+use anyhow::Result;
+use arrow_schema::SchemaRef;
+use datafusion::arrow::{
+    array::{new_null_array, RecordBatch},
+    compute::cast,
+    ipc::reader::StreamReader,
+};
+use duo_api::peer::{peer_client::PeerClient, MemoryBatchesRequest, MemoryLinksRequest};
+use tonic::{
+    metadata::MetadataValue,
+    transport::{Channel, Endpoint},
+    Request,
+};
+
+use crate::{
+    config,
+    dependency::DependencyLink,
+    schema,
+    tenant::{Tenants, TENANT_HEADER},
+};
+
+static PEERS: OnceLock<Vec<(String, Channel)>> = OnceLock::new();
+
+/// Periodically load the processes and log schema shared by the nodes,
+/// the first load happens immediately.
+pub fn spawn_refresh(tenants: Arc<Tenants>) {
+    let refresh_secs = config::load().cluster.refresh_secs;
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(refresh_secs));
+        loop {
+            interval.tick().await;
+
+            for tenant in tenants.all() {
+                if let Err(err) = tenant.reload_processes().await {
+                    println!("load processes failed: tenant {}, {err:#}", tenant.id);
+                }
+                if let Err(err) = schema::reload_log_schema(&tenant.id).await {
+                    println!("reload log schema failed: tenant {}, {err:#}", tenant.id);
+                }
+            }
+        }
+    });
+}
+
+/// The channels connect lazily and reconnect on failures.
+fn peers() -> &'static [(String, Channel)] {
+    PEERS.get_or_init(|| {
+        let config = config::load();
+        let timeout = Duration::from_millis(config.cluster.peer_timeout_ms);
+        config
+            .cluster
+            .peers
+            .iter()
+            .filter_map(|peer| {
+                let endpoint = Endpoint::from_shared(peer.clone()).ok()?;
+                let channel = endpoint
+                    .connect_timeout(timeout)
+                    .timeout(timeout)
+                    .connect_lazy();
+                Some((peer.clone(), channel))
+            })
+            .collect()
+    })
+}
+
+/// The in-memory batches of the table on all the peers, aligned to `schema`.
+/// The unreachable peers are skipped, their data shows up once persisted.
+pub async fn peer_batches(tenant: &str, table: &str, schema: &SchemaRef) -> Vec<RecordBatch> {
+    let fetches = peers().iter().map(|(peer, channel)| async move {
+        match fetch(channel.clone(), tenant, table).await {
+            Ok(batches) => batches,
+            Err(err) => {
+                println!("read peer {peer} failed: {err:#}");
+                Vec::new()
+            }
+        }
+    });
+    futures::future::join_all(fetches)
+        .await
+        .into_iter()
+        .flatten()
+        .filter_map(|batch| align_batch(&batch, schema).ok())
+        .collect()
+}
+
+/// The request to a peer on behalf of the tenant.
+fn peer_request<T>(tenant: &str, message: T) -> Result<Request<T>> {
+    let mut request = Request::new(message);
+    let metadata = request.metadata_mut();
+    metadata.insert(TENANT_HEADER, MetadataValue::try_from(tenant)?);
+    if let Some(key) = &config::load().cluster.peer_key {
+        metadata.insert(
+            "authorization",
+            MetadataValue::try_from(format!("Bearer {key}"))?,
+        );
+    }
+    Ok(request)
+}
+
+async fn fetch(channel: Channel, tenant: &str, table: &str) -> Result<Vec<RecordBatch>> {
+    let request = peer_request(
+        tenant,
+        MemoryBatchesRequest {
+            table: table.to_string(),
+        },
+    )?;
+    let response = PeerClient::new(channel).memory_batches(request).await?;
+    let mut batches = Vec::new();
+    for data in response.into_inner().batches {
+        let reader = StreamReader::try_new(Cursor::new(data), None)?;
+        for batch in reader {
+            batches.push(batch?);
+        }
+    }
+    Ok(batches)
+}
+
+/// The service links each peer resolved between its in-memory spans and the
+/// spans it has written, see [`crate::dependency::memory_links`].
+/// The unreachable peers are skipped like [`peer_batches`].
+pub async fn peer_links(tenant: &str, start: i64, end: i64) -> Vec<DependencyLink> {
+    let fetches = peers().iter().map(|(peer, channel)| async move {
+        match fetch_links(channel.clone(), tenant, start, end).await {
+            Ok(links) => links,
+            Err(err) => {
+                println!("read peer {peer} links failed: {err:#}");
+                Vec::new()
+            }
+        }
+    });
+    futures::future::join_all(fetches)
+        .await
+        .into_iter()
+        .flatten()
+        .collect()
+}
+
+async fn fetch_links(
+    channel: Channel,
+    tenant: &str,
+    start: i64,
+    end: i64,
+) -> Result<Vec<DependencyLink>> {
+    let request = peer_request(tenant, MemoryLinksRequest { start, end })?;
+    let response = PeerClient::new(channel).memory_links(request).await?;
+    Ok(response
+        .into_inner()
+        .links
+        .into_iter()
+        .map(|link| DependencyLink {
+            parent: link.parent,
+            child: link.child,
+            call_count: link.call_count,
+        })
+        .collect())
+}
+
+/// Reorder the columns to the schema, fill the missing ones with nulls.
+fn align_batch(batch: &RecordBatch, schema: &SchemaRef) -> Result<RecordBatch> {
+    let columns = schema
+        .fields()
+        .iter()
+        .map(|field| match batch.column_by_name(field.name()) {
+            Some(column) if column.data_type() == field.data_type() => Ok(Arc::clone(column)),
+            Some(column) => Ok(cast(column, field.data_type())?),
+            None => Ok(new_null_array(field.data_type(), batch.num_rows())),
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(RecordBatch::try_new(Arc::clone(schema), columns)?)
+}
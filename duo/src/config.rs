@@ -1,20 +1,42 @@
+#[cfg(unix)]
+use std::path::PathBuf;
 use std::{
-    env, fs,
+    env, fmt, fs,
+    net::SocketAddr,
     path::Path,
     sync::{Arc, OnceLock},
 };
 
-use anyhow::{Context, Result};
-use object_store::{aws::AmazonS3Builder, local::LocalFileSystem, ObjectStore};
+use anyhow::{anyhow, bail, Context, Result};
+use object_store::{
+    aws::AmazonS3Builder, azure::MicrosoftAzureBuilder, gcp::GoogleCloudStorageBuilder,
+    local::LocalFileSystem, ObjectStore,
+};
 use serde::Deserialize;
+use time::{Duration, OffsetDateTime};
 use url::Url;
 
+use crate::tenant;
+
 static DUO_CONFIG: OnceLock<Arc<DuoConfig>> = OnceLock::new();
+/// The prefix of the environment variables which override the config.
+const ENV_PREFIX: &str = "DUO_";
 
 #[derive(Debug, Deserialize)]
+#[serde(default)]
 pub struct DuoConfig {
     pub data_dir: String,
     storage: StorageConfig,
+    pub aggregator: AggregatorConfig,
+    pub sampling: SamplingConfig,
+    /// The tenants accepted besides the default one, any valid id if empty.
+    pub tenants: Vec<String>,
+    /// The maximum tenants created on demand when `tenants` is empty.
+    pub max_tenants: usize,
+    pub auth: AuthConfig,
+    pub server: ServersConfig,
+    pub tiering: Option<TieringConfig>,
+    pub cluster: ClusterConfig,
 }
 
 impl Default for DuoConfig {
@@ -22,22 +44,391 @@ impl Default for DuoConfig {
         Self {
             data_dir: "data".to_string(),
             storage: Default::default(),
+            aggregator: Default::default(),
+            sampling: Default::default(),
+            tenants: Vec::new(),
+            max_tenants: 100,
+            auth: Default::default(),
+            server: Default::default(),
+            tiering: None,
+            cluster: Default::default(),
+        }
+    }
+}
+
+/// The listen addresses and TLS of the servers.
+///
+/// ```toml
+/// [server.web]
+/// addr = "[::]:3000"
+///
+/// [server.grpc]
+/// addr = "unix:/run/duo/ingest.sock"
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ServersConfig {
+    pub web: ServerConfig,
+    pub grpc: ServerConfig,
+}
+
+impl ServersConfig {
+    pub fn web_addr(&self) -> ListenAddr {
+        self.web.addr_or(3000)
+    }
+
+    pub fn grpc_addr(&self) -> ListenAddr {
+        self.grpc.addr_or(6000)
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    /// Listen on all interfaces if absent.
+    pub addr: Option<ListenAddr>,
+    /// Serve over TLS, plaintext if absent.
+    pub tls: Option<TlsConfig>,
+}
+
+impl ServerConfig {
+    fn addr_or(&self, port: u16) -> ListenAddr {
+        self.addr
+            .clone()
+            .unwrap_or_else(|| ListenAddr::Tcp(SocketAddr::from(([0, 0, 0, 0], port))))
+    }
+
+    /// Override the port, listen on all interfaces unless a TCP address configured.
+    pub fn set_port(&mut self, port: u16) {
+        let addr = match self.addr.take() {
+            Some(ListenAddr::Tcp(mut addr)) => {
+                addr.set_port(port);
+                addr
+            }
+            _ => SocketAddr::from(([0, 0, 0, 0], port)),
+        };
+        self.addr = Some(ListenAddr::Tcp(addr));
+    }
+}
+
+/// A TCP socket address (`0.0.0.0:6000`, `[::1]:6000`)
+/// or a unix domain socket path (`unix:/path/to/duo.sock`).
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl TryFrom<String> for ListenAddr {
+    type Error = String;
+
+    fn try_from(addr: String) -> Result<Self, Self::Error> {
+        if let Some(path) = addr.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err(String::from("empty unix socket path"));
+            }
+            #[cfg(unix)]
+            return Ok(ListenAddr::Unix(PathBuf::from(path)));
+            #[cfg(not(unix))]
+            return Err(String::from(
+                "unix sockets are not supported on this platform",
+            ));
+        }
+        addr.parse()
+            .map(ListenAddr::Tcp)
+            .map_err(|err| format!("invalid listen address `{addr}`: {err}"))
+    }
+}
+
+/// The PEM files of a server certificate.
+///
+/// ```toml
+/// [server.grpc.tls]
+/// cert = "server.pem"
+/// key = "server.key"
+/// client_ca = "ca.pem"
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+pub struct TlsConfig {
+    /// The certificate chain.
+    pub cert: String,
+    /// The private key.
+    pub key: String,
+    /// Require the clients to present a certificate signed by this CA (mTLS).
+    pub client_ca: Option<String>,
+}
+
+/// The PEM contents of the certificate, key and client CA.
+pub type TlsPem = (Vec<u8>, Vec<u8>, Option<Vec<u8>>);
+
+impl TlsConfig {
+    /// Read the certificate, key and client CA.
+    pub fn read(&self) -> Result<TlsPem> {
+        let read = |path: &String| fs::read(path).with_context(|| format!("Read `{path}` failed"));
+        let client_ca = match &self.client_ca {
+            Some(path) => Some(read(path)?),
+            None => None,
+        };
+        Ok((read(&self.cert)?, read(&self.key)?, client_ca))
+    }
+}
+
+/// The API keys accepted by the gRPC and web servers,
+/// everything is open if no key configured.
+///
+/// ```toml
+/// [[auth.keys]]
+/// key = "secret"
+/// scopes = ["ingest", "read"]
+/// tenant = "team-a"
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct AuthConfig {
+    pub keys: Vec<ApiKey>,
+    /// Load more keys from a TOML file of `[[keys]]` tables,
+    /// to keep them out of the main config.
+    pub keys_file: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ApiKey {
+    pub key: String,
+    pub scopes: Vec<Scope>,
+    /// Restrict the key to the tenant.
+    pub tenant: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    /// Send spans and logs to the gRPC server.
+    Ingest,
+    /// Query the web API.
+    Read,
+}
+
+/// How long and how many unfinished spans are held in memory.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AggregatorConfig {
+    /// Force emit the span not updated for this long, marked as `incomplete`.
+    pub span_timeout_secs: u64,
+    /// The maximum unfinished spans held, the least recently
+    /// updated ones are force emitted beyond it.
+    pub max_spans: usize,
+}
+
+impl Default for AggregatorConfig {
+    fn default() -> Self {
+        Self {
+            span_timeout_secs: 600,
+            max_spans: 100_000,
+        }
+    }
+}
+
+/// Tail-based sampling, decided once the root span of a trace closed.
+///
+/// ```toml
+/// [sampling]
+/// rate = 0.1
+/// keep_errors = true
+/// latency_threshold_ms = 500
+///
+/// [[sampling.rules]]
+/// service = "payment"
+/// rate = 1.0
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SamplingConfig {
+    /// The probability to keep a trace which matches nothing below,
+    /// `1.0` disables the tail sampling.
+    pub rate: f64,
+    /// Always keep the traces which have an error.
+    pub keep_errors: bool,
+    /// Always keep the traces whose root span lasts longer than this.
+    pub latency_threshold_ms: Option<u64>,
+    /// How long to wait for the root span before deciding with the spans we have.
+    pub decision_wait_secs: u64,
+    /// The first matched rule overrides the rate.
+    pub rules: Vec<SamplingRule>,
+}
+
+impl Default for SamplingConfig {
+    fn default() -> Self {
+        Self {
+            rate: 1.0,
+            keep_errors: true,
+            latency_threshold_ms: None,
+            decision_wait_secs: 30,
+            rules: Vec::new(),
         }
     }
 }
 
+impl SamplingConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.rate < 1.0 || !self.rules.is_empty()
+    }
+}
+
+/// Match the traces which have a span of the service and operation.
+#[derive(Debug, Clone, Deserialize)]
+pub struct SamplingRule {
+    pub service: Option<String>,
+    pub operation: Option<String>,
+    #[serde(default = "default_rule_rate")]
+    pub rate: f64,
+}
+
+fn default_rule_rate() -> f64 {
+    1.0
+}
+
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "snake_case")]
 enum StorageConfig {
     Local {
         dir: Option<String>,
     },
+    /// AWS S3 or a S3 compatible service like MinIO.
     S3 {
         bucket: String,
-        region: String,
+        region: Option<String>,
+        /// The custom endpoint of a S3 compatible service.
+        endpoint: Option<String>,
+        /// Request `https://<bucket>.<endpoint>` instead of
+        /// the path-style `https://<endpoint>/<bucket>`.
+        #[serde(default)]
+        virtual_hosted_style: bool,
+        /// Allow a plain `http://` endpoint.
+        #[serde(default)]
+        allow_http: bool,
+        /// Use the `AWS_*` environment variables or the instance profile
+        /// if no access key configured.
         aws_access_key: Option<String>,
         aws_access_secret: Option<String>,
+        aws_session_token: Option<String>,
+    },
+    /// Google Cloud Storage, the `GOOGLE_*` environment variables also apply.
+    Gcs {
+        bucket: String,
+        /// The service account JSON file, use the application
+        /// default credentials if absent.
+        service_account_path: Option<String>,
     },
+    /// Azure Blob Storage, the `AZURE_*` environment variables also apply.
+    Azure {
+        account: String,
+        container: String,
+        /// Use the managed identity if absent.
+        access_key: Option<String>,
+    },
+}
+
+/// Run several duo nodes sharing one object store.
+///
+/// ```toml
+/// [cluster]
+/// node_id = "duo-1"
+/// role = "collector"
+/// peers = ["http://duo-2:6000"]
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ClusterConfig {
+    /// Unique among the nodes, names the partition files written by this node.
+    pub node_id: String,
+    pub role: NodeRole,
+    /// The gRPC URIs of the other collectors, whose in-memory data is
+    /// merged into the queries. Only the persisted data of the nodes
+    /// absent here is visible.
+    pub peers: Vec<String>,
+    /// The API key with the `read` scope, to read from the peers when auth enabled.
+    pub peer_key: Option<String>,
+    /// The timeout to read the in-memory data from a peer.
+    pub peer_timeout_ms: u64,
+    /// How often to reload the processes and log schema shared by the nodes.
+    pub refresh_secs: u64,
+}
+
+impl Default for ClusterConfig {
+    fn default() -> Self {
+        Self {
+            node_id: env::var("HOSTNAME")
+                .ok()
+                .filter(|hostname| tenant::is_valid_id(hostname))
+                .unwrap_or_else(|| format!("{:08x}", rand::random::<u32>())),
+            role: NodeRole::default(),
+            peers: Vec::new(),
+            peer_key: None,
+            peer_timeout_ms: 1000,
+            refresh_secs: 30,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NodeRole {
+    /// Serve both the gRPC ingest and the web API.
+    #[default]
+    All,
+    /// Only serve the gRPC ingest.
+    Collector,
+    /// Only serve the web API.
+    Query,
+}
+
+impl NodeRole {
+    pub fn collects(self) -> bool {
+        self != NodeRole::Query
+    }
+
+    pub fn queries(self) -> bool {
+        self != NodeRole::Collector
+    }
+}
+
+/// Keep the recent partitions in `storage` for fast queries,
+/// move the older ones to the cold storage.
+///
+/// ```toml
+/// [tiering]
+/// hot_hours = 24
+///
+/// [tiering.cold.s3]
+/// bucket = "duo-archive"
+/// region = "us-east-1"
+/// ```
+#[derive(Debug, Deserialize)]
+pub struct TieringConfig {
+    /// Move the partitions older than this.
+    #[serde(default = "default_hot_hours")]
+    pub hot_hours: u64,
+    /// How often to look for the partitions to move.
+    #[serde(default = "default_move_interval_secs")]
+    pub move_interval_secs: u64,
+    cold: StorageConfig,
+}
+
+impl TieringConfig {
+    /// The partitions before it belong to the cold storage.
+    pub fn cutoff(&self) -> OffsetDateTime {
+        OffsetDateTime::now_utc() - Duration::hours(self.hot_hours as i64)
+    }
+}
+
+fn default_hot_hours() -> u64 {
+    24
+}
+
+fn default_move_interval_secs() -> u64 {
+    600
 }
 
 impl Default for StorageConfig {
@@ -46,6 +437,42 @@ impl Default for StorageConfig {
     }
 }
 
+/// Override the config keys with the `DUO_` prefixed environment variables,
+/// nested keys are joined by `__`, e.g. `DUO_SERVER__WEB__ADDR=[::]:3000`
+/// sets `server.web.addr`. The value is parsed as a TOML value, or taken
+/// as a string if it isn't one.
+fn apply_env(table: &mut toml::Table, vars: impl Iterator<Item = (String, String)>) {
+    for (name, value) in vars {
+        let Some(key) = name.strip_prefix(ENV_PREFIX) else {
+            continue;
+        };
+        let key = key.to_lowercase();
+        let mut path = key.split("__").filter(|segment| !segment.is_empty());
+        let Some(mut segment) = path.next() else {
+            continue;
+        };
+
+        let mut current = &mut *table;
+        for next in path {
+            let entry = current
+                .entry(segment)
+                .or_insert(toml::Value::Table(toml::Table::new()));
+            if !entry.is_table() {
+                *entry = toml::Value::Table(toml::Table::new());
+            }
+            current = entry.as_table_mut().unwrap();
+            segment = next;
+        }
+
+        let value = format!("value = {value}")
+            .parse::<toml::Table>()
+            .ok()
+            .and_then(|mut parsed| parsed.remove("value"))
+            .unwrap_or(toml::Value::String(value));
+        current.insert(segment.to_string(), value);
+    }
+}
+
 pub fn load() -> Arc<DuoConfig> {
     Arc::clone(DUO_CONFIG.get().expect("DuoConfig not initialized"))
 }
@@ -57,77 +484,449 @@ pub fn set(config: DuoConfig) {
 }
 
 impl DuoConfig {
-    pub fn parse_from_toml<P: AsRef<Path>>(source: P) -> Result<Self> {
-        let source = source.as_ref();
-        let content = fs::read_to_string(source)
-            .with_context(|| format!("Read `{}` failed", source.display()))?;
+    /// Load the config file if any, apply the environment overrides, then validate it.
+    pub fn load<P: AsRef<Path>>(source: Option<P>) -> Result<Self> {
+        let mut table = match &source {
+            Some(source) => {
+                let source = source.as_ref();
+                let content = fs::read_to_string(source)
+                    .with_context(|| format!("Read `{}` failed", source.display()))?;
+                // Deserialize the file alone first, to report the errors with their line.
+                toml::from_str::<DuoConfig>(&content)
+                    .with_context(|| format!("Parse `{}` failed", source.display()))?;
+                toml::from_str::<toml::Table>(&content)?
+            }
+            None => toml::Table::new(),
+        };
+        apply_env(&mut table, env::vars());
 
-        Ok(toml::from_str::<DuoConfig>(&content)
-            .unwrap_or_else(|err| panic!("Parse `{}` failed: {}", source.display(), err)))
+        let config = toml::Value::Table(table)
+            .try_into::<DuoConfig>()
+            .context("Invalid `DUO_*` environment variables")?;
+        config.validate()?;
+        Ok(config)
     }
 
-    pub fn object_store_url(&self) -> Url {
-        match &self.storage {
-            StorageConfig::Local { dir } => {
-                let dir = dir.as_ref().unwrap_or(&self.data_dir);
-                let path = Path::new(&dir);
-                if path.is_relative() {
-                    if let Ok(cwd) = env::current_dir() {
-                        let path = cwd.join(path);
-                        if path.is_relative() {
-                            panic!("Invalid path: {}", path.display());
-                        }
-                        // A trailing slash is significant. Without it, the last path component
-                        // is considered to be a “file” name to be removed to get at the “directory”
-                        // that is used as the base.
-                        // https://docs.rs/url/latest/url/struct.Url.html#method.join
-                        return Url::parse(&format!("file://{}/", path.display())).unwrap();
-                    }
+    /// Check the values which deserialize fine but can't work,
+    /// report all the problems at once.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut problems = Vec::new();
+        let mut check = |ok: bool, problem: String| {
+            if !ok {
+                problems.push(problem);
+            }
+        };
+
+        check(
+            !self.data_dir.is_empty(),
+            String::from("data_dir: must not be empty"),
+        );
+        self.storage.check("storage", &self.data_dir, &mut check);
+        if let Some(tiering) = &self.tiering {
+            tiering
+                .cold
+                .check("tiering.cold", &self.data_dir, &mut check);
+            check(
+                !matches!(tiering.cold, StorageConfig::Local { .. }),
+                String::from("tiering.cold: must be an object store"),
+            );
+            check(
+                tiering.hot_hours > 0,
+                String::from("tiering.hot_hours: must be positive"),
+            );
+            check(
+                tiering.move_interval_secs > 0,
+                String::from("tiering.move_interval_secs: must be positive"),
+            );
+        }
+
+        check(
+            self.aggregator.span_timeout_secs > 0,
+            String::from("aggregator.span_timeout_secs: must be positive"),
+        );
+        check(
+            self.aggregator.max_spans > 0,
+            String::from("aggregator.max_spans: must be positive"),
+        );
+
+        let is_rate = |rate: f64| (0.0..=1.0).contains(&rate);
+        check(
+            is_rate(self.sampling.rate),
+            format!(
+                "sampling.rate: {} is not between 0 and 1",
+                self.sampling.rate
+            ),
+        );
+        for (i, rule) in self.sampling.rules.iter().enumerate() {
+            check(
+                is_rate(rule.rate),
+                format!(
+                    "sampling.rules[{i}].rate: {} is not between 0 and 1",
+                    rule.rate
+                ),
+            );
+        }
+
+        for tenant in &self.tenants {
+            check(
+                tenant::is_valid_id(tenant),
+                format!("tenants: invalid tenant `{tenant}`"),
+            );
+        }
+
+        for (i, key) in self.auth.keys.iter().enumerate() {
+            check(
+                !key.key.is_empty(),
+                format!("auth.keys[{i}].key: must not be empty"),
+            );
+            check(
+                !key.scopes.is_empty(),
+                format!("auth.keys[{i}].scopes: must not be empty"),
+            );
+            if let Some(tenant) = &key.tenant {
+                check(
+                    tenant::is_valid_id(tenant),
+                    format!("auth.keys[{i}].tenant: invalid tenant `{tenant}`"),
+                );
+            }
+        }
+        if let Some(keys_file) = &self.auth.keys_file {
+            check(
+                Path::new(keys_file).is_file(),
+                format!("auth.keys_file: `{keys_file}` not found"),
+            );
+        }
+
+        check(
+            tenant::is_valid_id(&self.cluster.node_id),
+            format!(
+                "cluster.node_id: invalid node id `{}`",
+                self.cluster.node_id
+            ),
+        );
+        for (i, peer) in self.cluster.peers.iter().enumerate() {
+            check(
+                peer.parse::<tonic::transport::Uri>().is_ok(),
+                format!("cluster.peers[{i}]: invalid URI `{peer}`"),
+            );
+        }
+        check(
+            self.cluster.refresh_secs > 0,
+            String::from("cluster.refresh_secs: must be positive"),
+        );
+
+        #[cfg(unix)]
+        check(
+            !matches!(self.server.web_addr(), ListenAddr::Unix(_)),
+            String::from("server.web.addr: the web server only listens on TCP"),
+        );
+        for (name, server) in [("web", &self.server.web), ("grpc", &self.server.grpc)] {
+            let Some(tls) = &server.tls else {
+                continue;
+            };
+            let files = [
+                ("cert", Some(&tls.cert)),
+                ("key", Some(&tls.key)),
+                ("client_ca", tls.client_ca.as_ref()),
+            ];
+            for (field, path) in files {
+                if let Some(path) = path {
+                    check(
+                        Path::new(path).is_file(),
+                        format!("server.{name}.tls.{field}: `{path}` not found"),
+                    );
                 }
-                Url::parse(&format!("file://{dir}/")).unwrap()
             }
-            StorageConfig::S3 { bucket, .. } => Url::parse(&format!("s3://{bucket}/")).unwrap(),
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError(problems))
         }
     }
 
-    pub fn object_store(&self) -> Arc<dyn ObjectStore> {
-        match &self.storage {
-            StorageConfig::Local { dir } => {
-                let dir = dir.as_ref().unwrap_or(&self.data_dir);
-                let path = Path::new(dir);
-                if !path.exists() {
-                    std::fs::create_dir_all(path).unwrap();
+    pub fn object_store_url(&self) -> Result<Url> {
+        self.storage.url(&self.data_dir)
+    }
+
+    pub fn object_store(&self) -> Result<Arc<dyn ObjectStore>> {
+        self.storage.store(&self.data_dir)
+    }
+
+    /// The cold storage url if tiering configured.
+    pub fn cold_object_store_url(&self) -> Result<Option<Url>> {
+        self.tiering
+            .as_ref()
+            .map(|tiering| tiering.cold.url(&self.data_dir))
+            .transpose()
+    }
+
+    /// The cold storage if tiering configured.
+    pub fn cold_object_store(&self) -> Result<Option<Arc<dyn ObjectStore>>> {
+        self.tiering
+            .as_ref()
+            .map(|tiering| tiering.cold.store(&self.data_dir))
+            .transpose()
+    }
+}
+
+impl StorageConfig {
+    /// Report the problems of the storage configured at `name`.
+    fn check(&self, name: &str, data_dir: &str, check: &mut impl FnMut(bool, String)) {
+        if let Err(err) = self.url(data_dir) {
+            check(false, format!("{name}: {err}"));
+        }
+        match self {
+            StorageConfig::Local { .. } => {}
+            StorageConfig::S3 {
+                bucket,
+                aws_access_key,
+                aws_access_secret,
+                aws_session_token,
+                ..
+            } => {
+                check(
+                    !bucket.is_empty(),
+                    format!("{name}.s3.bucket: must not be empty"),
+                );
+                if let Err(err) =
+                    s3_credentials(aws_access_key, aws_access_secret, aws_session_token)
+                {
+                    check(false, format!("{name}.s3: {err}"));
                 }
+            }
+            StorageConfig::Gcs {
+                bucket,
+                service_account_path,
+            } => {
+                check(
+                    !bucket.is_empty(),
+                    format!("{name}.gcs.bucket: must not be empty"),
+                );
+                if let Some(path) = service_account_path {
+                    check(
+                        Path::new(path).is_file(),
+                        format!("{name}.gcs.service_account_path: `{path}` not found"),
+                    );
+                }
+            }
+            StorageConfig::Azure {
+                account, container, ..
+            } => {
+                check(
+                    !account.is_empty(),
+                    format!("{name}.azure.account: must not be empty"),
+                );
+                check(
+                    !container.is_empty(),
+                    format!("{name}.azure.container: must not be empty"),
+                );
+            }
+        }
+    }
+
+    fn url(&self, data_dir: &str) -> Result<Url> {
+        match self {
+            StorageConfig::Local { dir } => {
+                let dir = dir.as_deref().unwrap_or(data_dir);
+                let path = env::current_dir()
+                    .context("Get the current directory failed")?
+                    .join(dir);
+                // A trailing slash is significant. Without it, the last path component
+                // is considered to be a “file” name to be removed to get at the “directory”
+                // that is used as the base.
+                // https://docs.rs/url/latest/url/struct.Url.html#method.join
+                Url::from_directory_path(&path)
+                    .map_err(|_| anyhow!("invalid path `{}`", path.display()))
+            }
+            StorageConfig::S3 { bucket, .. } => Url::parse(&format!("s3://{bucket}/"))
+                .with_context(|| format!("invalid bucket `{bucket}`")),
+            StorageConfig::Gcs { bucket, .. } => Url::parse(&format!("gs://{bucket}/"))
+                .with_context(|| format!("invalid bucket `{bucket}`")),
+            StorageConfig::Azure { container, .. } => Url::parse(&format!("az://{container}/"))
+                .with_context(|| format!("invalid container `{container}`")),
+        }
+    }
 
-                Arc::new(LocalFileSystem::new_with_prefix(dir).unwrap())
+    fn store(&self, data_dir: &str) -> Result<Arc<dyn ObjectStore>> {
+        match self {
+            StorageConfig::Local { dir } => {
+                let dir = dir.as_deref().unwrap_or(data_dir);
+                fs::create_dir_all(dir).with_context(|| format!("Create `{dir}` failed"))?;
+                Ok(Arc::new(LocalFileSystem::new_with_prefix(dir)?))
             }
             StorageConfig::S3 {
                 bucket,
                 region,
+                endpoint,
+                virtual_hosted_style,
+                allow_http,
                 aws_access_key,
                 aws_access_secret,
+                aws_session_token,
             } => {
-                let s3 = AmazonS3Builder::new()
+                // Read the `AWS_*` variables, such as the credentials of a web
+                // identity or the container, overridden by the configured ones.
+                let mut builder = AmazonS3Builder::from_env()
                     .with_bucket_name(bucket)
-                    .with_region(region)
-                    .with_access_key_id(
-                        env::var("AWS_ACCESS_KEY_ID")
-                            .ok()
-                            .as_ref()
-                            .or(aws_access_key.as_ref())
-                            .unwrap(),
-                    )
-                    .with_secret_access_key(
-                        env::var("AWS_SECRET_ACCESS_KEY")
-                            .ok()
-                            .as_ref()
-                            .or(aws_access_secret.as_ref())
-                            .unwrap(),
-                    )
-                    .build()
-                    .unwrap();
-                Arc::new(s3)
+                    .with_virtual_hosted_style_request(*virtual_hosted_style)
+                    .with_allow_http(*allow_http);
+                if let Some(region) = region {
+                    builder = builder.with_region(region);
+                }
+                if let Some(endpoint) = endpoint {
+                    builder = builder.with_endpoint(endpoint);
+                }
+                if let Some((access_key, secret, token)) =
+                    s3_credentials(aws_access_key, aws_access_secret, aws_session_token)?
+                {
+                    builder = builder
+                        .with_access_key_id(access_key)
+                        .with_secret_access_key(secret);
+                    if let Some(token) = token {
+                        builder = builder.with_token(token);
+                    }
+                }
+                Ok(Arc::new(builder.build()?))
+            }
+            StorageConfig::Gcs {
+                bucket,
+                service_account_path,
+            } => {
+                let mut builder = GoogleCloudStorageBuilder::from_env().with_bucket_name(bucket);
+                if let Some(path) = service_account_path {
+                    builder = builder.with_service_account_path(path);
+                }
+                Ok(Arc::new(builder.build()?))
+            }
+            StorageConfig::Azure {
+                account,
+                container,
+                access_key,
+            } => {
+                let mut builder = MicrosoftAzureBuilder::from_env()
+                    .with_account(account)
+                    .with_container_name(container);
+                if let Some(access_key) = access_key {
+                    builder = builder.with_access_key(access_key);
+                }
+                Ok(Arc::new(builder.build()?))
             }
         }
     }
 }
+
+/// The configured static S3 credentials, `None` to fallback to
+/// the `AWS_*` environment variables or the instance profile.
+fn s3_credentials(
+    access_key: &Option<String>,
+    access_secret: &Option<String>,
+    session_token: &Option<String>,
+) -> Result<Option<(String, String, Option<String>)>> {
+    match (access_key, access_secret) {
+        (Some(access_key), Some(access_secret)) => Ok(Some((
+            access_key.clone(),
+            access_secret.clone(),
+            session_token.clone(),
+        ))),
+        (None, None) => Ok(None),
+        (Some(_), None) => bail!("missing `aws_access_secret`"),
+        (None, Some(_)) => bail!("missing `aws_access_key`"),
+    }
+}
+
+/// The problems found by [`DuoConfig::validate`].
+#[derive(Debug)]
+pub struct ConfigError(Vec<String>);
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid config:")?;
+        for problem in &self.0 {
+            write!(f, "\n  - {problem}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply_env() {
+        let mut table = toml::from_str::<toml::Table>(
+            r#"
+            data_dir = "data"
+
+            [server.web]
+            addr = "0.0.0.0:3000"
+            "#,
+        )
+        .unwrap();
+        let vars = [
+            ("DUO_DATA_DIR", "/var/lib/duo"),
+            ("DUO_SERVER__WEB__ADDR", "[::]:8080"),
+            ("DUO_SERVER__GRPC__ADDR", "127.0.0.1:6000"),
+            ("DUO_AGGREGATOR__MAX_SPANS", "1000"),
+            ("DUO_TENANTS", r#"["team-a"]"#),
+            ("HOME", "/root"),
+        ]
+        .map(|(name, value)| (name.to_string(), value.to_string()));
+        apply_env(&mut table, vars.into_iter());
+
+        let config = toml::Value::Table(table).try_into::<DuoConfig>().unwrap();
+        assert_eq!(config.data_dir, "/var/lib/duo");
+        assert_eq!(
+            config.server.web_addr(),
+            ListenAddr::Tcp("[::]:8080".parse().unwrap())
+        );
+        assert_eq!(
+            config.server.grpc_addr(),
+            ListenAddr::Tcp("127.0.0.1:6000".parse().unwrap())
+        );
+        assert_eq!(config.aggregator.max_spans, 1000);
+        assert_eq!(config.tenants, vec!["team-a"]);
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut config = DuoConfig::default();
+        assert!(config.validate().is_ok());
+
+        config.sampling.rate = 1.5;
+        config.aggregator.max_spans = 0;
+        config.tenants = vec![String::from("team/a")];
+        let problems = config.validate().unwrap_err().0;
+        assert_eq!(
+            problems,
+            vec![
+                "aggregator.max_spans: must be positive",
+                "sampling.rate: 1.5 is not between 0 and 1",
+                "tenants: invalid tenant `team/a`",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_validate_tls() {
+        let cert = concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml");
+        let mut config = DuoConfig::default();
+        config.server.grpc.tls = Some(TlsConfig {
+            cert: cert.to_string(),
+            key: String::from("/nonexistent/server.key"),
+            client_ca: Some(String::from("/nonexistent/ca.pem")),
+        });
+        let problems = config.validate().unwrap_err().0;
+        assert_eq!(
+            problems,
+            vec![
+                "server.grpc.tls.key: `/nonexistent/server.key` not found",
+                "server.grpc.tls.client_ca: `/nonexistent/ca.pem` not found",
+            ]
+        );
+    }
+}
@@ -0,0 +1,314 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+};
+
+use anyhow::Result;
+use datafusion::arrow::array::{RecordBatch, StringArray, UInt64Array};
+use serde::{Deserialize, Serialize};
+
+use crate::{arrow::serialize_record_batches, schema, tenant::Tenant, Process};
+
+/// How many partition writes a span is kept for the late children,
+/// a partition is written every minute.
+const RETAINED_ROUNDS: u32 = 5;
+
+/// The span fields to link a child span to its parent.
+#[derive(Debug, Clone, Deserialize)]
+pub struct SpanNode {
+    pub id: u64,
+    pub trace_id: u64,
+    pub parent_id: Option<u64>,
+    pub process_id: String,
+}
+
+/// A caller→callee edge between services, in Jaeger's dependencies format.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DependencyLink {
+    pub parent: String,
+    pub child: String,
+    #[serde(rename = "callCount")]
+    pub call_count: u64,
+}
+
+/// The calls between processes, keyed by the (caller, callee) process ids.
+pub type ProcessCalls = HashMap<(String, String), u64>;
+
+/// Count the calls between different processes, the children whose
+/// parent is not among the spans are ignored.
+pub fn process_calls(spans: &[SpanNode]) -> ProcessCalls {
+    let processes = spans
+        .iter()
+        .map(|span| ((span.trace_id, span.id), span.process_id.as_str()))
+        .collect::<HashMap<_, _>>();
+    let mut calls = ProcessCalls::new();
+    for span in spans {
+        let Some(parent_id) = span.parent_id else {
+            continue;
+        };
+        if let Some(parent) = processes.get(&(span.trace_id, parent_id)) {
+            add_call(&mut calls, parent, &span.process_id);
+        }
+    }
+    calls
+}
+
+fn add_call(calls: &mut ProcessCalls, parent: &str, child: &str) {
+    if parent != child {
+        *calls
+            .entry((parent.to_string(), child.to_string()))
+            .or_default() += 1;
+    }
+}
+
+/// Links the spans written in successive partitions, the parent span usually
+/// ends after its children so it may be written in a later partition.
+#[derive(Debug, Default)]
+pub struct DependencyTracker {
+    round: u32,
+    // The process and the round of the recent spans, keyed by (trace id, span id).
+    recent: HashMap<(u64, u64), (String, u32)>,
+    // The children whose parent is not written yet.
+    pending: Vec<(SpanNode, u32)>,
+}
+
+impl DependencyTracker {
+    /// Count the calls resolved by the spans of a new partition.
+    pub fn track(&mut self, spans: Vec<SpanNode>) -> ProcessCalls {
+        self.round += 1;
+        let round = self.round;
+        for span in &spans {
+            self.recent
+                .insert((span.trace_id, span.id), (span.process_id.clone(), round));
+        }
+
+        let mut calls = ProcessCalls::new();
+        let mut pending = Vec::new();
+        let children = self
+            .pending
+            .drain(..)
+            .chain(spans.into_iter().map(|span| (span, round)));
+        for (span, seen) in children {
+            let Some(parent_id) = span.parent_id else {
+                continue;
+            };
+            match self.recent.get(&(span.trace_id, parent_id)) {
+                Some((parent, _)) => add_call(&mut calls, parent, &span.process_id),
+                None if round - seen < RETAINED_ROUNDS => pending.push((span, seen)),
+                // The parent was lost or never sampled.
+                None => {}
+            }
+        }
+        self.pending = pending;
+        self.recent
+            .retain(|_, (_, seen)| round - *seen < RETAINED_ROUNDS);
+        calls
+    }
+
+    /// The calls the spans not written yet resolve with the tracked ones,
+    /// without tracking them. The calls among `spans` are left to
+    /// [`process_calls`].
+    pub fn pending_calls(&self, spans: &[SpanNode]) -> ProcessCalls {
+        let processes = spans
+            .iter()
+            .map(|span| ((span.trace_id, span.id), span.process_id.as_str()))
+            .collect::<HashMap<_, _>>();
+        let mut calls = ProcessCalls::new();
+        // The new children of the written parents.
+        for span in spans {
+            let Some(parent_id) = span.parent_id else {
+                continue;
+            };
+            let key = (span.trace_id, parent_id);
+            if processes.contains_key(&key) {
+                continue;
+            }
+            if let Some((parent, _)) = self.recent.get(&key) {
+                add_call(&mut calls, parent, &span.process_id);
+            }
+        }
+        // The written children waiting for the new parents.
+        for (span, _) in &self.pending {
+            let Some(parent_id) = span.parent_id else {
+                continue;
+            };
+            if let Some(parent) = processes.get(&(span.trace_id, parent_id)) {
+                add_call(&mut calls, parent, &span.process_id);
+            }
+        }
+        calls
+    }
+}
+
+/// The service links between the spans of the tenant not written yet and
+/// the spans written by this node, of the spans started within the range
+/// (in microseconds). The links among the unwritten spans are not included.
+pub fn memory_links(tenant: &Tenant, start: i64, end: i64) -> Result<Vec<DependencyLink>> {
+    #[derive(Deserialize)]
+    struct TimedSpanNode {
+        id: u64,
+        trace_id: u64,
+        parent_id: Option<u64>,
+        process_id: String,
+        start: i64,
+    }
+
+    let batches = { tenant.memory_store.read().span_batches.clone() };
+    let spans = serialize_record_batches::<TimedSpanNode>(&batches)?
+        .into_iter()
+        .filter(|span| (start..=end).contains(&span.start))
+        .map(|span| SpanNode {
+            id: span.id,
+            trace_id: span.trace_id,
+            parent_id: span.parent_id,
+            process_id: span.process_id,
+        })
+        .collect::<Vec<_>>();
+    let calls = tenant.dependencies.read().pending_calls(&spans);
+    let processes = { tenant.memory_store.read().processes() };
+    Ok(service_links(calls, &processes))
+}
+
+/// Aggregate the process calls by service, sorted by parent then child.
+/// The calls between the processes of the same service are dropped.
+pub fn service_links(
+    calls: ProcessCalls,
+    processes: &HashMap<String, Process>,
+) -> Vec<DependencyLink> {
+    merge_links(
+        calls
+            .into_iter()
+            .filter_map(|((parent, child), call_count)| {
+                let parent = &processes.get(&parent)?.service_name;
+                let child = &processes.get(&child)?.service_name;
+                (parent != child).then(|| DependencyLink {
+                    parent: parent.clone(),
+                    child: child.clone(),
+                    call_count,
+                })
+            }),
+    )
+}
+
+/// Sum the call counts of the same edges, sorted by parent then child.
+pub fn merge_links(links: impl IntoIterator<Item = DependencyLink>) -> Vec<DependencyLink> {
+    let mut merged = BTreeMap::<(String, String), u64>::new();
+    for link in links {
+        *merged.entry((link.parent, link.child)).or_default() += link.call_count;
+    }
+    merged
+        .into_iter()
+        .map(|((parent, child), call_count)| DependencyLink {
+            parent,
+            child,
+            call_count,
+        })
+        .collect()
+}
+
+pub fn convert_links_to_record_batch(links: &[DependencyLink]) -> Result<RecordBatch> {
+    Ok(RecordBatch::try_new(
+        schema::get_dependency_schema(),
+        vec![
+            Arc::new(StringArray::from_iter_values(
+                links.iter().map(|link| link.parent.as_str()),
+            )),
+            Arc::new(StringArray::from_iter_values(
+                links.iter().map(|link| link.child.as_str()),
+            )),
+            Arc::new(UInt64Array::from_iter_values(
+                links.iter().map(|link| link.call_count),
+            )),
+        ],
+    )?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn span(id: u64, parent_id: Option<u64>, process_id: &str) -> SpanNode {
+        SpanNode {
+            id,
+            trace_id: 1,
+            parent_id,
+            process_id: process_id.into(),
+        }
+    }
+
+    #[test]
+    fn test_process_calls() {
+        let calls = process_calls(&[
+            span(1, None, "web"),
+            span(2, Some(1), "api"),
+            span(3, Some(1), "api"),
+            span(4, Some(2), "api"),
+            span(5, Some(9), "db"),
+        ]);
+        assert_eq!(calls, HashMap::from([(("web".into(), "api".into()), 2)]));
+    }
+
+    #[test]
+    fn test_service_links() {
+        let processes = [
+            testing::process("web-1", "web"),
+            testing::process("api-1", "api"),
+            testing::process("api-2", "api"),
+        ]
+        .into_iter()
+        .map(|process| (process.id.clone(), process))
+        .collect();
+        let calls = ProcessCalls::from([
+            (("web-1".into(), "api-1".into()), 2),
+            (("web-1".into(), "api-2".into()), 1),
+            (("api-1".into(), "api-2".into()), 4),
+            (("web-1".into(), "db-1".into()), 1),
+        ]);
+        assert_eq!(
+            service_links(calls, &processes),
+            vec![DependencyLink {
+                parent: "web".into(),
+                child: "api".into(),
+                call_count: 3,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_tracker_late_parent() {
+        let mut tracker = DependencyTracker::default();
+        assert!(tracker.track(vec![span(2, Some(1), "api")]).is_empty());
+        let calls = tracker.track(vec![span(1, None, "web"), span(3, Some(1), "db")]);
+        assert_eq!(
+            calls,
+            HashMap::from([
+                (("web".into(), "api".into()), 1),
+                (("web".into(), "db".into()), 1)
+            ])
+        );
+
+        // The pending children are dropped after the retained rounds.
+        tracker.track(vec![span(5, Some(4), "api")]);
+        for _ in 0..RETAINED_ROUNDS {
+            tracker.track(Vec::new());
+        }
+        assert!(tracker.track(vec![span(4, None, "web")]).is_empty());
+    }
+
+    #[test]
+    fn test_tracker_pending_calls() {
+        let mut tracker = DependencyTracker::default();
+        tracker.track(vec![span(1, None, "web"), span(3, Some(2), "db")]);
+        let spans = [span(2, Some(1), "api"), span(4, Some(2), "cache")];
+        assert_eq!(
+            tracker.pending_calls(&spans),
+            HashMap::from([
+                (("web".into(), "api".into()), 1),
+                (("api".into(), "db".into()), 1)
+            ])
+        );
+        // Not tracked.
+        assert_eq!(tracker.track(Vec::new()), HashMap::new());
+    }
+}
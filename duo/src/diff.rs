@@ -0,0 +1,325 @@
+use std::collections::{BTreeSet, HashMap};
+
+use serde::Serialize;
+use serde_json::Value as JsonValue;
+
+use crate::{Span, TraceExt};
+
+/// The tags not compared, the timings are compared by the durations.
+const IGNORED_TAGS: [&str; 2] = ["busy", "idle"];
+
+/// The span trees of two traces aligned, the durations are in microseconds.
+#[derive(Debug, Serialize)]
+pub struct TraceDiff {
+    #[serde(rename = "baseTraceID")]
+    pub base_trace_id: String,
+    #[serde(rename = "otherTraceID")]
+    pub other_trace_id: String,
+    #[serde(rename = "durationDelta")]
+    pub duration_delta: i64,
+    pub matched: usize,
+    pub added: usize,
+    pub missing: usize,
+    pub roots: Vec<DiffNode>,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DiffStatus {
+    Matched,
+    /// Only in the other trace.
+    Added,
+    /// Only in the base trace.
+    Missing,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DiffNode {
+    pub service: String,
+    pub operation: String,
+    pub status: DiffStatus,
+    #[serde(rename = "baseSpanID", skip_serializing_if = "Option::is_none")]
+    pub base_span_id: Option<String>,
+    #[serde(rename = "otherSpanID", skip_serializing_if = "Option::is_none")]
+    pub other_span_id: Option<String>,
+    #[serde(rename = "baseDuration", skip_serializing_if = "Option::is_none")]
+    pub base_duration: Option<i64>,
+    #[serde(rename = "otherDuration", skip_serializing_if = "Option::is_none")]
+    pub other_duration: Option<i64>,
+    #[serde(rename = "durationDelta", skip_serializing_if = "Option::is_none")]
+    pub duration_delta: Option<i64>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<TagDiff>,
+    pub children: Vec<DiffNode>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TagDiff {
+    pub key: String,
+    pub base: Option<JsonValue>,
+    pub other: Option<JsonValue>,
+}
+
+struct Tree<'a> {
+    trace: &'a TraceExt,
+    children: HashMap<Option<u64>, Vec<&'a Span>>,
+}
+
+impl<'a> Tree<'a> {
+    fn new(trace: &'a TraceExt) -> Self {
+        let ids = trace
+            .spans
+            .iter()
+            .map(|span| span.id)
+            .collect::<BTreeSet<_>>();
+        let mut children = HashMap::<Option<u64>, Vec<&Span>>::new();
+        for span in &trace.spans {
+            // The spans whose parent is missing are roots too.
+            let parent = span
+                .parent_id
+                .filter(|id| ids.contains(id) && *id != span.id);
+            children.entry(parent).or_default().push(span);
+        }
+        for spans in children.values_mut() {
+            spans.sort_by_key(|span| (span.start, span.id));
+        }
+        Tree { trace, children }
+    }
+
+    fn children(&self, parent: Option<u64>) -> &[&'a Span] {
+        self.children
+            .get(&parent)
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    fn key(&self, span: &Span) -> (String, String) {
+        let service = self
+            .trace
+            .processes
+            .get(&span.process_id)
+            .map(|process| process.service_name.clone())
+            .unwrap_or_else(|| span.process_id.clone());
+        (service, span.name.clone())
+    }
+
+    fn duration(&self) -> i64 {
+        let start = self.trace.spans.iter().map(|span| span.start).min();
+        let end = self
+            .trace
+            .spans
+            .iter()
+            .map(|span| span.end.unwrap_or(span.start))
+            .max();
+        match (start, end) {
+            (Some(start), Some(end)) => (end - start).whole_microseconds() as i64,
+            _ => 0,
+        }
+    }
+}
+
+/// Compare the other trace to the base trace. The siblings are aligned by
+/// their service and operation, the n-th occurrence in start order matching
+/// the n-th one of the other trace.
+pub fn diff(base: &TraceExt, other: &TraceExt) -> TraceDiff {
+    let base_tree = Tree::new(base);
+    let other_tree = Tree::new(other);
+    let roots = diff_children(&base_tree, None, &other_tree, None);
+
+    let mut diff = TraceDiff {
+        base_trace_id: base.trace_id.to_string(),
+        other_trace_id: other.trace_id.to_string(),
+        duration_delta: other_tree.duration() - base_tree.duration(),
+        matched: 0,
+        added: 0,
+        missing: 0,
+        roots,
+    };
+    let mut stack = diff.roots.iter().collect::<Vec<_>>();
+    while let Some(node) = stack.pop() {
+        match node.status {
+            DiffStatus::Matched => diff.matched += 1,
+            DiffStatus::Added => diff.added += 1,
+            DiffStatus::Missing => diff.missing += 1,
+        }
+        stack.extend(&node.children);
+    }
+    diff
+}
+
+fn diff_children(
+    base: &Tree,
+    base_parent: Option<u64>,
+    other: &Tree,
+    other_parent: Option<u64>,
+) -> Vec<DiffNode> {
+    let mut others = HashMap::<(String, String), Vec<&Span>>::new();
+    for span in other.children(other_parent) {
+        others.entry(other.key(span)).or_default().push(span);
+    }
+    for spans in others.values_mut() {
+        spans.reverse();
+    }
+
+    let mut nodes = Vec::new();
+    for span in base.children(base_parent) {
+        let key = base.key(span);
+        let node = match others.get_mut(&key).and_then(Vec::pop) {
+            Some(other_span) => matched_node(key, base, span, other, other_span),
+            None => single_node(key, base, span, DiffStatus::Missing),
+        };
+        nodes.push(node);
+    }
+    // The unmatched spans of the other trace, in start order.
+    let mut added = others
+        .into_iter()
+        .flat_map(|(key, spans)| spans.into_iter().map(move |span| (key.clone(), span)))
+        .collect::<Vec<_>>();
+    added.sort_by_key(|(_, span)| (span.start, span.id));
+    nodes.extend(
+        added
+            .into_iter()
+            .map(|(key, span)| single_node(key, other, span, DiffStatus::Added)),
+    );
+    nodes
+}
+
+fn matched_node(
+    (service, operation): (String, String),
+    base: &Tree,
+    base_span: &Span,
+    other: &Tree,
+    other_span: &Span,
+) -> DiffNode {
+    let base_duration = base_span.duration().whole_microseconds() as i64;
+    let other_duration = other_span.duration().whole_microseconds() as i64;
+    DiffNode {
+        service,
+        operation,
+        status: DiffStatus::Matched,
+        base_span_id: Some(base_span.id.to_string()),
+        other_span_id: Some(other_span.id.to_string()),
+        base_duration: Some(base_duration),
+        other_duration: Some(other_duration),
+        duration_delta: Some(other_duration - base_duration),
+        tags: diff_tags(&base_span.tags, &other_span.tags),
+        children: diff_children(base, Some(base_span.id), other, Some(other_span.id)),
+    }
+}
+
+/// The node of a span only in one trace, with its whole subtree.
+fn single_node(
+    (service, operation): (String, String),
+    tree: &Tree,
+    span: &Span,
+    status: DiffStatus,
+) -> DiffNode {
+    let duration = Some(span.duration().whole_microseconds() as i64);
+    let span_id = Some(span.id.to_string());
+    let children = tree
+        .children(Some(span.id))
+        .iter()
+        .map(|child| {
+            let status = match status {
+                DiffStatus::Added => DiffStatus::Added,
+                _ => DiffStatus::Missing,
+            };
+            single_node(tree.key(child), tree, child, status)
+        })
+        .collect();
+    let (base_span_id, other_span_id, base_duration, other_duration) = match status {
+        DiffStatus::Added => (None, span_id, None, duration),
+        _ => (span_id, None, duration, None),
+    };
+    DiffNode {
+        service,
+        operation,
+        status,
+        base_span_id,
+        other_span_id,
+        base_duration,
+        other_duration,
+        duration_delta: None,
+        tags: Vec::new(),
+        children,
+    }
+}
+
+fn diff_tags(
+    base: &HashMap<String, JsonValue>,
+    other: &HashMap<String, JsonValue>,
+) -> Vec<TagDiff> {
+    base.keys()
+        .chain(other.keys())
+        .filter(|key| !IGNORED_TAGS.contains(&key.as_str()))
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .filter(|key| base.get(*key) != other.get(*key))
+        .map(|key| TagDiff {
+            key: key.clone(),
+            base: base.get(key).cloned(),
+            other: other.get(key).cloned(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::testing::{span, trace};
+
+    #[test]
+    fn test_diff() {
+        let mut base_query = span(2, Some(1), 10, Some(20)).named("query");
+        base_query.tags.insert("rows".into(), json!(1));
+        let base = trace(
+            1,
+            vec![
+                span(1, None, 0, Some(50)).named("get"),
+                base_query,
+                span(3, Some(1), 20, Some(30)).named("query"),
+                span(4, Some(1), 30, Some(40)).named("cache"),
+            ],
+        );
+        let mut other_query = span(12, Some(11), 10, Some(60)).named("query");
+        other_query.tags.insert("rows".into(), json!(100));
+        let other = trace(
+            2,
+            vec![
+                span(11, None, 0, Some(100)).named("get"),
+                other_query,
+                span(13, Some(11), 60, Some(70)).named("query"),
+                span(14, Some(11), 70, Some(90)).named("render"),
+            ],
+        );
+
+        let diff = diff(&base, &other);
+        assert_eq!(diff.duration_delta, 50);
+        assert_eq!((diff.matched, diff.added, diff.missing), (3, 1, 1));
+
+        let root = &diff.roots[0];
+        assert_eq!(root.duration_delta, Some(50));
+        let children = root
+            .children
+            .iter()
+            .map(|node| (node.operation.as_str(), &node.status, node.duration_delta))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            children,
+            vec![
+                ("query", &DiffStatus::Matched, Some(40)),
+                ("query", &DiffStatus::Matched, Some(0)),
+                ("cache", &DiffStatus::Missing, None),
+                ("render", &DiffStatus::Added, None),
+            ]
+        );
+        let tags = &root.children[0].tags;
+        assert_eq!(tags.len(), 1);
+        assert_eq!(
+            (tags[0].base.clone(), tags[0].other.clone()),
+            (Some(json!(1)), Some(json!(100)))
+        );
+    }
+}
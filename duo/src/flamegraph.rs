@@ -0,0 +1,255 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use serde::Serialize;
+
+use crate::{analysis::exclusive_time, Process, Span};
+
+/// A frame of the call tree merged from many spans, the times are in microseconds.
+#[derive(Debug, Serialize)]
+pub struct CallNode {
+    /// `service: operation`
+    pub name: String,
+    /// The number of the spans merged into the node.
+    pub count: u64,
+    #[serde(rename = "totalTime")]
+    pub total_time: i64,
+    #[serde(rename = "selfTime")]
+    pub self_time: i64,
+    /// Sorted by name.
+    pub children: Vec<CallNode>,
+}
+
+#[derive(Default)]
+struct Frame {
+    count: u64,
+    total_time: i64,
+    self_time: i64,
+    // The indexes of the child frames, always after their parent.
+    children: BTreeMap<String, usize>,
+}
+
+/// Build the call tree bottom-up from the frames, the first one is the root.
+fn into_node(frames: Vec<Frame>, name: String) -> CallNode {
+    let mut nodes = frames
+        .iter()
+        .map(|_| None)
+        .collect::<Vec<Option<CallNode>>>();
+    for (i, frame) in frames.into_iter().enumerate().rev() {
+        let children = frame
+            .children
+            .into_iter()
+            .filter_map(|(name, child)| {
+                let mut node = nodes[child].take()?;
+                node.name = name;
+                Some(node)
+            })
+            .collect();
+        nodes[i] = Some(CallNode {
+            name: String::new(),
+            count: frame.count,
+            total_time: frame.total_time,
+            self_time: frame.self_time,
+            children,
+        });
+    }
+    let mut root = nodes.swap_remove(0).expect("the root frame");
+    root.name = name;
+    root
+}
+
+struct Forest<'a> {
+    spans: &'a [Span],
+    processes: &'a HashMap<String, Process>,
+    // The indexes of the children, keyed by (trace id, parent id).
+    children: HashMap<(u64, u64), Vec<usize>>,
+}
+
+impl<'a> Forest<'a> {
+    fn label(&self, span: &Span) -> String {
+        let service = self
+            .processes
+            .get(&span.process_id)
+            .map(|process| process.service_name.as_str())
+            .unwrap_or(&span.process_id);
+        // The folded stacks separate the frames by `;`.
+        format!("{service}: {}", span.name).replace(';', ",")
+    }
+
+    fn children(&self, span: &Span) -> &[usize] {
+        self.children
+            .get(&(span.trace_id, span.id))
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    /// The unfinished spans are counted as zero duration.
+    fn times(span: &Span) -> (i64, i64) {
+        let start = span.start_as_micros();
+        (start, span.end_as_micros().unwrap_or(start).max(start))
+    }
+
+    /// Merge the subtree of the span into the first frame. The stack is explicit
+    /// as a trace may be deeper than the thread stack allows.
+    fn merge(&self, root: usize, frames: &mut Vec<Frame>) {
+        // (span, frame, entering), the span leaves the path after its subtree.
+        let mut stack = vec![(root, 0, true)];
+        let mut path = HashSet::new();
+        while let Some((i, f, entering)) = stack.pop() {
+            if !entering {
+                path.remove(&i);
+                continue;
+            }
+            // Guard against the malformed parent links.
+            if !path.insert(i) {
+                continue;
+            }
+            stack.push((i, f, false));
+
+            let span = &self.spans[i];
+            let (start, end) = Self::times(span);
+            let children = self.children(span);
+            let frame = &mut frames[f];
+            frame.count += 1;
+            frame.total_time += end - start;
+            frame.self_time += exclusive_time(
+                start,
+                end,
+                children
+                    .iter()
+                    .map(|&child| Self::times(&self.spans[child])),
+            );
+            for &child in children {
+                let label = self.label(&self.spans[child]);
+                let new = frames.len();
+                let child_frame = *frames[f].children.entry(label).or_insert(new);
+                if child_frame == new {
+                    frames.push(Frame::default());
+                }
+                stack.push((child, child_frame, true));
+            }
+        }
+    }
+}
+
+/// Merge the subtrees of the spans matching `is_root` into one call tree,
+/// `None` if no span matches. A matching span nested in another one is merged
+/// as a part of the outer subtree only.
+pub fn merge_call_tree(
+    spans: &[Span],
+    processes: &HashMap<String, Process>,
+    is_root: impl Fn(&Span) -> bool,
+) -> Option<CallNode> {
+    let mut children = HashMap::<(u64, u64), Vec<usize>>::new();
+    for (i, span) in spans.iter().enumerate() {
+        if let Some(parent_id) = span.parent_id {
+            children
+                .entry((span.trace_id, parent_id))
+                .or_default()
+                .push(i);
+        }
+    }
+    let forest = Forest {
+        spans,
+        processes,
+        children,
+    };
+
+    let matched = spans
+        .iter()
+        .enumerate()
+        .filter(|(_, span)| is_root(span))
+        .map(|(i, span)| ((span.trace_id, span.id), i))
+        .collect::<HashMap<_, _>>();
+    let parents = spans
+        .iter()
+        .map(|span| ((span.trace_id, span.id), span.parent_id))
+        .collect::<HashMap<_, _>>();
+    let has_matched_ancestor = |span: &Span| {
+        let mut seen = HashSet::new();
+        let mut parent_id = span.parent_id;
+        while let Some(id) = parent_id {
+            let key = (span.trace_id, id);
+            if matched.contains_key(&key) {
+                return true;
+            }
+            if !seen.insert(id) {
+                return false;
+            }
+            parent_id = parents.get(&key).copied().flatten();
+        }
+        false
+    };
+
+    let mut roots = matched
+        .values()
+        .copied()
+        .filter(|&i| !has_matched_ancestor(&spans[i]))
+        .collect::<Vec<_>>();
+    roots.sort_unstable();
+    let name = forest.label(&spans[*roots.first()?]);
+    let mut frames = vec![Frame::default()];
+    for root in roots {
+        forest.merge(root, &mut frames);
+    }
+    Some(into_node(frames, name))
+}
+
+/// The folded stacks of the call tree, one `frame;frame;frame self_time` line per path,
+/// as read by flamegraph.pl, inferno and speedscope.
+pub fn folded_stacks(root: &CallNode) -> String {
+    fn fold(node: &CallNode, stack: &mut Vec<String>, output: &mut String) {
+        stack.push(node.name.clone());
+        if node.self_time > 0 {
+            output.push_str(&stack.join(";"));
+            output.push_str(&format!(" {}\n", node.self_time));
+        }
+        for child in &node.children {
+            fold(child, stack, output);
+        }
+        stack.pop();
+    }
+
+    let mut output = String::new();
+    fold(root, &mut Vec::new(), &mut output);
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::testing::span;
+
+    #[test]
+    fn test_merge_call_tree() {
+        let spans = vec![
+            span(1, None, 0, Some(100)).named("get"),
+            span(2, Some(1), 10, Some(40)).named("query"),
+            span(3, Some(1), 50, Some(60)).named("query"),
+            span(1, None, 0, Some(50)).named("get").in_trace(2),
+            span(2, Some(1), 10, Some(30)).named("render").in_trace(2),
+            // Nested in the matched span, merged as its child only.
+            span(3, Some(2), 15, Some(20)).named("get").in_trace(2),
+        ];
+        let root = merge_call_tree(&spans, &HashMap::new(), |span| span.name == "get").unwrap();
+        assert_eq!(root.name, "api: get");
+        assert_eq!((root.count, root.total_time, root.self_time), (2, 150, 90));
+
+        let children = root
+            .children
+            .iter()
+            .map(|node| (node.name.as_str(), node.count, node.self_time))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            children,
+            vec![("api: query", 2, 40), ("api: render", 1, 15)]
+        );
+
+        assert_eq!(
+            folded_stacks(&root),
+            "api: get 90\napi: get;api: query 40\napi: get;api: render 15\napi: get;api: render;api: get 5\n"
+        );
+        assert!(merge_call_tree(&spans, &HashMap::new(), |span| span.name == "none").is_none());
+    }
+}
@@ -1,27 +1,133 @@
-use std::{net::SocketAddr, sync::Arc};
+use std::sync::Arc;
+#[cfg(unix)]
+use std::{fs, path::Path};
 
-use crate::MemoryStore;
+use crate::{
+    auth::{AuthError, Authenticator},
+    config::{self, ListenAddr, Scope},
+    tenant::{self, Tenant, Tenants, TENANT_HEADER},
+};
 
-use self::server::DuoServer;
+use self::{peer::PeerService, server::DuoServer};
 
+#[cfg(unix)]
+use anyhow::Context;
+use anyhow::Result;
 use duo_api as proto;
-use parking_lot::RwLock;
-use proto::instrument::instrument_server::InstrumentServer;
-use tonic::transport::Server;
+use proto::{instrument::instrument_server::InstrumentServer, peer::peer_server::PeerServer};
+#[cfg(unix)]
+use tokio::net::UnixListener;
+#[cfg(unix)]
+use tokio_stream::wrappers::UnixListenerStream;
+use tonic::{
+    service::Interceptor,
+    transport::{Certificate, Identity, Server, ServerTlsConfig},
+    Request, Status,
+};
 
+mod peer;
 mod server;
 
-pub fn spawn_server(memory_store: Arc<RwLock<MemoryStore>>, port: u16) {
+/// The tenant of the request, from the `x-duo-tenant` metadata.
+#[allow(clippy::result_large_err)]
+fn request_tenant<T>(tenants: &Tenants, request: &Request<T>) -> Result<Arc<Tenant>, Status> {
+    let header = request
+        .metadata()
+        .get(TENANT_HEADER)
+        .map(|value| value.to_str())
+        .transpose()
+        .map_err(|_| Status::invalid_argument("invalid tenant"))?;
+    let id = tenant::tenant_id(header).map_err(|err| Status::invalid_argument(err.to_string()))?;
+    tenants
+        .get(id)
+        .map_err(|err| Status::internal(err.to_string()))
+}
+
+/// Require the scope for every request.
+#[derive(Clone)]
+struct ScopeAuth(Arc<Authenticator>, Scope);
+
+impl Interceptor for ScopeAuth {
+    fn call(&mut self, request: Request<()>) -> Result<Request<()>, Status> {
+        if !self.0.is_enabled() {
+            return Ok(request);
+        }
+
+        let metadata = request.metadata();
+        let header = metadata
+            .get("authorization")
+            .and_then(|value| value.to_str().ok());
+        let tenant = metadata
+            .get(TENANT_HEADER)
+            .map(|value| value.to_str())
+            .transpose()
+            .map_err(|_| Status::invalid_argument("invalid tenant"))?;
+        let tenant =
+            tenant::tenant_id(tenant).map_err(|err| Status::invalid_argument(err.to_string()))?;
+        match self.0.authorize(header, self.1, tenant) {
+            Ok(()) => Ok(request),
+            Err(err @ AuthError::Unauthenticated) => Err(Status::unauthenticated(err.to_string())),
+            Err(err @ AuthError::Forbidden) => Err(Status::permission_denied(err.to_string())),
+        }
+    }
+}
+
+pub fn spawn_server(tenants: Arc<Tenants>, authenticator: Arc<Authenticator>) -> Result<()> {
+    let config = config::load();
+    let mut builder = Server::builder();
+    let scheme = match &config.server.grpc.tls {
+        Some(tls) => {
+            let (cert, key, client_ca) = tls.read()?;
+            let mut tls_config = ServerTlsConfig::new().identity(Identity::from_pem(cert, key));
+            if let Some(client_ca) = client_ca {
+                tls_config = tls_config.client_ca_root(Certificate::from_pem(client_ca));
+            }
+            builder = builder.tls_config(tls_config)?;
+            "https"
+        }
+        None => "grpc",
+    };
+
+    let addr = config.server.grpc_addr();
     tokio::spawn(async move {
-        let addr = SocketAddr::from(([0, 0, 0, 0], port));
-        let mut service = DuoServer::new(memory_store);
+        let mut service = DuoServer::new(Arc::clone(&tenants));
         service.spawn();
 
-        println!("gRPC server listening on grpc://{}", addr);
-        Server::builder()
-            .add_service(InstrumentServer::new(service))
-            .serve(addr)
-            .await
-            .unwrap();
+        let router = builder
+            .add_service(InstrumentServer::with_interceptor(
+                service,
+                ScopeAuth(Arc::clone(&authenticator), Scope::Ingest),
+            ))
+            .add_service(PeerServer::with_interceptor(
+                PeerService::new(tenants),
+                ScopeAuth(authenticator, Scope::Read),
+            ));
+        match addr {
+            ListenAddr::Tcp(addr) => {
+                println!("gRPC server listening on {scheme}://{addr}");
+                router.serve(addr).await.unwrap();
+            }
+            #[cfg(unix)]
+            ListenAddr::Unix(path) => {
+                let incoming = bind_unix(&path).unwrap();
+                println!("gRPC server listening on unix:{}", path.display());
+                router.serve_with_incoming(incoming).await.unwrap();
+            }
+        }
     });
+    Ok(())
+}
+
+#[cfg(unix)]
+fn bind_unix(path: &Path) -> Result<UnixListenerStream> {
+    // Remove the stale socket left by the previous run.
+    if path.exists() {
+        fs::remove_file(path).with_context(|| format!("Remove `{}` failed", path.display()))?;
+    }
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let listener =
+        UnixListener::bind(path).with_context(|| format!("Bind `{}` failed", path.display()))?;
+    Ok(UnixListenerStream::new(listener))
 }
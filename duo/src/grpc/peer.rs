@@ -0,0 +1,74 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use datafusion::arrow::{array::RecordBatch, ipc::writer::StreamWriter};
+use duo_api::peer::{
+    peer_server::Peer, DependencyLink, MemoryBatchesRequest, MemoryBatchesResponse,
+    MemoryLinksRequest, MemoryLinksResponse,
+};
+use tonic::{Request, Response, Status};
+
+use crate::{dependency, tenant::Tenants};
+
+/// Serve the in-memory data of this node to the query nodes.
+pub struct PeerService {
+    tenants: Arc<Tenants>,
+}
+
+impl PeerService {
+    pub fn new(tenants: Arc<Tenants>) -> Self {
+        Self { tenants }
+    }
+}
+
+fn encode_batch(batch: &RecordBatch) -> Result<Vec<u8>> {
+    let mut writer = StreamWriter::try_new(Vec::new(), &batch.schema())?;
+    writer.write(batch)?;
+    writer.finish()?;
+    Ok(writer.into_inner()?)
+}
+
+#[tonic::async_trait]
+impl Peer for PeerService {
+    async fn memory_batches(
+        &self,
+        request: Request<MemoryBatchesRequest>,
+    ) -> Result<Response<MemoryBatchesResponse>, Status> {
+        let tenant = super::request_tenant(&self.tenants, &request)?;
+        let batches = {
+            let memory_store = tenant.memory_store.read();
+            match request.get_ref().table.as_str() {
+                "span" => memory_store.span_batches.clone(),
+                "log" => memory_store.log_batches.clone(),
+                table => {
+                    return Err(Status::invalid_argument(format!("unknown table: {table}")));
+                }
+            }
+        };
+        let batches = batches
+            .iter()
+            .filter(|batch| batch.num_rows() > 0)
+            .map(encode_batch)
+            .collect::<Result<Vec<_>>>()
+            .map_err(|err| Status::internal(err.to_string()))?;
+        Ok(Response::new(MemoryBatchesResponse { batches }))
+    }
+
+    async fn memory_links(
+        &self,
+        request: Request<MemoryLinksRequest>,
+    ) -> Result<Response<MemoryLinksResponse>, Status> {
+        let tenant = super::request_tenant(&self.tenants, &request)?;
+        let MemoryLinksRequest { start, end } = request.into_inner();
+        let links = dependency::memory_links(&tenant, start, end)
+            .map_err(|err| Status::internal(err.to_string()))?
+            .into_iter()
+            .map(|link| DependencyLink {
+                parent: link.parent,
+                child: link.child,
+                call_count: link.call_count,
+            })
+            .collect();
+        Ok(Response::new(MemoryLinksResponse { links }))
+    }
+}
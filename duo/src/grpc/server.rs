@@ -1,47 +1,42 @@
 use std::{mem, sync::Arc, time::Duration};
 
-use crate::{ipc::IpcFile, partition::PartitionWriter, schema, Log, MemoryStore, SpanAggregator};
+use crate::{
+    arrow::serialize_record_batches,
+    dependency::{self, SpanNode},
+    ipc::IpcFile,
+    partition::PartitionWriter,
+    registry, schema,
+    tenant::{Tenant, Tenants},
+    Log,
+};
+use datafusion::arrow::array::RecordBatch;
 use duo_api::instrument::{
-    instrument_server::Instrument, RecordEventRequest, RecordEventResponse, RecordSpanRequest,
+    instrument_server::Instrument, HeartbeatRequest, HeartbeatResponse, RecordBatchRequest,
+    RecordBatchResponse, RecordEventRequest, RecordEventResponse, RecordSpanRequest,
     RecordSpanResponse, RegisterProcessRequest, RegisterProcessResponse,
 };
-use parking_lot::RwLock;
 use tonic::{Request, Response, Status};
 use tracing::{debug, info};
 
 pub struct DuoServer {
-    memory_store: Arc<RwLock<MemoryStore>>,
-    aggregator: Arc<RwLock<SpanAggregator>>,
-    logs: Arc<RwLock<Vec<Log>>>,
+    tenants: Arc<Tenants>,
 }
 
 impl DuoServer {
-    pub fn new(memory_store: Arc<RwLock<MemoryStore>>) -> Self {
-        Self {
-            memory_store,
-            aggregator: Arc::new(RwLock::new(SpanAggregator::new())),
-            logs: Arc::new(RwLock::new(vec![])),
-        }
+    pub fn new(tenants: Arc<Tenants>) -> Self {
+        Self { tenants }
     }
 
     pub fn spawn(&mut self) {
-        let aggregator = Arc::clone(&self.aggregator);
-        let memory_store = Arc::clone(&self.memory_store);
-        let logs = Arc::clone(&self.logs);
+        let tenants = Arc::clone(&self.tenants);
         tokio::spawn(async move {
             let mut interval = tokio::time::interval(Duration::from_secs(1));
             loop {
                 interval.tick().await;
 
-                let logs = mem::take(&mut *logs.write());
-                let spans = aggregator.write().aggregate();
-                if logs.is_empty() || spans.is_empty() {
-                    continue;
+                for tenant in tenants.all() {
+                    aggregate(&tenant);
                 }
-
-                let mut guard = memory_store.write();
-                guard.merge_logs(logs);
-                guard.merge_spans(spans);
             }
         });
 
@@ -50,43 +45,30 @@ impl DuoServer {
             return;
         }
 
-        let memory_store = Arc::clone(&self.memory_store);
+        let tenants = Arc::clone(&self.tenants);
         tokio::spawn(async move {
             let mut interval = tokio::time::interval(Duration::from_secs(10));
             interval.tick().await;
             loop {
                 interval.tick().await;
 
-                println!(
-                    "ipc writing: is locked {}, is_locked_exclusive {}",
-                    memory_store.is_locked(),
-                    memory_store.is_locked_exclusive()
-                );
-                let guard = memory_store.read();
-                if !guard.is_dirty {
-                    continue;
-                }
-
-                let ipc_file = IpcFile::new();
-                if !guard.span_batches.is_empty() {
-                    ipc_file.write_span_ipc(&guard.span_batches).unwrap();
-                }
-
-                if !guard.log_batches.is_empty() {
-                    ipc_file
-                        .write_log_ipc(&guard.log_batches, &guard.log_schema)
-                        .unwrap();
+                for tenant in tenants.all() {
+                    save_processes(&tenant).await;
+                    if write_ipc(&tenant) {
+                        tokio::spawn(async move {
+                            if let Err(err) = schema::persit_log_schema(&tenant.id).await {
+                                println!(
+                                    "persist log schema failed: tenant {}, {err:#}",
+                                    tenant.id
+                                );
+                            }
+                        });
+                    }
                 }
-                drop(guard);
-
-                memory_store.write().is_dirty = false;
-                tokio::spawn(async {
-                    schema::persit_log_schema().await;
-                });
             }
         });
 
-        let memory_store = Arc::clone(&self.memory_store);
+        let tenants = Arc::clone(&self.tenants);
         tokio::spawn(async move {
             // TODO: replace interval with job scheduler
             let mut interval = tokio::time::interval(Duration::from_secs(60));
@@ -94,30 +76,167 @@ impl DuoServer {
             loop {
                 interval.tick().await;
 
-                let pw = PartitionWriter::with_minute();
+                for tenant in tenants.all() {
+                    write_partition(&tenant).await;
+                }
+            }
+        });
+    }
+}
+
+/// Move the logs and intact spans of the tenant into its memory store.
+fn aggregate(tenant: &Tenant) {
+    let memory_store = &tenant.memory_store;
+    let logs = mem::take(&mut *tenant.logs.write());
+    let spans = tenant
+        .aggregator
+        .write()
+        .aggregate(|process_id| memory_store.read().service_of(process_id));
+    if logs.is_empty() && spans.is_empty() {
+        return;
+    }
+
+    let mut guard = memory_store.write();
+    if !logs.is_empty() {
+        if let Err(err) = guard.merge_logs(logs) {
+            println!("merge logs failed: tenant {}, {err:#}", tenant.id);
+        }
+    }
+    if !spans.is_empty() {
+        guard.merge_spans(spans);
+    }
+}
+
+/// Save the heartbeats since the last round to the registry,
+/// one write per process however often it beats.
+async fn save_processes(tenant: &Tenant) {
+    let processes = tenant.memory_store.write().take_unsaved_processes();
+    for (i, process) in processes.iter().enumerate() {
+        if let Err(err) = registry::save_process(&tenant.id, process).await {
+            println!("save processes failed: tenant {}, {err:#}", tenant.id);
+            // Retry at the next round.
+            tenant.memory_store.write().mark_unsaved(&processes[i..]);
+            return;
+        }
+    }
+}
+
+/// Snapshot the memory store of the tenant, return whether written.
+/// It stays dirty to be written at the next round if failed.
+fn write_ipc(tenant: &Tenant) -> bool {
+    let memory_store = &tenant.memory_store;
+    tracing::debug!(
+        "ipc writing: tenant {}, is locked {}, is_locked_exclusive {}",
+        tenant.id,
+        memory_store.is_locked(),
+        memory_store.is_locked_exclusive()
+    );
+    let guard = memory_store.read();
+    if !guard.is_dirty {
+        return false;
+    }
+
+    let ipc_file = IpcFile::new(&tenant.id);
+    let mut result = Ok(());
+    if !guard.span_batches.is_empty() {
+        result = ipc_file.write_span_ipc(&guard.span_batches);
+    }
+
+    if result.is_ok() && !guard.log_batches.is_empty() {
+        result = ipc_file.write_log_ipc(&guard.log_batches, &guard.log_schema);
+    }
+    drop(guard);
+
+    if let Err(err) = result {
+        println!("write ipc failed: tenant {}, {err:#}", tenant.id);
+        return false;
+    }
+    memory_store.write().is_dirty = false;
+    true
+}
+
+/// Flush the memory store of the tenant to the object store.
+async fn write_partition(tenant: &Tenant) {
+    let memory_store = &tenant.memory_store;
+    let pw = match PartitionWriter::with_minute(&tenant.id) {
+        Ok(pw) => pw,
+        Err(err) => {
+            // Keep the data in memory, retry at the next round.
+            println!("write partition failed: tenant {}, {err:#}", tenant.id);
+            return;
+        }
+    };
+    tracing::debug!(
+        "write partition: tenant {}, is locked {}, is_locked_exclusive {}",
+        tenant.id,
+        memory_store.is_locked(),
+        memory_store.is_locked_exclusive()
+    );
+
+    // clear the previous log schema
+    let (span_batches, log_batches) = { memory_store.write().reset() };
+
+    let (mut failed_spans, mut failed_logs) = (vec![], vec![]);
+    if !span_batches.is_empty() {
+        match pw.write_partition("span", &span_batches).await {
+            Ok(()) => {
+                tracing::debug!("write partition done: tenant {}, span", tenant.id);
+                write_dependencies(tenant, &pw, &span_batches).await;
+            }
+            Err(err) => {
                 println!(
-                    "write partition: is locked {}, is_locked_exclusive {}",
-                    memory_store.is_locked(),
-                    memory_store.is_locked_exclusive()
+                    "write partition failed: tenant {}, span, {err:#}",
+                    tenant.id
                 );
+                failed_spans = span_batches;
+            }
+        }
+    }
 
-                // clear the previous log schema
-                let (span_batches, log_batches) = { memory_store.write().reset() };
+    if !log_batches.is_empty() {
+        match pw.write_partition("log", &log_batches).await {
+            Ok(()) => tracing::debug!("write partition done: tenant {}, log", tenant.id),
+            Err(err) => {
+                println!("write partition failed: tenant {}, log, {err:#}", tenant.id);
+                failed_logs = log_batches;
+            }
+        }
+    }
 
-                if !span_batches.is_empty() {
-                    pw.write_partition("span", &span_batches).await.unwrap();
-                    println!("write partition done: span");
-                }
+    let ipc_file = IpcFile::new(&tenant.id);
+    if let Err(err) = ipc_file.clear() {
+        println!("clear ipc failed: tenant {}, {err:#}", tenant.id);
+    }
+    if !failed_spans.is_empty() || !failed_logs.is_empty() {
+        // Retry at the next round, and keep them in the IPC file meanwhile
+        // rather than the written ones.
+        memory_store.write().restore(failed_spans, failed_logs);
+        write_ipc(tenant);
+    }
+}
 
-                if !log_batches.is_empty() {
-                    pw.write_partition("log", &log_batches).await.unwrap();
-                    println!("write partition done: log");
-                }
+/// Pre-aggregate the service links resolved by the written spans.
+async fn write_dependencies(tenant: &Tenant, pw: &PartitionWriter, span_batches: &[RecordBatch]) {
+    let spans = match serialize_record_batches::<SpanNode>(span_batches) {
+        Ok(spans) => spans,
+        Err(err) => {
+            println!("read span nodes failed: tenant {}, {err:#}", tenant.id);
+            return;
+        }
+    };
+    let calls = tenant.dependencies.write().track(spans);
+    if calls.is_empty() {
+        return;
+    }
 
-                let ipc_file = IpcFile::new();
-                ipc_file.clear().unwrap();
-            }
-        });
+    let processes = { tenant.memory_store.read().processes() };
+    let links = dependency::service_links(calls, &processes);
+    let result = match dependency::convert_links_to_record_batch(&links) {
+        Ok(batch) => pw.write_partition("dependency", &[batch]).await,
+        Err(err) => Err(err),
+    };
+    if let Err(err) = result {
+        println!("write dependencies failed: tenant {}, {err:#}", tenant.id);
     }
 }
 
@@ -127,29 +246,54 @@ impl Instrument for DuoServer {
         &self,
         request: Request<RegisterProcessRequest>,
     ) -> Result<Response<RegisterProcessResponse>, Status> {
-        let process = request
-            .into_inner()
-            .process
-            .ok_or_else(|| tonic::Status::invalid_argument("missing process"))?;
+        let tenant = super::request_tenant(&self.tenants, &request)?;
+        let RegisterProcessRequest {
+            process,
+            process_id,
+        } = request.into_inner();
+        let process = process.ok_or_else(|| tonic::Status::invalid_argument("missing process"))?;
         info!("register process: {}", process.name);
-        let process_id = self
-            .memory_store
-            .write()
-            .register_process(process)
-            .expect("Register process failed.");
-        Ok(Response::new(RegisterProcessResponse { process_id }))
+        let mut known = None;
+        if !process_id.is_empty() {
+            // Reconnecting, keep the id if any node knows it.
+            known = tenant
+                .memory_store
+                .write()
+                .reregister_process(&process_id, &process);
+            if known.is_none() && !crate::is_memory_mode() {
+                tenant
+                    .reload_processes()
+                    .await
+                    .map_err(|err| Status::internal(err.to_string()))?;
+                known = tenant
+                    .memory_store
+                    .write()
+                    .reregister_process(&process_id, &process);
+            }
+        }
+        let process =
+            known.unwrap_or_else(|| tenant.memory_store.write().register_process(process));
+        if !crate::is_memory_mode() {
+            registry::save_process(&tenant.id, &process)
+                .await
+                .map_err(|err| Status::internal(err.to_string()))?;
+        }
+        Ok(Response::new(RegisterProcessResponse {
+            process_id: process.id,
+        }))
     }
 
     async fn record_span(
         &self,
         request: Request<RecordSpanRequest>,
     ) -> Result<Response<RecordSpanResponse>, Status> {
+        let tenant = super::request_tenant(&self.tenants, &request)?;
         let span = request
             .into_inner()
             .span
             .ok_or_else(|| tonic::Status::invalid_argument("missing span"))?;
         debug!(target: "duo_internal", "record span: {}", span.name);
-        self.aggregator.write().record_span(span);
+        tenant.aggregator.write().record_span(span);
         Ok(Response::new(RecordSpanResponse {}))
     }
 
@@ -159,11 +303,56 @@ impl Instrument for DuoServer {
     ) -> Result<Response<RecordEventResponse>, Status> {
         debug!(target: "duo_internal", "record event, {:?}", request);
 
+        let tenant = super::request_tenant(&self.tenants, &request)?;
         let log = request
             .into_inner()
             .log
             .ok_or_else(|| tonic::Status::invalid_argument("missing event"))?;
-        self.logs.write().push(log.into());
+        tenant.record_logs(vec![log.into()]);
         Ok(Response::new(RecordEventResponse {}))
     }
+
+    async fn record_batch(
+        &self,
+        request: Request<RecordBatchRequest>,
+    ) -> Result<Response<RecordBatchResponse>, Status> {
+        let tenant = super::request_tenant(&self.tenants, &request)?;
+        let RecordBatchRequest { spans, logs } = request.into_inner();
+        debug!(target: "duo_internal", "record batch: {} spans, {} logs", spans.len(), logs.len());
+        if !spans.is_empty() {
+            let mut aggregator = tenant.aggregator.write();
+            spans
+                .into_iter()
+                .for_each(|span| aggregator.record_span(span));
+        }
+        if !logs.is_empty() {
+            tenant.record_logs(logs.into_iter().map(Log::from).collect());
+        }
+        Ok(Response::new(RecordBatchResponse {}))
+    }
+
+    async fn heartbeat(
+        &self,
+        request: Request<HeartbeatRequest>,
+    ) -> Result<Response<HeartbeatResponse>, Status> {
+        let tenant = super::request_tenant(&self.tenants, &request)?;
+        let HeartbeatRequest {
+            process_id,
+            stopped,
+        } = request.into_inner();
+        // Saved to the registry by the next IPC round.
+        let mut process = tenant.memory_store.write().heartbeat(&process_id, stopped);
+        if process.is_none() && !crate::is_memory_mode() {
+            // Registered by another node since the last refresh.
+            tenant
+                .reload_processes()
+                .await
+                .map_err(|err| Status::internal(err.to_string()))?;
+            process = tenant.memory_store.write().heartbeat(&process_id, stopped);
+        }
+        if process.is_none() {
+            return Err(Status::not_found(format!("unknown process: {process_id}")));
+        }
+        Ok(Response::new(HeartbeatResponse {}))
+    }
 }
@@ -1,22 +1,21 @@
 use std::fs::{self, File};
-use std::path::{Path, PathBuf};
+use std::path::PathBuf;
 
 use anyhow::Result;
 use arrow_schema::Schema;
 use datafusion::arrow::ipc::writer::FileWriter;
 use datafusion::arrow::{array::RecordBatch, ipc::reader::FileReader};
 
-use crate::{config, schema};
+use crate::{schema, tenant};
 
 pub struct IpcFile {
     path: PathBuf,
 }
 
 impl IpcFile {
-    pub fn new() -> Self {
-        let config = config::load();
+    pub fn new(tenant: &str) -> Self {
         Self {
-            path: Path::new(&config.data_dir).join("ipc"),
+            path: tenant::data_dir(tenant).join("ipc"),
         }
     }
 
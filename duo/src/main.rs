@@ -1,13 +1,18 @@
-use std::sync::{
-    atomic::{AtomicBool, Ordering},
-    Arc,
+use std::{
+    future,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
 };
 
 use anyhow::Result;
-use clap::Parser;
-use config::DuoConfig;
+use auth::Authenticator;
+use clap::{Parser, Subcommand};
+use config::{DuoConfig, ListenAddr};
 use duo_subscriber::DuoLayer;
-use parking_lot::RwLock;
+use tenant::Tenants;
 use tracing::Level;
 use tracing_subscriber::{
     filter::{self, Targets},
@@ -18,19 +23,30 @@ use tracing_subscriber::{
 };
 
 mod aggregator;
+mod analysis;
 mod arrow;
+mod auth;
+mod cluster;
 mod config;
+mod dependency;
+mod diff;
+mod flamegraph;
 mod grpc;
 mod ipc;
 mod memory;
 mod models;
 mod partition;
 mod query;
+mod registry;
 mod schema;
+mod tenant;
+#[cfg(test)]
+mod testing;
 mod utils;
 mod web;
 
 pub use aggregator::SpanAggregator;
+pub use dependency::DependencyTracker;
 pub use grpc::spawn_server as spawn_grpc_server;
 pub use memory::MemoryStore;
 pub use models::{Log, Process, Span, TraceExt};
@@ -51,12 +67,12 @@ static MEMORY_MODE: AtomicBool = AtomicBool::new(false);
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    /// The web server listening port.
-    #[arg(short, default_value_t = 3000)]
-    web_port: u16,
-    /// The gRPC server listening port.
-    #[arg(short, default_value_t = 6000)]
-    grpc_port: u16,
+    /// The web server listening port, overrides `server.web.addr`.
+    #[arg(short)]
+    web_port: Option<u16>,
+    /// The gRPC server listening port, overrides `server.grpc.addr`.
+    #[arg(short)]
+    grpc_port: Option<u16>,
     #[arg(short, long)]
     /// Enable the memory mode, which never persist collected data.
     /// This mode suit for local development.
@@ -65,49 +81,114 @@ struct Args {
     #[arg(short = 's', long)]
     collect_self: bool,
     /// Configuration file path.
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     config_file: Option<String>,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Manage the configuration.
+    #[command(subcommand)]
+    Config(ConfigCommand),
+}
+
+#[derive(Subcommand, Debug)]
+enum ConfigCommand {
+    /// Validate the configuration file and the `DUO_*` environment
+    /// variables without starting the servers.
+    Check,
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    println!("{}", DUO_BANNER);
     let Args {
         web_port,
         grpc_port,
         memory_mode,
         collect_self,
         config_file,
+        command,
     } = Args::parse();
+    if let Some(Command::Config(ConfigCommand::Check)) = command {
+        DuoConfig::load(config_file.as_ref())?;
+        println!("Config is valid");
+        return Ok(());
+    }
+
+    println!("{}", DUO_BANNER);
     if memory_mode {
         MEMORY_MODE.store(memory_mode, Ordering::Relaxed);
         println!("Running Duo in memory mode, all data will be lost after the process exits");
     }
 
-    let config = match config_file {
-        Some(config_file) => DuoConfig::parse_from_toml(config_file)?,
-        None => DuoConfig::default(),
-    };
+    let mut config = DuoConfig::load(config_file)?;
+    if let Some(port) = web_port {
+        config.server.web.set_port(port);
+    }
+    if let Some(port) = grpc_port {
+        config.server.grpc.set_port(port);
+    }
     config::set(config);
     schema::load().await?;
+    let role = config::load().cluster.role;
+    if !memory_mode && role.collects() {
+        partition::spawn_mover()?;
+    }
 
-    let memory_store = Arc::new(RwLock::new(MemoryStore::load()?));
-    spawn_grpc_server(Arc::clone(&memory_store), grpc_port);
+    let tenants = Arc::new(Tenants::load()?);
+    let authenticator = Arc::new(Authenticator::load(&config::load().auth)?);
+    cluster::spawn_refresh(Arc::clone(&tenants));
+    if role.collects() {
+        spawn_grpc_server(Arc::clone(&tenants), Arc::clone(&authenticator))?;
+    }
 
-    let duo_layer = if collect_self {
-        let layer = DuoLayer::new(
-            "duo",
-            format!("grpc://127.0.0.1:{}", grpc_port).parse().unwrap(),
-        )
-        .await
-        .with_filter(filter::filter_fn(|metadata| {
+    let config = config::load();
+    let self_addr = match config.server.grpc_addr() {
+        _ if !collect_self => None,
+        _ if !role.collects() => {
+            println!("Warning: --collect-self is not supported on a query node");
+            None
+        }
+        _ if config.server.grpc.tls.is_some() => {
+            println!("Warning: --collect-self is not supported when the gRPC server uses TLS");
+            None
+        }
+        ListenAddr::Tcp(addr) => {
+            // Reach the server through loopback if it listens on all interfaces.
+            let ip = match addr.ip() {
+                IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+                ip => ip,
+            };
+            Some(SocketAddr::new(ip, addr.port()))
+        }
+        #[cfg(unix)]
+        ListenAddr::Unix(_) => {
+            println!(
+                "Warning: --collect-self is not supported when the gRPC server uses a unix socket"
+            );
+            None
+        }
+    };
+    // Keep the guard alive until the server exits.
+    let (duo_layer, _duo_guard) = if let Some(addr) = self_addr {
+        let mut builder = DuoLayer::builder()
+            .service_name("duo")
+            .uri(format!("grpc://{addr}").parse().unwrap());
+        if let Some(key) = authenticator.ingest_key() {
+            builder = builder.auth_token(key);
+        }
+        let (layer, guard) = builder.build()?;
+        let layer = layer.with_filter(filter::filter_fn(|metadata| {
             // Ignore "duo_internal" event to avoid recursively report event to duo-server
             metadata.target() != "duo_internal"
         }));
         tracing::debug!("Collect self spans and logs...");
-        Some(layer)
+        (Some(layer), Some(guard))
     } else {
-        None
+        (None, None)
     };
     tracing_subscriber::registry()
         .with(fmt::layer())
@@ -116,7 +197,12 @@ async fn main() -> Result<()> {
         // .with(Targets::new().with_default(Level::DEBUG))
         .init();
 
-    run_web_server(memory_store, web_port).await?;
+    if role.queries() {
+        run_web_server(tenants, authenticator).await?;
+    } else {
+        // The collector only serves the gRPC ingest.
+        future::pending::<()>().await;
+    }
     Ok(())
 }
 
@@ -1,9 +1,16 @@
 use std::sync::Arc;
-use std::{collections::HashMap, fmt::Debug, fs::File, io::Write, mem, path::Path};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Debug,
+    fs::File,
+    mem,
+};
 
 use crate::arrow::{convert_log_to_record_batch, convert_span_to_record_batch};
 use crate::ipc::IpcFile;
-use crate::{config, schema, Log, Process, Span};
+use crate::tenant::{self, DEFAULT_TENANT};
+use crate::utils::now_micros;
+use crate::{schema, Log, Process, Span};
 use anyhow::Result;
 use arrow_schema::Schema;
 use datafusion::arrow::array::RecordBatch;
@@ -11,8 +18,11 @@ use datafusion::arrow::array::RecordBatch;
 use duo_api as proto;
 
 pub struct MemoryStore {
+    pub tenant: String,
     // Collection of services.
     services: HashMap<String, Vec<Process>>,
+    // The processes with heartbeats not saved to the registry yet.
+    unsaved_processes: HashSet<String>,
     pub log_schema: Arc<Schema>,
     pub span_batches: Vec<RecordBatch>,
     pub log_batches: Vec<RecordBatch>,
@@ -22,6 +32,7 @@ pub struct MemoryStore {
 impl Debug for MemoryStore {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("MemoryStore")
+            .field("tenant", &self.tenant)
             .field("services", &self.services.len())
             .finish()
     }
@@ -36,27 +47,31 @@ impl Default for MemoryStore {
 impl MemoryStore {
     pub fn new() -> Self {
         MemoryStore {
+            tenant: DEFAULT_TENANT.to_string(),
             services: HashMap::new(),
-            log_schema: schema::get_log_schema(),
+            unsaved_processes: HashSet::new(),
+            log_schema: schema::get_log_schema(DEFAULT_TENANT),
             span_batches: vec![],
             log_batches: vec![],
             is_dirty: false,
         }
     }
 
-    pub fn load() -> Result<Self> {
-        let config = config::load();
-        let path = Path::new(&config.data_dir);
-        let ipc_file = IpcFile::new();
+    pub fn load(tenant: &str) -> Result<Self> {
+        let path = tenant::data_dir(tenant);
+        let ipc_file = IpcFile::new(tenant);
         let span_batches = ipc_file.read_span_ipc()?;
         let log_batches = ipc_file.read_log_ipc()?;
         let mut store = Self {
+            tenant: tenant.to_string(),
             span_batches,
             log_batches,
             services: HashMap::new(),
-            log_schema: schema::get_log_schema(),
+            unsaved_processes: HashSet::new(),
+            log_schema: schema::get_log_schema(tenant),
             is_dirty: false,
         };
+        // Registered before the processes were shared in the object store.
         let path = path.join("process.json");
         if !path.exists() {
             return Ok(store);
@@ -87,6 +102,17 @@ impl MemoryStore {
         )
     }
 
+    /// Put back the batches failed to write, before the ones merged meanwhile.
+    pub(super) fn restore(
+        &mut self,
+        span_batches: Vec<RecordBatch>,
+        log_batches: Vec<RecordBatch>,
+    ) {
+        self.span_batches.splice(0..0, span_batches);
+        self.log_batches.splice(0..0, log_batches);
+        self.is_dirty = true;
+    }
+
     pub(super) fn processes(&self) -> HashMap<String, Process> {
         self.services
             .values()
@@ -99,37 +125,129 @@ impl MemoryStore {
             .collect()
     }
 
+    /// The service name of the process.
+    pub(super) fn service_of(&self, process_id: &str) -> Option<String> {
+        self.services
+            .values()
+            .flatten()
+            .find(|process| process.id == process_id)
+            .map(|process| process.service_name.clone())
+    }
+
     pub(super) fn service_names(&self) -> Vec<String> {
         self.services.keys().cloned().collect()
     }
 
-    /// Register new process and return the process id.
-    pub(crate) fn register_process(&mut self, process: proto::Process) -> Result<String> {
+    /// Register new process, the caller should save it to the shared registry.
+    pub(crate) fn register_process(&mut self, process: proto::Process) -> Process {
         let service_name = process.name;
         let service_processes = self.services.entry(service_name.clone()).or_default();
 
-        // TODO: generate new process id
-        let process_id = format!("{}-{}", &service_name, service_processes.len());
-        service_processes.push(Process {
-            id: process_id.clone(),
+        let now = now_micros();
+        let process = Process {
+            id: new_process_id(&service_name),
             service_name,
             tags: process
                 .tags
                 .into_iter()
                 .map(|(key, value)| (key, value.into()))
                 .collect(),
-        });
-        self.write_process()?;
-        Ok(process_id)
+            start_time: Some(now),
+            last_seen: Some(now),
+            stop_time: None,
+        };
+        service_processes.push(process.clone());
+        process
+    }
+
+    /// Register the process again with the id of its previous registration,
+    /// return the updated process to save, `None` if the id is unknown or
+    /// belongs to another service.
+    pub(crate) fn reregister_process(
+        &mut self,
+        process_id: &str,
+        process: &proto::Process,
+    ) -> Option<Process> {
+        let known = self
+            .services
+            .get_mut(&process.name)?
+            .iter_mut()
+            .find(|known| known.id == process_id)?;
+        known.tags = process
+            .tags
+            .iter()
+            .map(|(key, value)| (key.clone(), value.clone().into()))
+            .collect();
+        known.last_seen = Some(now_micros());
+        known.stop_time = None;
+        Some(known.clone())
+    }
+
+    /// Record the heartbeat of the process, it's saved by the next
+    /// [`take_unsaved_processes`](Self::take_unsaved_processes).
+    /// Return the updated process, `None` if the process is unknown.
+    pub(crate) fn heartbeat(&mut self, process_id: &str, stopped: bool) -> Option<Process> {
+        let process = self
+            .services
+            .values_mut()
+            .flatten()
+            .find(|process| process.id == process_id)?;
+        let now = now_micros();
+        process.last_seen = Some(now);
+        if stopped {
+            process.stop_time = Some(now);
+        }
+        self.unsaved_processes.insert(process.id.clone());
+        Some(process.clone())
+    }
+
+    /// Take the processes to save to the registry, the caller should
+    /// [`mark_unsaved`](Self::mark_unsaved) them again if failed.
+    pub(crate) fn take_unsaved_processes(&mut self) -> Vec<Process> {
+        let unsaved = mem::take(&mut self.unsaved_processes);
+        self.services
+            .values()
+            .flatten()
+            .filter(|process| unsaved.contains(&process.id))
+            .cloned()
+            .collect()
+    }
+
+    pub(crate) fn mark_unsaved(&mut self, processes: &[Process]) {
+        self.unsaved_processes
+            .extend(processes.iter().map(|process| process.id.clone()));
+    }
+
+    /// The ids of the processes of the service.
+    pub(crate) fn process_ids(&self, service: &str) -> Vec<String> {
+        self.services
+            .get(service)
+            .map(|processes| processes.iter().map(|process| process.id.clone()).collect())
+            .unwrap_or_default()
+    }
+
+    /// Add the processes registered by the other nodes.
+    pub(crate) fn merge_processes(&mut self, processes: Vec<Process>) {
+        for process in processes {
+            let service_processes = self
+                .services
+                .entry(process.service_name.clone())
+                .or_default();
+            match service_processes.iter_mut().find(|p| p.id == process.id) {
+                Some(existing) => existing.merge(process),
+                None => service_processes.push(process),
+            }
+        }
     }
 
-    pub fn merge_logs(&mut self, logs: Vec<Log>) {
-        let batches = convert_log_to_record_batch(logs).unwrap();
+    pub fn merge_logs(&mut self, logs: Vec<Log>) -> Result<()> {
+        let batches = convert_log_to_record_batch(&self.tenant, logs)?;
 
         let schema = batches.schema();
-        self.log_schema = schema::merge_log_schema(schema);
+        self.log_schema = schema::merge_log_schema(&self.tenant, schema)?;
         self.log_batches.push(batches);
         self.is_dirty = true;
+        Ok(())
     }
 
     pub fn merge_spans(&mut self, spans: Vec<Span>) {
@@ -137,13 +255,66 @@ impl MemoryStore {
             .push(convert_span_to_record_batch(spans).unwrap());
         self.is_dirty = true;
     }
+}
 
-    fn write_process(&self) -> Result<()> {
-        let config = config::load();
-        let mut file = File::create(Path::new(&config.data_dir).join("process.json"))?;
-        file.write_all(
-            serde_json::to_string(&self.processes().values().collect::<Vec<_>>())?.as_bytes(),
-        )?;
-        Ok(())
+/// Unique across restarts and the nodes sharing the registry.
+pub(crate) fn new_process_id(service_name: &str) -> String {
+    format!("{service_name}-{:016x}", rand::random::<u64>())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_heartbeat() {
+        let mut store = MemoryStore::new();
+        let process = store.register_process(proto::Process {
+            name: String::from("api"),
+            ..Default::default()
+        });
+
+        let alive = store.heartbeat(&process.id, false).unwrap();
+        assert!(alive.last_seen >= process.last_seen);
+        assert_eq!(alive.stop_time, None);
+
+        let stopped = store.heartbeat(&process.id, true).unwrap();
+        assert_eq!(stopped.stop_time, stopped.last_seen);
+        // Updated in the store too.
+        assert_eq!(store.processes()[&process.id].stop_time, stopped.stop_time);
+
+        // Saved once for both heartbeats.
+        let unsaved = store.take_unsaved_processes();
+        assert_eq!(unsaved.len(), 1);
+        assert_eq!(unsaved[0].stop_time, stopped.stop_time);
+        assert!(store.take_unsaved_processes().is_empty());
+        store.mark_unsaved(&unsaved);
+        assert_eq!(store.take_unsaved_processes().len(), 1);
+
+        assert!(store.heartbeat("unknown", false).is_none());
+    }
+
+    #[test]
+    fn test_reregister_process() {
+        let mut store = MemoryStore::new();
+        let api = proto::Process {
+            name: String::from("api"),
+            ..Default::default()
+        };
+        let process = store.register_process(api.clone());
+        store.heartbeat(&process.id, true).unwrap();
+
+        let again = store.reregister_process(&process.id, &api).unwrap();
+        assert_eq!(again.id, process.id);
+        assert_eq!(again.start_time, process.start_time);
+        assert_eq!(again.stop_time, None);
+        assert_eq!(store.processes().len(), 1);
+
+        let web = proto::Process {
+            name: String::from("web"),
+            ..Default::default()
+        };
+        assert!(store.reregister_process(&process.id, &web).is_none());
+        assert!(store.reregister_process("unknown", &api).is_none());
     }
 }
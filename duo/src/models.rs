@@ -1,8 +1,9 @@
+use crate::aggregator::RUNNING_TAG;
 use crate::web::deser;
 use duo_api as proto;
 use serde::{Deserialize, Serialize};
 use serde_json::Value as JsonValue;
-use std::{collections::HashMap, time::SystemTime};
+use std::{cmp::Ordering, collections::HashMap, time::SystemTime};
 use time::{Duration, OffsetDateTime};
 use tracing::Level;
 
@@ -12,6 +13,41 @@ pub struct Process {
     #[serde(rename = "serviceName")]
     pub service_name: String,
     pub tags: HashMap<String, JsonValue>,
+    // The times in microseconds, absent for the processes registered
+    // before they were recorded.
+    #[serde(rename = "startTime", default, skip_serializing_if = "Option::is_none")]
+    pub start_time: Option<i64>,
+    #[serde(rename = "lastSeen", default, skip_serializing_if = "Option::is_none")]
+    pub last_seen: Option<i64>,
+    #[serde(rename = "stopTime", default, skip_serializing_if = "Option::is_none")]
+    pub stop_time: Option<i64>,
+}
+
+/// A process is live if it sent a heartbeat within three heartbeat intervals.
+const LIVE_TIMEOUT_MICROS: i64 = 90_000_000;
+
+impl Process {
+    /// Whether the process is running at `now` (in microseconds).
+    pub fn is_live(&self, now: i64) -> bool {
+        self.stop_time.is_none()
+            && self
+                .last_seen
+                .is_some_and(|last_seen| now - last_seen <= LIVE_TIMEOUT_MICROS)
+    }
+
+    /// Merge the same process known by another node, keep the latest times.
+    /// The later copy decides whether it stopped, a re-registration
+    /// after stopping runs it again.
+    pub fn merge(&mut self, other: Process) {
+        match other.last_seen.cmp(&self.last_seen) {
+            Ordering::Greater => {
+                self.last_seen = other.last_seen;
+                self.stop_time = other.stop_time;
+            }
+            Ordering::Equal => self.stop_time = self.stop_time.or(other.stop_time),
+            Ordering::Less => {}
+        }
+    }
 }
 
 #[derive(Clone, Deserialize)]
@@ -70,6 +106,20 @@ impl Span {
         self.end.map(|end| end - self.start).unwrap_or_default()
     }
 
+    /// Whether the span is still open in the aggregator, merged into the query results.
+    pub fn is_running(&self) -> bool {
+        self.end.is_none() && self.tags.contains_key(RUNNING_TAG)
+    }
+
+    /// The duration, or the time elapsed until `now` if the span is running.
+    pub fn duration_until(&self, now: OffsetDateTime) -> Duration {
+        if self.is_running() {
+            now - self.start
+        } else {
+            self.duration()
+        }
+    }
+
     /// Whether the span is intact.
     /// Intact means the span have both time values: start and end.
     #[inline]
@@ -131,14 +181,11 @@ impl From<proto::Span> for Span {
                         .map(OffsetDateTime::from)
                 })
                 .unwrap_or_else(OffsetDateTime::now_utc),
-            end: span
-                .end
-                .and_then(|timestamp| {
-                    SystemTime::try_from(timestamp)
-                        .ok()
-                        .map(OffsetDateTime::from)
-                })
-                .or_else(|| Some(OffsetDateTime::now_utc())),
+            end: span.end.and_then(|timestamp| {
+                SystemTime::try_from(timestamp)
+                    .ok()
+                    .map(OffsetDateTime::from)
+            }),
             tags,
             logs: Vec::new(),
         }
@@ -196,7 +243,49 @@ fn format_timing_value(value: u64) -> String {
 
 #[cfg(test)]
 mod tests {
-    use super::format_timing_value;
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn test_process_is_live() {
+        let mut process = Process {
+            start_time: Some(0),
+            last_seen: Some(10_000_000),
+            ..testing::process("api-1", "api")
+        };
+        assert!(process.is_live(60_000_000));
+        assert!(!process.is_live(200_000_000));
+
+        process.stop_time = Some(20_000_000);
+        assert!(!process.is_live(30_000_000));
+
+        process.stop_time = None;
+        process.last_seen = None;
+        assert!(!process.is_live(0));
+    }
+
+    #[test]
+    fn test_unfinished_span() {
+        let start = OffsetDateTime::now_utc() - Duration::seconds(5);
+        let mut span = Span::from(proto::Span {
+            id: 1,
+            trace_id: 1,
+            name: "get".into(),
+            start: Some(SystemTime::from(start).into()),
+            ..Default::default()
+        });
+        assert_eq!(span.end, None);
+        assert_eq!(
+            span.duration_until(OffsetDateTime::now_utc()),
+            Duration::ZERO
+        );
+
+        span.tags.insert(RUNNING_TAG.into(), JsonValue::Bool(true));
+        assert_eq!(
+            span.duration_until(start + Duration::seconds(3)),
+            Duration::seconds(3)
+        );
+    }
 
     #[test]
     fn test_timings_format() {
@@ -1,5 +1,7 @@
+mod mover;
 mod query;
 mod writer;
 
+pub use mover::spawn_mover;
 pub use query::PartitionQuery;
 pub use writer::PartitionWriter;
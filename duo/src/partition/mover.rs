@@ -0,0 +1,207 @@
+use std::time::Duration as StdDuration;
+
+use anyhow::Result;
+use futures::TryStreamExt;
+use object_store::{path::Path, ObjectMeta, ObjectStore};
+use time::{Date, Duration, Month, OffsetDateTime};
+
+use crate::{
+    config,
+    tenant::{self, DEFAULT_TENANT},
+};
+
+/// Periodically move the partitions aged out of the hot storage
+/// to the cold storage, if tiering configured.
+pub fn spawn_mover() -> Result<()> {
+    let config = config::load();
+    let (Some(tiering), Some(cold)) = (&config.tiering, config.cold_object_store()?) else {
+        return Ok(());
+    };
+    let hot = config.object_store()?;
+    let hot_hours = tiering.hot_hours;
+    let mut interval = tokio::time::interval(StdDuration::from_secs(tiering.move_interval_secs));
+    tokio::spawn(async move {
+        loop {
+            interval.tick().await;
+
+            let cutoff = OffsetDateTime::now_utc() - Duration::hours(hot_hours as i64);
+            match move_partitions(&*hot, &*cold, cutoff).await {
+                Ok(0) => {}
+                Ok(moved) => println!("moved {moved} partition files to the cold storage"),
+                Err(err) => println!("move partitions failed: {err:#}"),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// The tables partitioned by time.
+const TABLES: [&str; 3] = ["span", "log", "dependency"];
+
+/// Move the partition files whose hour ended before `cutoff`, return the number moved.
+async fn move_partitions(
+    hot: &dyn ObjectStore,
+    cold: &dyn ObjectStore,
+    cutoff: OffsetDateTime,
+) -> Result<usize> {
+    // The default tenant stays at the root, the others are under `tenants/`.
+    let mut prefixes = vec![tenant::storage_prefix(DEFAULT_TENANT)];
+    let tenants = hot
+        .list_with_delimiter(Some(&Path::from("tenants")))
+        .await?;
+    for prefix in tenants.common_prefixes {
+        if let Some(id) = prefix.filename() {
+            prefixes.push(tenant::storage_prefix(id));
+        }
+    }
+
+    let mut moved = 0;
+    for prefix in prefixes {
+        for table in TABLES {
+            let table = Path::from(format!("{prefix}{table}"));
+            for partition in aged_partitions(hot, &table, cutoff).await? {
+                moved += move_files(hot, cold, &partition).await?;
+            }
+        }
+    }
+    Ok(moved)
+}
+
+/// The hour partitions of the table ended before `cutoff`, only list
+/// the hours of the dates which have any.
+async fn aged_partitions(
+    hot: &dyn ObjectStore,
+    table: &Path,
+    cutoff: OffsetDateTime,
+) -> Result<Vec<Path>> {
+    let mut partitions = vec![];
+    for date in hot.list_with_delimiter(Some(table)).await?.common_prefixes {
+        let first_hour = Path::from(format!("{date}/hour=00"));
+        if !partition_end(first_hour.as_ref()).is_some_and(|end| end <= cutoff) {
+            continue;
+        }
+        for hour in hot.list_with_delimiter(Some(&date)).await?.common_prefixes {
+            if partition_end(hour.as_ref()).is_some_and(|end| end <= cutoff) {
+                partitions.push(hour);
+            }
+        }
+    }
+    Ok(partitions)
+}
+
+/// Move the files of the partition, return the number moved.
+async fn move_files(
+    hot: &dyn ObjectStore,
+    cold: &dyn ObjectStore,
+    partition: &Path,
+) -> Result<usize> {
+    let objects = hot
+        .list(Some(partition))
+        .try_collect::<Vec<ObjectMeta>>()
+        .await?;
+    let mut moved = 0;
+    for meta in objects {
+        let data = match hot.get(&meta.location).await {
+            Ok(data) => data.bytes().await?,
+            // Moved by another node sharing the hot storage.
+            Err(object_store::Error::NotFound { .. }) => continue,
+            Err(err) => return Err(err.into()),
+        };
+        // Copy before delete, a query meanwhile may read the file
+        // from both storages but never misses it.
+        cold.put(&meta.location, data.into()).await?;
+        match hot.delete(&meta.location).await {
+            Ok(()) | Err(object_store::Error::NotFound { .. }) => {}
+            Err(err) => return Err(err.into()),
+        }
+        moved += 1;
+    }
+    Ok(moved)
+}
+
+/// The end of the hour which the partition file belongs to, parsed from the
+/// `date=YYYY-MM-DD/hour=HH` segments. `None` for the files out of partitions,
+/// such as the log schema.
+fn partition_end(path: &str) -> Option<OffsetDateTime> {
+    let (mut date, mut hour) = (None, None);
+    for segment in path.split('/') {
+        if let Some(value) = segment.strip_prefix("date=") {
+            date = parse_date(value);
+        } else if let Some(value) = segment.strip_prefix("hour=") {
+            hour = value.parse::<u8>().ok();
+        }
+    }
+    let start = date?.with_hms(hour?, 0, 0).ok()?.assume_utc();
+    Some(start + Duration::hours(1))
+}
+
+fn parse_date(value: &str) -> Option<Date> {
+    let mut parts = value.splitn(3, '-');
+    let year = parts.next()?.parse().ok()?;
+    let month = Month::try_from(parts.next()?.parse::<u8>().ok()?).ok()?;
+    let day = parts.next()?.parse().ok()?;
+    Date::from_calendar_date(year, month, day).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+    use object_store::memory::InMemory;
+
+    use super::*;
+
+    #[test]
+    fn test_partition_end() {
+        let end = partition_end("tenants/a/span/date=2024-03-01/hour=23/minute=05/1.parquet");
+        assert_eq!(
+            end,
+            Some(
+                Date::from_calendar_date(2024, Month::March, 2)
+                    .unwrap()
+                    .midnight()
+                    .assume_utc()
+            )
+        );
+        assert_eq!(partition_end("schema/log_schema.json"), None);
+        assert_eq!(partition_end("log/date=2024-03-01/1.parquet"), None);
+    }
+
+    #[test]
+    fn test_move_partitions() {
+        let (hot, cold) = (InMemory::new(), InMemory::new());
+        let files = [
+            "span/date=2024-03-01/hour=10/minute=00/a.parquet",
+            "span/date=2024-03-01/hour=12/minute=00/a.parquet",
+            "tenants/a/log/date=2024-03-01/hour=10/minute=59/a.parquet",
+            "tenants/a/log/date=2024-03-02/hour=00/minute=00/a.parquet",
+            "schema/log_schema.json",
+        ];
+        let cutoff = Date::from_calendar_date(2024, Month::March, 1)
+            .unwrap()
+            .with_hms(12, 0, 0)
+            .unwrap()
+            .assume_utc();
+        let list = |store: &InMemory| {
+            block_on(
+                store
+                    .list(None)
+                    .map_ok(|meta| meta.location.to_string())
+                    .try_collect::<Vec<_>>(),
+            )
+            .unwrap()
+        };
+
+        block_on(async {
+            for file in files {
+                hot.put(&Path::from(file), b"data".to_vec().into())
+                    .await
+                    .unwrap();
+            }
+            assert_eq!(move_partitions(&hot, &cold, cutoff).await.unwrap(), 2);
+        });
+        let mut moved = list(&cold);
+        moved.sort();
+        assert_eq!(moved, vec![files[0], files[2]]);
+        assert_eq!(list(&hot).len(), 3);
+    }
+}
@@ -1,88 +1,258 @@
-use std::sync::Arc;
+use std::{collections::HashSet, sync::Arc};
 
 use anyhow::Result;
 use datafusion::{
-    arrow::array::RecordBatch,
     datasource::{
         file_format::parquet::ParquetFormat,
         listing::{ListingOptions, ListingTable, ListingTableConfig, ListingTableUrl},
         TableProvider,
     },
-    prelude::{DataFrame, Expr, SessionConfig, SessionContext},
+    prelude::{DataFrame, SessionConfig, SessionContext},
 };
-use time::{Duration, OffsetDateTime};
+use futures::TryStreamExt;
+use object_store::ObjectStore;
+use time::OffsetDateTime;
 use url::Url;
 
-use crate::{config, schema, utils::TimePeriod};
+use crate::{config, schema, tenant, utils::TimePeriod};
 
 static TABLE_SPAN: &str = "span";
+static TABLE_DEPENDENCY: &str = "dependency";
 
 pub struct PartitionQuery {
     ctx: SessionContext,
     object_store_url: Url,
+    // Set if the time range reaches the partitions moved to the cold storage.
+    cold_store_url: Option<Url>,
+    tenant: String,
+    // The storage prefix of the tenant.
+    tenant_prefix: String,
     prefixes: Vec<String>,
 }
 
 impl PartitionQuery {
-    pub fn new(start: OffsetDateTime, end: OffsetDateTime) -> Self {
+    pub fn new(tenant: &str, start: OffsetDateTime, end: OffsetDateTime) -> Result<Self> {
+        let config = config::load();
+        let hot = (config.object_store_url()?, config.object_store()?);
+        let cold = match &config.tiering {
+            Some(tiering) if start < tiering.cutoff() => config
+                .cold_object_store_url()?
+                .zip(config.cold_object_store()?),
+            _ => None,
+        };
+        Ok(Self::with_stores(tenant, start, end, hot, cold))
+    }
+
+    /// Query the hot storage, and the cold storage if the time range
+    /// reaches the partitions moved there.
+    fn with_stores(
+        tenant: &str,
+        start: OffsetDateTime,
+        end: OffsetDateTime,
+        (object_store_url, hot): (Url, Arc<dyn ObjectStore>),
+        cold: Option<(Url, Arc<dyn ObjectStore>)>,
+    ) -> Self {
         let ctx = SessionContext::new_with_config(
             // Enable bloom filter pruning for parquet readers
             SessionConfig::new().with_parquet_bloom_filter_pruning(true),
         );
-        let config = config::load();
-        let object_store_url = config.object_store_url();
-        ctx.register_object_store(&object_store_url, config.object_store());
+        ctx.register_object_store(&object_store_url, hot);
+        let cold_store_url = cold.map(|(url, store)| {
+            ctx.register_object_store(&url, store);
+            url
+        });
         PartitionQuery {
             ctx,
             object_store_url,
+            cold_store_url,
+            tenant: tenant.to_string(),
+            tenant_prefix: tenant::storage_prefix(tenant),
             prefixes: TimePeriod::new(start, end, 1).generate_prefixes(),
         }
     }
 
-    pub fn recent_hours(hours: i64) -> Self {
-        let now = OffsetDateTime::now_utc();
-        let hours_ago = now - Duration::hours(hours);
-        Self::new(hours_ago, now)
-    }
-
-    fn table_paths(&self, table_name: &str) -> Vec<ListingTableUrl> {
+    fn table_paths(&self, store_url: &Url, table_name: &str) -> Vec<ListingTableUrl> {
         self.prefixes
             .iter()
             .filter_map(|prefix| {
                 ListingTableUrl::parse(
-                    self.object_store_url
-                        .join(&format!("{table_name}/{prefix}"))
-                        .unwrap(),
+                    store_url
+                        .join(&format!("{}{table_name}/{prefix}", self.tenant_prefix))
+                        .ok()?,
                 )
                 .ok()
             })
             .collect()
     }
 
-    async fn get_table(&self, table_name: &str) -> Result<Arc<dyn TableProvider>> {
+    /// The files of the cold storage, except the ones still in the hot
+    /// storage, which are being moved and would be read twice.
+    async fn cold_files(&self, cold_url: &Url, table_name: &str) -> Result<Vec<ListingTableUrl>> {
+        let list = |store_url: &Url| {
+            let paths = self.table_paths(store_url, table_name);
+            let runtime = self.ctx.runtime_env();
+            async move {
+                let mut files = Vec::new();
+                for path in paths {
+                    let store = runtime.object_store(path.object_store())?;
+                    files.extend(
+                        store
+                            .list(Some(path.prefix()))
+                            .map_ok(|meta| meta.location)
+                            .try_collect::<Vec<_>>()
+                            .await?,
+                    );
+                }
+                anyhow::Ok(files)
+            }
+        };
+        let hot_files = list(&self.object_store_url)
+            .await?
+            .into_iter()
+            .collect::<HashSet<_>>();
+        let mut files = Vec::new();
+        for location in list(cold_url).await? {
+            if hot_files.contains(&location) {
+                continue;
+            }
+            let mut url = cold_url.clone();
+            url.set_path(&format!("/{location}"));
+            files.push(ListingTableUrl::parse(url)?);
+        }
+        Ok(files)
+    }
+
+    fn get_table(
+        &self,
+        table_name: &str,
+        table_paths: Vec<ListingTableUrl>,
+    ) -> Result<Arc<dyn TableProvider>> {
         let listing_options =
             ListingOptions::new(Arc::new(ParquetFormat::default().with_enable_pruning(true)))
                 .with_file_extension(".parquet");
-        let mut listing_table_config =
-            ListingTableConfig::new_with_multi_paths(self.table_paths(table_name))
-                .with_listing_options(listing_options);
+        let mut listing_table_config = ListingTableConfig::new_with_multi_paths(table_paths)
+            .with_listing_options(listing_options);
         if table_name == TABLE_SPAN {
             listing_table_config = listing_table_config.with_schema(schema::get_span_schema());
+        } else if table_name == TABLE_DEPENDENCY {
+            listing_table_config =
+                listing_table_config.with_schema(schema::get_dependency_schema());
         } else {
             // FIXME: log dynamic fields schema
-            listing_table_config = listing_table_config.with_schema(schema::get_log_schema());
+            listing_table_config =
+                listing_table_config.with_schema(schema::get_log_schema(&self.tenant));
             // listing_table_config = listing_table_config.infer_schema(&self.ctx.state()).await?;
             // println!("listing schema: {:?}", listing_table_config.file_schema);
         }
         Ok(Arc::new(ListingTable::try_new(listing_table_config)?))
     }
 
+    /// A listing table reads from one object store, so the storage tiers
+    /// are read by separate tables.
     pub async fn df(&self, table_name: &str) -> Result<DataFrame> {
-        Ok(self.ctx.read_table(self.get_table(table_name).await?)?)
+        // The hot storage may still have the aged partitions not moved yet,
+        // so always query it.
+        let hot_paths = self.table_paths(&self.object_store_url, table_name);
+        let mut df = self
+            .ctx
+            .read_table(self.get_table(table_name, hot_paths)?)?;
+        if let Some(cold_url) = &self.cold_store_url {
+            let cold_files = self.cold_files(cold_url, table_name).await?;
+            if !cold_files.is_empty() {
+                df = df.union(
+                    self.ctx
+                        .read_table(self.get_table(table_name, cold_files)?)?,
+                )?;
+            }
+        }
+        Ok(df)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use datafusion::{
+        arrow::array::{Int64Array, RecordBatch, StringArray, UInt64Array},
+        parquet::arrow::ArrowWriter,
+    };
+    use object_store::{memory::InMemory, path::Path};
+    use time::Duration;
+
+    use super::*;
+
+    fn span_file(id: u64) -> Vec<u8> {
+        let batch = RecordBatch::try_new(
+            schema::get_span_schema(),
+            vec![
+                Arc::new(UInt64Array::from(vec![id])),
+                Arc::new(UInt64Array::from(vec![None])),
+                Arc::new(UInt64Array::from(vec![1])),
+                Arc::new(StringArray::from(vec!["get"])),
+                Arc::new(StringArray::from(vec!["api-1"])),
+                Arc::new(Int64Array::from(vec![0])),
+                Arc::new(Int64Array::from(vec![Some(10)])),
+                Arc::new(StringArray::from(vec![None::<&str>])),
+            ],
+        )
+        .unwrap();
+        let mut data = Vec::new();
+        let mut writer = ArrowWriter::try_new(&mut data, batch.schema(), None).unwrap();
+        writer.write(&batch).unwrap();
+        writer.close().unwrap();
+        data
     }
 
-    pub async fn query_table(&self, table_name: &str, expr: Expr) -> Result<Vec<RecordBatch>> {
-        let df = self.df(table_name).await?;
-        Ok(df.filter(expr)?.collect().await.unwrap_or_default())
+    #[test]
+    fn test_query_cold_partitions() {
+        let (hot, cold) = (Arc::new(InMemory::new()), Arc::new(InMemory::new()));
+        let partition = "span/date=2024-03-01/hour=10/minute=00";
+        let start = OffsetDateTime::from_unix_timestamp(1_709_287_200).unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let ids = runtime.block_on(async {
+            // Only in the cold storage.
+            cold.put(
+                &Path::from(format!("{partition}/node-1.parquet")),
+                span_file(1).into(),
+            )
+            .await
+            .unwrap();
+            // Being moved, in both storages.
+            for store in [&hot, &cold] {
+                store
+                    .put(
+                        &Path::from(format!("{partition}/node-2.parquet")),
+                        span_file(2).into(),
+                    )
+                    .await
+                    .unwrap();
+            }
+
+            let query = PartitionQuery::with_stores(
+                tenant::DEFAULT_TENANT,
+                start,
+                start + Duration::minutes(1),
+                (Url::parse("memory://hot/").unwrap(), hot.clone()),
+                Some((Url::parse("memory://cold/").unwrap(), cold.clone())),
+            );
+            let batches = query.df(TABLE_SPAN).await.unwrap().collect().await.unwrap();
+            let mut ids = batches
+                .iter()
+                .flat_map(|batch| {
+                    batch
+                        .column_by_name("id")
+                        .unwrap()
+                        .as_any()
+                        .downcast_ref::<UInt64Array>()
+                        .unwrap()
+                        .values()
+                        .to_vec()
+                })
+                .collect::<Vec<_>>();
+            ids.sort_unstable();
+            ids
+        });
+        assert_eq!(ids, vec![1, 2]);
     }
 }
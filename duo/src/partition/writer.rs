@@ -6,28 +6,39 @@ use datafusion::parquet::schema::types::ColumnPath;
 use datafusion::{arrow::array::RecordBatch, parquet::file::properties::WriterProperties};
 use object_store::{path::Path, ObjectStore};
 use rand::{rngs::ThreadRng, Rng};
-use time::OffsetDateTime;
+use time::{OffsetDateTime, UtcOffset};
 
-use crate::config;
+use crate::{config, tenant};
 
 pub struct PartitionWriter {
     object_store: Arc<dyn ObjectStore>,
+    // Prefix the file names, to never collide with the other nodes.
+    node_id: String,
+    // The storage prefix of the tenant.
+    prefix: String,
     partition_path: String,
 }
 
 impl PartitionWriter {
-    pub fn with_minute() -> Self {
-        let now = OffsetDateTime::now_utc();
+    pub fn with_minute(tenant: &str) -> Result<Self> {
+        Self::at(tenant, OffsetDateTime::now_utc())
+    }
+
+    /// Write to the partition of the minute of `time`.
+    pub fn at(tenant: &str, time: OffsetDateTime) -> Result<Self> {
+        let time = time.to_offset(UtcOffset::UTC);
         let config = config::load();
-        PartitionWriter {
-            object_store: config.object_store(),
+        Ok(PartitionWriter {
+            object_store: config.object_store()?,
+            node_id: config.cluster.node_id.clone(),
+            prefix: tenant::storage_prefix(tenant),
             partition_path: format!(
                 "date={}/hour={:02}/minute={:02}",
-                now.date(),
-                now.hour(),
-                now.minute()
+                time.date(),
+                time.hour(),
+                time.minute()
             ),
-        }
+        })
     }
 
     pub async fn write_partition(
@@ -53,8 +64,10 @@ impl PartitionWriter {
         }
         writer.close().await?;
         let path = Path::from(format!(
-            "{table_name}/{}/{}.parquet",
+            "{}{table_name}/{}/{}-{}.parquet",
+            self.prefix,
             self.partition_path,
+            self.node_id,
             ThreadRng::default().gen::<u32>()
         ));
         self.object_store.put(&path, buffer.into()).await?;
@@ -2,12 +2,13 @@ use std::mem;
 use std::sync::Arc;
 
 use crate::arrow::serialize_record_batches;
+use crate::cluster;
 use crate::partition::PartitionQuery;
 use crate::schema;
 use crate::MemoryStore;
 
 use anyhow::{Ok, Result};
-use datafusion::datasource::MemTable;
+use datafusion::datasource::{MemTable, TableProvider};
 use datafusion::logical_expr::SortExpr;
 use datafusion::prelude::DataFrame;
 use datafusion::prelude::SessionContext;
@@ -32,6 +33,7 @@ impl QueryEngine {
     pub fn query_span(&self, expr: Expr) -> Query {
         let guard = self.memory_store.read();
         Query::new(
+            guard.tenant.clone(),
             "span",
             expr,
             MemTable::try_new(schema::get_span_schema(), vec![guard.span_batches.clone()])
@@ -42,6 +44,7 @@ impl QueryEngine {
     pub fn query_log(&self, expr: Expr) -> Query {
         let guard = self.memory_store.read();
         Query::new(
+            guard.tenant.clone(),
             "log",
             expr,
             MemTable::try_new(
@@ -54,6 +57,7 @@ impl QueryEngine {
 }
 
 pub struct Query {
+    tenant: String,
     table_name: &'static str,
     expr: Expr,
     memtable: MemTable,
@@ -62,6 +66,8 @@ pub struct Query {
     sort_expr: Vec<SortExpr>,
     limit: Option<usize>,
     skip: usize,
+    // Whether to query the partitions besides the memory data.
+    partitions: bool,
 }
 
 pub struct AggregateQuery {
@@ -71,8 +77,9 @@ pub struct AggregateQuery {
 }
 
 impl Query {
-    fn new(table_name: &'static str, expr: Expr, memtable: MemTable) -> Self {
+    fn new(tenant: String, table_name: &'static str, expr: Expr, memtable: MemTable) -> Self {
         Self {
+            tenant,
             table_name,
             expr,
             memtable,
@@ -81,6 +88,7 @@ impl Query {
             sort_expr: Vec::new(),
             limit: None,
             skip: 0,
+            partitions: true,
         }
     }
 
@@ -96,20 +104,36 @@ impl Query {
         }
     }
 
+    /// Only query the data not persisted yet, of this node and the peers.
+    pub fn memory_only(self) -> Self {
+        Self {
+            partitions: false,
+            ..self
+        }
+    }
+
     async fn df(self) -> Result<DataFrame> {
         let ctx = SessionContext::new();
+        let schema = self.memtable.schema();
         let mut df = ctx.read_table(Arc::new(self.memtable))?;
+        // The data of the peers not persisted yet.
+        let peer_batches = cluster::peer_batches(&self.tenant, self.table_name, &schema).await;
+        if !peer_batches.is_empty() {
+            let peer_table = MemTable::try_new(schema, vec![peer_batches])?;
+            df = df.union(ctx.read_table(Arc::new(peer_table))?)?;
+        }
 
         // Don't query data from storage in memory mode
-        if !crate::is_memory_mode() {
+        if self.partitions && !crate::is_memory_mode() {
             let pq = PartitionQuery::new(
+                &self.tenant,
                 self.start
                     .unwrap_or_else(|| OffsetDateTime::now_utc() - Duration::minutes(15)),
                 self.end.unwrap_or(OffsetDateTime::now_utc()),
-            );
+            )?;
             df = df.union(pq.df(self.table_name).await?)?;
         }
-        Ok(df.filter(self.expr)?.limit(self.skip, self.limit)?)
+        Ok(df.filter(self.expr)?)
     }
 
     pub fn sort(self, sort_expr: Vec<SortExpr>) -> Self {
@@ -126,11 +150,13 @@ impl Query {
 
     pub async fn collect<T: DeserializeOwned>(mut self) -> Result<Vec<T>> {
         let sort_expr = mem::take(&mut self.sort_expr);
+        let (skip, limit) = (self.skip, self.limit);
         let mut df = self.df().await?;
+        // Sort before limit, so the limit takes the first rows in order.
         if !sort_expr.is_empty() {
             df = df.sort(sort_expr)?;
         }
-        let batches = df.collect().await?;
+        let batches = df.limit(skip, limit)?.collect().await?;
         Ok(serialize_record_batches::<T>(&batches)?)
     }
 }
@@ -138,15 +164,17 @@ impl Query {
 impl AggregateQuery {
     pub async fn collect<T: DeserializeOwned>(mut self) -> Result<Vec<T>> {
         let sort_expr = mem::take(&mut self.raw_query.sort_expr);
+        let (skip, limit) = (self.raw_query.skip, self.raw_query.limit);
         let mut df = self
             .raw_query
             .df()
             .await?
             .aggregate(self.group_expr, self.aggr_expr)?;
+        // The limit applies to the aggregated rows.
         if !sort_expr.is_empty() {
             df = df.sort(sort_expr)?;
         }
-        let batches = df.collect().await?;
+        let batches = df.limit(skip, limit)?.collect().await?;
         Ok(serialize_record_batches::<T>(&batches)?)
     }
 }
@@ -0,0 +1,39 @@
+use anyhow::Result;
+use futures::TryStreamExt;
+use object_store::{path::Path, ObjectStore};
+
+use crate::{config, tenant, Process};
+
+/// The processes are shared by the nodes through the object store, one object
+/// per process, so the concurrent registrations never overwrite each other.
+fn processes_prefix(tenant: &str) -> Path {
+    Path::from(format!("{}processes", tenant::storage_prefix(tenant)))
+}
+
+pub async fn save_process(tenant: &str, process: &Process) -> Result<()> {
+    let object_store = config::load().object_store()?;
+    let path = processes_prefix(tenant).child(format!("{}.json", process.id));
+    object_store
+        .put(&path, serde_json::to_vec(process)?.into())
+        .await?;
+    Ok(())
+}
+
+/// Load the processes registered by all the nodes.
+pub async fn load_processes(tenant: &str) -> Result<Vec<Process>> {
+    let object_store = config::load().object_store()?;
+    let objects = object_store
+        .list(Some(&processes_prefix(tenant)))
+        .try_collect::<Vec<_>>()
+        .await?;
+
+    let mut processes = Vec::with_capacity(objects.len());
+    for meta in objects {
+        let data = object_store.get(&meta.location).await?.bytes().await?;
+        match serde_json::from_slice::<Process>(&data) {
+            Ok(process) => processes.push(process),
+            Err(err) => println!("Warning: read {} failed: {err}", meta.location),
+        }
+    }
+    Ok(processes)
+}
@@ -1,6 +1,6 @@
-use std::sync::{
-    atomic::{AtomicBool, Ordering},
-    Arc, LazyLock, OnceLock,
+use std::{
+    collections::HashMap,
+    sync::{Arc, LazyLock},
 };
 
 use anyhow::Result;
@@ -8,10 +8,28 @@ use arrow_schema::{DataType, Field, Schema};
 use object_store::path::Path;
 use parking_lot::RwLock;
 
-use crate::config;
+use crate::{
+    config,
+    tenant::{self, DEFAULT_TENANT},
+};
+
+/// The log schemas keyed by tenant id, the tenants log different fields.
+static LOG_SCHEMAS: LazyLock<RwLock<HashMap<String, LogSchema>>> = LazyLock::new(Default::default);
+
+struct LogSchema {
+    schema: Arc<Schema>,
+    // Whether has the fields not persisted yet.
+    dirty: bool,
+}
 
-static LOG_SCHEMA: OnceLock<RwLock<Arc<Schema>>> = OnceLock::new();
-static LOG_SCHEMA_DIRTY: AtomicBool = AtomicBool::new(false);
+impl Default for LogSchema {
+    fn default() -> Self {
+        LogSchema {
+            schema: default_log_schema(),
+            dirty: false,
+        }
+    }
+}
 
 static SPAN_SCHEMA: LazyLock<Arc<Schema>> = LazyLock::new(|| {
     Arc::new(Schema::new(vec![
@@ -26,6 +44,14 @@ static SPAN_SCHEMA: LazyLock<Arc<Schema>> = LazyLock::new(|| {
     ]))
 });
 
+static DEPENDENCY_SCHEMA: LazyLock<Arc<Schema>> = LazyLock::new(|| {
+    Arc::new(Schema::new(vec![
+        Field::new("parent", DataType::Utf8, false),
+        Field::new("child", DataType::Utf8, false),
+        Field::new("call_count", DataType::UInt64, false),
+    ]))
+});
+
 #[inline]
 fn default_log_schema() -> Arc<Schema> {
     Arc::new(Schema::new(vec![
@@ -45,57 +71,121 @@ pub fn get_span_schema() -> Arc<Schema> {
     Arc::clone(&SPAN_SCHEMA)
 }
 
+/// The schema of the service links pre-aggregated per partition.
+pub fn get_dependency_schema() -> Arc<Schema> {
+    Arc::clone(&DEPENDENCY_SCHEMA)
+}
+
+/// Load the log schema of the default tenant, the other tenants
+/// load theirs on first access.
 pub async fn load() -> Result<()> {
-    let config = config::load();
-    let object_store = config.object_store();
-    match object_store
-        .get(&Path::from("schema/log_schema.json"))
-        .await
-    {
-        Ok(data) => {
-            let schema = serde_json::from_slice::<Schema>(&data.bytes().await?)?;
-            let latest_schema =
-                Schema::try_merge(vec![(*default_log_schema()).clone(), schema]).unwrap();
-            LOG_SCHEMA_DIRTY.store(true, Ordering::Relaxed);
-            LOG_SCHEMA
-                .set(RwLock::new(Arc::new(latest_schema)))
-                .expect("LogSchema already initialized");
-        }
-        Err(_err) => {
-            LOG_SCHEMA
-                .set(RwLock::new(default_log_schema()))
-                .expect("LogSchema already initialized");
-        }
+    if let Err(err) = reload_log_schema(DEFAULT_TENANT).await {
+        println!("load log schema failed: {err:#}");
     }
-
     Ok(())
 }
 
-pub fn get_log_schema() -> Arc<Schema> {
-    Arc::clone(&LOG_SCHEMA.get().expect("LogSchema not initialized").read())
+/// The object store path of the log schema of the tenant.
+fn log_schema_path(tenant: &str) -> Path {
+    Path::from(format!(
+        "{}schema/log_schema.json",
+        tenant::storage_prefix(tenant)
+    ))
 }
 
-pub fn merge_log_schema(schema: Arc<Schema>) -> Arc<Schema> {
-    let mut guard = LOG_SCHEMA.get().expect("LogSchema not initialized").write();
-    if guard.contains(&schema) {
-        return Arc::clone(&*guard);
+/// The log schema of the tenant, the default one if the tenant has no log yet.
+pub fn get_log_schema(tenant: &str) -> Arc<Schema> {
+    LOG_SCHEMAS
+        .read()
+        .get(tenant)
+        .map(|log_schema| Arc::clone(&log_schema.schema))
+        .unwrap_or_else(default_log_schema)
+}
+
+/// Merge the new log fields of the tenant, fail if a field changes its type.
+pub fn merge_log_schema(tenant: &str, schema: Arc<Schema>) -> Result<Arc<Schema>> {
+    let mut schemas = LOG_SCHEMAS.write();
+    let log_schema = schemas.entry(tenant.to_string()).or_default();
+    if log_schema.schema.contains(&schema) {
+        return Ok(Arc::clone(&log_schema.schema));
     }
 
-    let new_schema =
-        Arc::new(Schema::try_merge(vec![(**guard).clone(), (*schema).clone()]).unwrap());
-    *guard = Arc::clone(&new_schema);
-    LOG_SCHEMA_DIRTY.store(true, Ordering::Relaxed);
-    new_schema
+    let new_schema = Arc::new(Schema::try_merge(vec![
+        (*log_schema.schema).clone(),
+        (*schema).clone(),
+    ])?);
+    log_schema.schema = Arc::clone(&new_schema);
+    log_schema.dirty = true;
+    Ok(new_schema)
+}
+
+/// Merge the log fields of the tenant persisted by the other nodes
+/// sharing the object store.
+pub async fn reload_log_schema(tenant: &str) -> Result<()> {
+    let object_store = config::load().object_store()?;
+    let data = match object_store.get(&log_schema_path(tenant)).await {
+        Ok(data) => data.bytes().await?,
+        Err(object_store::Error::NotFound { .. }) => return Ok(()),
+        Err(err) => return Err(err.into()),
+    };
+    let schema = serde_json::from_slice::<Schema>(&data)?;
+
+    let mut schemas = LOG_SCHEMAS.write();
+    let log_schema = schemas.entry(tenant.to_string()).or_default();
+    if !log_schema.schema.contains(&schema) {
+        // Not dirty, the merged fields are persisted already.
+        log_schema.schema = Arc::new(Schema::try_merge(vec![
+            (*log_schema.schema).clone(),
+            schema,
+        ])?);
+    }
+    Ok(())
 }
 
-pub async fn persit_log_schema() {
-    if LOG_SCHEMA_DIRTY.load(Ordering::Relaxed) {
-        let object_store = config::load().object_store();
-        let payload = serde_json::to_vec(&get_log_schema()).unwrap();
+/// Persist the new log fields of the tenant, they stay dirty
+/// to be persisted next time if failed.
+pub async fn persit_log_schema(tenant: &str) -> Result<()> {
+    let is_dirty = LOG_SCHEMAS
+        .read()
+        .get(tenant)
+        .is_some_and(|log_schema| log_schema.dirty);
+    if is_dirty {
+        // Don't overwrite the fields persisted by the other nodes meanwhile.
+        if let Err(err) = reload_log_schema(tenant).await {
+            println!("reload log schema failed: tenant {tenant}, {err:#}");
+        }
+        let object_store = config::load().object_store()?;
+        let payload = serde_json::to_vec(&get_log_schema(tenant))?;
         object_store
-            .put(&Path::from("schema/log_schema.json"), payload.into())
-            .await
-            .unwrap();
-        LOG_SCHEMA_DIRTY.store(false, Ordering::Relaxed);
+            .put(&log_schema_path(tenant), payload.into())
+            .await?;
+        if let Some(log_schema) = LOG_SCHEMAS.write().get_mut(tenant) {
+            log_schema.dirty = false;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log_schema_per_tenant() {
+        let field = |data_type| Arc::new(Schema::new(vec![Field::new("user_id", data_type, true)]));
+        merge_log_schema("schema-a", field(DataType::Int64)).unwrap();
+        merge_log_schema("schema-b", field(DataType::Utf8)).unwrap();
+        // The type of a field never changes.
+        assert!(merge_log_schema("schema-a", field(DataType::Utf8)).is_err());
+
+        let data_type = |tenant| {
+            get_log_schema(tenant)
+                .field_with_name("user_id")
+                .map(|field| field.data_type().clone())
+                .ok()
+        };
+        assert_eq!(data_type("schema-a"), Some(DataType::Int64));
+        assert_eq!(data_type("schema-b"), Some(DataType::Utf8));
+        assert_eq!(data_type("schema-c"), None);
     }
 }
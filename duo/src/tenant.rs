@@ -0,0 +1,165 @@
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{bail, Result};
+use parking_lot::RwLock;
+use tracing::Level;
+
+use crate::{config, registry, schema, DependencyTracker, Log, MemoryStore, SpanAggregator};
+
+/// The gRPC metadata key and HTTP header which carry the tenant id.
+pub const TENANT_HEADER: &str = "x-duo-tenant";
+/// The tenant of the requests without tenant header.
+pub const DEFAULT_TENANT: &str = "default";
+
+/// The data of a tenant, isolated from other tenants.
+pub struct Tenant {
+    pub id: String,
+    pub memory_store: Arc<RwLock<MemoryStore>>,
+    pub aggregator: Arc<RwLock<SpanAggregator>>,
+    /// The logs waiting for the next aggregation.
+    pub logs: RwLock<Vec<Log>>,
+    /// Links the spans across the partition writes.
+    pub dependencies: RwLock<DependencyTracker>,
+}
+
+impl Debug for Tenant {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Tenant").field("id", &self.id).finish()
+    }
+}
+
+impl Tenant {
+    fn load(id: &str) -> Result<Self> {
+        Ok(Tenant {
+            id: id.to_string(),
+            memory_store: Arc::new(RwLock::new(MemoryStore::load(id)?)),
+            aggregator: Arc::new(RwLock::new(SpanAggregator::new(&config::load()))),
+            logs: RwLock::new(Vec::new()),
+            dependencies: RwLock::new(DependencyTracker::default()),
+        })
+    }
+
+    pub fn record_logs(&self, logs: Vec<Log>) {
+        // The tail sampling always keeps the traces with an error log.
+        for log in &logs {
+            if let (Level::ERROR, Some(trace_id)) = (log.level, log.trace_id) {
+                self.aggregator.write().record_error(trace_id);
+            }
+        }
+        self.logs.write().extend(logs);
+    }
+
+    /// Load the processes registered by all the nodes.
+    pub async fn reload_processes(&self) -> Result<()> {
+        let processes = registry::load_processes(&self.id).await?;
+        self.memory_store.write().merge_processes(processes);
+        Ok(())
+    }
+}
+
+/// All tenants, loaded on first access.
+pub struct Tenants {
+    tenants: RwLock<HashMap<String, Arc<Tenant>>>,
+}
+
+impl Tenants {
+    pub fn load() -> Result<Self> {
+        let tenant = Tenant::load(DEFAULT_TENANT)?;
+        Ok(Tenants {
+            tenants: RwLock::new(HashMap::from([(
+                DEFAULT_TENANT.to_string(),
+                Arc::new(tenant),
+            )])),
+        })
+    }
+
+    /// Get the tenant, `id` should be validated by [`tenant_id`].
+    pub fn get(&self, id: &str) -> Result<Arc<Tenant>> {
+        if let Some(tenant) = self.tenants.read().get(id) {
+            return Ok(Arc::clone(tenant));
+        }
+
+        let mut tenants = self.tenants.write();
+        // Loaded by another request meanwhile.
+        if let Some(tenant) = tenants.get(id) {
+            return Ok(Arc::clone(tenant));
+        }
+        // Any valid id creates a tenant unless the tenants are configured,
+        // bound them to not exhaust the memory.
+        let config = config::load();
+        let created = tenants.keys().filter(|id| *id != DEFAULT_TENANT).count();
+        if config.tenants.is_empty() && created >= config.max_tenants {
+            bail!("Too many tenants, the limit is {}", config.max_tenants);
+        }
+        let tenant = Arc::new(Tenant::load(id)?);
+        tenants.insert(id.to_string(), Arc::clone(&tenant));
+
+        let loading = Arc::clone(&tenant);
+        tokio::spawn(async move {
+            if let Err(err) = loading.reload_processes().await {
+                println!("load processes failed: tenant {}, {err:#}", loading.id);
+            }
+            if let Err(err) = schema::reload_log_schema(&loading.id).await {
+                println!("load log schema failed: tenant {}, {err:#}", loading.id);
+            }
+        });
+        Ok(tenant)
+    }
+
+    pub fn all(&self) -> Vec<Arc<Tenant>> {
+        self.tenants.read().values().cloned().collect()
+    }
+}
+
+/// Validate the tenant id from the request header,
+/// fallback to the default tenant if absent.
+pub fn tenant_id(header: Option<&str>) -> Result<&str> {
+    let id = match header {
+        Some(id) => id,
+        None => return Ok(DEFAULT_TENANT),
+    };
+    if !is_valid_id(id) {
+        bail!("Invalid tenant: {id}");
+    }
+
+    let allowed = &config::load().tenants;
+    if id != DEFAULT_TENANT && !allowed.is_empty() && !allowed.iter().any(|t| t == id) {
+        bail!("Unknown tenant: {id}");
+    }
+    Ok(id)
+}
+
+/// The id is a part of the storage path, only allow the safe characters.
+pub fn is_valid_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= 64
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+/// The object store prefix of the tenant, the default tenant
+/// stays at the root to read the data written before tenants.
+pub fn storage_prefix(tenant: &str) -> String {
+    if tenant == DEFAULT_TENANT {
+        String::new()
+    } else {
+        format!("tenants/{tenant}/")
+    }
+}
+
+/// The local directory for the process list and IPC files of the tenant.
+pub fn data_dir(tenant: &str) -> PathBuf {
+    let config = config::load();
+    let data_dir = Path::new(&config.data_dir);
+    if tenant == DEFAULT_TENANT {
+        data_dir.to_path_buf()
+    } else {
+        data_dir.join("tenants").join(tenant)
+    }
+}
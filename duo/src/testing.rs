@@ -0,0 +1,72 @@
+//! The fixtures shared by the tests.
+
+use std::collections::HashMap;
+
+use time::{Duration, OffsetDateTime};
+
+use crate::{Process, Span, TraceExt};
+
+/// A span of trace 1 by the `api` process named `op{id}`. The times are in
+/// microseconds since the epoch, an unfinished span has no end.
+pub(crate) fn span(id: u64, parent_id: Option<u64>, start: i64, end: Option<i64>) -> Span {
+    let epoch = OffsetDateTime::UNIX_EPOCH;
+    Span {
+        id,
+        trace_id: 1,
+        parent_id,
+        process_id: "api".into(),
+        name: format!("op{id}"),
+        start: epoch + Duration::microseconds(start),
+        end: end.map(|end| epoch + Duration::microseconds(end)),
+        tags: HashMap::new(),
+        logs: Vec::new(),
+    }
+}
+
+impl Span {
+    pub(crate) fn named(self, name: &str) -> Span {
+        Span {
+            name: name.into(),
+            ..self
+        }
+    }
+
+    pub(crate) fn by(self, process_id: &str) -> Span {
+        Span {
+            process_id: process_id.into(),
+            ..self
+        }
+    }
+
+    pub(crate) fn in_trace(self, trace_id: u64) -> Span {
+        Span { trace_id, ..self }
+    }
+}
+
+/// A process of the service, without the times.
+pub(crate) fn process(id: &str, service_name: &str) -> Process {
+    Process {
+        id: id.into(),
+        service_name: service_name.into(),
+        tags: HashMap::new(),
+        start_time: None,
+        last_seen: None,
+        stop_time: None,
+    }
+}
+
+/// The trace of the spans, each process is a service of the same name.
+pub(crate) fn trace(trace_id: u64, spans: Vec<Span>) -> TraceExt {
+    let processes = spans
+        .iter()
+        .map(|span| {
+            let id = span.process_id.as_str();
+            (id.to_string(), process(id, id))
+        })
+        .collect();
+    TraceExt {
+        trace_id,
+        spans,
+        processes,
+    }
+}
@@ -181,6 +181,11 @@ impl TimePeriod {
     }
 }
 
+/// The current time in microseconds, the unit of the stored timestamps.
+pub fn now_micros() -> i64 {
+    (OffsetDateTime::now_utc().unix_timestamp_nanos() / 1000) as i64
+}
+
 #[cfg(test)]
 mod tests {
     use rstest::*;
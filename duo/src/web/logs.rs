@@ -1,21 +1,22 @@
 use std::sync::Arc;
 
-use axum::extract::{Extension, Path, Query};
+use axum::extract::{Path, Query};
 use axum::http::StatusCode;
 use axum::response::{IntoResponse, Response};
 use axum::Json;
 use datafusion::common::DFSchema;
 use datafusion::functions_aggregate::count::count;
 use datafusion::prelude::*;
-use parking_lot::RwLock;
 use serde::{Deserialize, Serialize};
 use time::OffsetDateTime;
 use tracing::{debug, info, warn};
 
 use crate::query::QueryEngine;
-use crate::{schema, Log, MemoryStore};
+use crate::tenant::Tenant;
+use crate::{schema, Log};
 
-use super::deser;
+use super::services::{service_expr, service_process_ids};
+use super::{deser, CurrentTenant};
 
 const DEFAUT_LOG_LIMIT: usize = 50;
 
@@ -34,39 +35,44 @@ pub(super) struct QueryParameters {
 }
 
 #[tracing::instrument]
-pub(super) async fn schema() -> impl IntoResponse {
-    Json(schema::get_log_schema())
+pub(super) async fn schema(CurrentTenant(tenant): CurrentTenant) -> impl IntoResponse {
+    Json(schema::get_log_schema(&tenant.id))
 }
 
 impl QueryParameters {
-    fn expr(&self) -> Expr {
-        let process_prefix = &self.service;
-        let mut expr = col("process_id").like(lit(format!("{process_prefix}%")));
+    fn expr(&self, tenant: &Tenant) -> Expr {
+        let mut expr = service_expr(&service_process_ids(tenant, &self.service));
         if let Some(sql_expr) = &self.expr {
-            let df_schema = DFSchema::try_from(schema::get_log_schema()).unwrap();
-            match SessionContext::new().parse_sql_expr(sql_expr, &df_schema) {
-                Ok(sql_expr) => {
-                    debug!("Parsed expr: {sql_expr}");
-                    expr = expr.and(sql_expr);
-                }
-                Err(err) => {
-                    warn!("Parse expr failed: {err}");
-                    expr = expr.and(col("message").ilike(lit(format!("%{sql_expr}%"))));
-                }
-            }
+            expr = expr.and(parse_expr(&tenant.id, sql_expr));
         }
         info!(expr = ?expr, "Query expr: ");
         expr
     }
 }
 
+/// Parse the SQL expression on the log fields, fallback to
+/// search the text in the message if invalid.
+pub(super) fn parse_expr(tenant: &str, sql_expr: &str) -> Expr {
+    let df_schema = DFSchema::try_from(schema::get_log_schema(tenant)).unwrap();
+    match SessionContext::new().parse_sql_expr(sql_expr, &df_schema) {
+        Ok(expr) => {
+            debug!("Parsed expr: {expr}");
+            expr
+        }
+        Err(err) => {
+            warn!("Parse expr failed: {err}");
+            col("message").ilike(lit(format!("%{sql_expr}%")))
+        }
+    }
+}
+
 #[tracing::instrument]
 pub(super) async fn field_stats(
     Path(field): Path<String>,
     Query(p): Query<QueryParameters>,
-    Extension(memory_store): Extension<Arc<RwLock<MemoryStore>>>,
+    CurrentTenant(tenant): CurrentTenant,
 ) -> Response {
-    if schema::get_log_schema().index_of(&field).is_err() {
+    if schema::get_log_schema(&tenant.id).index_of(&field).is_err() {
         return (StatusCode::NOT_FOUND, format!("Field {field} not exists")).into_response();
     }
 
@@ -75,10 +81,10 @@ pub(super) async fn field_stats(
         value: Option<serde_json::Value>,
         count: i64,
     }
-    let query_engine = QueryEngine::new(memory_store);
+    let query_engine = QueryEngine::new(Arc::clone(&tenant.memory_store));
     let c = col(field);
     let stats = query_engine
-        .query_log(p.expr())
+        .query_log(p.expr(&tenant))
         .range(p.start, p.end)
         // sort by count desc
         .sort(vec![col("count").sort(false, false)])
@@ -100,16 +106,20 @@ pub(super) async fn field_stats(
 #[tracing::instrument]
 pub(super) async fn list(
     Query(p): Query<QueryParameters>,
-    Extension(memory_store): Extension<Arc<RwLock<MemoryStore>>>,
+    CurrentTenant(tenant): CurrentTenant,
 ) -> impl IntoResponse {
-    let query_engine = QueryEngine::new(memory_store);
-    let total_logs = query_engine
-        .query_log(p.expr())
+    Json(query_logs(&tenant, &p, DEFAUT_LOG_LIMIT).await)
+}
+
+/// The logs matching the parameters, the latest first.
+pub(super) async fn query_logs(tenant: &Tenant, p: &QueryParameters, limit: usize) -> Vec<Log> {
+    let query_engine = QueryEngine::new(Arc::clone(&tenant.memory_store));
+    query_engine
+        .query_log(p.expr(tenant))
         .range(p.start, p.end)
         .sort(vec![col("time").sort(false, false)])
-        .limit(p.skip.unwrap_or(0), p.limit.or(Some(DEFAUT_LOG_LIMIT)))
+        .limit(p.skip.unwrap_or(0), p.limit.or(Some(limit)))
         .collect::<Log>()
         .await
-        .unwrap_or_default();
-    Json(total_logs.into_iter().collect::<Vec<_>>())
+        .unwrap_or_default()
 }
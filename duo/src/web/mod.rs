@@ -1,25 +1,36 @@
-use std::{net::SocketAddr, sync::Arc};
+use std::sync::Arc;
 
 use axum::{
+    async_trait,
     body::Body,
-    extract::Extension,
-    http::{header, Method, StatusCode, Uri},
+    extract::{DefaultBodyLimit, Extension, FromRequestParts, Request, State},
+    http::{header, request::Parts, HeaderName, Method, StatusCode, Uri},
+    middleware::{self, Next},
     response::{IntoResponse, Response},
-    routing::get,
+    routing::{get, post},
     Router,
 };
-use parking_lot::RwLock;
+use axum_server::tls_rustls::RustlsConfig;
 use rust_embed::RustEmbed;
 use tower::ServiceBuilder;
 use tower_http::cors::{Any, CorsLayer};
 
-use crate::MemoryStore;
+use crate::{
+    auth::{AuthError, Authenticator},
+    config::{self, ListenAddr, Scope},
+    tenant::{self, Tenant, Tenants, TENANT_HEADER},
+};
 
 pub mod deser;
 mod logs;
+mod otlp;
+mod search;
 pub mod serialize;
 mod services;
+mod stats;
+mod tls;
 mod trace;
+mod transfer;
 
 pub struct JaegerData<I: IntoIterator>(pub I);
 
@@ -29,6 +40,36 @@ struct UiAssets;
 
 pub struct StaticFile(Uri);
 
+/// The caller's tenant, from the `x-duo-tenant` header.
+#[derive(Debug)]
+pub(super) struct CurrentTenant(pub Arc<Tenant>);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for CurrentTenant
+where
+    S: Send + Sync,
+{
+    type Rejection = (StatusCode, String);
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let Some(tenants) = parts.extensions.get::<Arc<Tenants>>() else {
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, "tenants missing".into()));
+        };
+        let header = parts
+            .headers
+            .get(TENANT_HEADER)
+            .map(|value| value.to_str())
+            .transpose()
+            .map_err(|_| (StatusCode::BAD_REQUEST, String::from("invalid tenant")))?;
+        let id =
+            tenant::tenant_id(header).map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?;
+        tenants
+            .get(id)
+            .map(CurrentTenant)
+            .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
+    }
+}
+
 impl IntoResponse for StaticFile {
     fn into_response(self) -> Response {
         let new_path = match self.0.path().trim_start_matches('/') {
@@ -54,49 +95,110 @@ impl IntoResponse for StaticFile {
     }
 }
 
+/// Require the scope for the API.
+async fn require_scope(State(scope): State<Scope>, request: Request, next: Next) -> Response {
+    let Some(authenticator) = request.extensions().get::<Arc<Authenticator>>() else {
+        return (StatusCode::INTERNAL_SERVER_ERROR, "authenticator missing").into_response();
+    };
+    if !authenticator.is_enabled() {
+        return next.run(request).await;
+    }
+
+    let headers = request.headers();
+    let header = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok());
+    let tenant = match headers
+        .get(TENANT_HEADER)
+        .map(|value| value.to_str())
+        .transpose()
+    {
+        Ok(tenant) => tenant,
+        Err(_) => return (StatusCode::BAD_REQUEST, "invalid tenant").into_response(),
+    };
+    let tenant = match tenant::tenant_id(tenant) {
+        Ok(tenant) => tenant,
+        Err(err) => return (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
+    };
+    match authenticator.authorize(header, scope, tenant) {
+        Ok(()) => next.run(request).await,
+        Err(err @ AuthError::Unauthenticated) => {
+            (StatusCode::UNAUTHORIZED, err.to_string()).into_response()
+        }
+        Err(err @ AuthError::Forbidden) => (StatusCode::FORBIDDEN, err.to_string()).into_response(),
+    }
+}
+
 pub async fn run_web_server(
-    memory_store: Arc<RwLock<MemoryStore>>,
-    port: u16,
+    tenants: Arc<Tenants>,
+    authenticator: Arc<Authenticator>,
 ) -> anyhow::Result<()> {
-    let addr = SocketAddr::from(([0, 0, 0, 0], port));
-    let listener = tokio::net::TcpListener::bind(addr).await?;
+    let config = config::load();
+    let addr = match config.server.web_addr() {
+        ListenAddr::Tcp(addr) => addr,
+        #[cfg(unix)]
+        ListenAddr::Unix(_) => anyhow::bail!("The web server only listens on TCP"),
+    };
     let cors = CorsLayer::new()
         // allow `GET` and `POST` when accessing the resource
         .allow_methods([Method::GET, Method::POST])
+        // allow the credential and tenant headers
+        .allow_headers([
+            header::AUTHORIZATION,
+            HeaderName::from_static(TENANT_HEADER),
+        ])
         // allow requests from any origin
         .allow_origin(Any);
     let layer = ServiceBuilder::new()
-        .layer(Extension(memory_store))
+        .layer(Extension(tenants))
+        .layer(Extension(authenticator))
         .layer(cors);
 
-    let app = Router::new()
-        .nest_service("/", get(static_handler))
+    let api = Router::new()
         .route("/api/traces", get(trace::list))
         .route("/api/traces/:id", get(trace::get_by_id))
+        .route("/api/traces/:id/analysis", get(trace::analyze))
+        .route("/api/traces/:id/diff/:other", get(trace::diff))
         .route("/api/services", get(trace::services))
         .route("/api/services/:service/operations", get(trace::operations))
+        .route("/api/dependencies", get(trace::dependency_links))
+        .route("/api/flamegraph", get(trace::flame_graph))
         .route("/api/logs", get(logs::list))
         .route("/api/logs/schema", get(logs::schema))
         .route("/api/logs/stats/:field", get(logs::field_stats))
-        .route("/stats", get(self::stats))
+        .route("/api/stats/services", get(stats::services))
+        .route("/api/stats/processes", get(stats::processes))
+        .route("/api/traces/:id/export", get(transfer::export_trace))
+        .route("/api/logs/export", get(transfer::export_logs))
+        .route_layer(middleware::from_fn_with_state(Scope::Read, require_scope));
+    // Importing writes data like the gRPC ingestion.
+    let import = Router::new()
+        .route("/api/import", post(transfer::import))
+        .layer(DefaultBodyLimit::max(transfer::IMPORT_BODY_LIMIT))
+        .route_layer(middleware::from_fn_with_state(Scope::Ingest, require_scope));
+    let app = Router::new()
+        .nest_service("/", get(static_handler))
+        .merge(api)
+        .merge(import)
         .layer(layer);
 
-    println!("Web server listening on http://{}", addr);
-    axum::serve(listener, app.into_make_service()).await?;
+    match &config.server.web.tls {
+        Some(tls) => {
+            let rustls_config = RustlsConfig::from_config(Arc::new(tls::server_config(tls)?));
+            println!("Web server listening on https://{}", addr);
+            axum_server::bind_rustls(addr, rustls_config)
+                .serve(app.into_make_service())
+                .await?;
+        }
+        None => {
+            let listener = tokio::net::TcpListener::bind(addr).await?;
+            println!("Web server listening on http://{}", addr);
+            axum::serve(listener, app.into_make_service()).await?;
+        }
+    }
     Ok(())
 }
 
 async fn static_handler(uri: Uri) -> impl IntoResponse {
     StaticFile(uri)
 }
-
-#[tracing::instrument]
-async fn stats(Extension(memory_store): Extension<Arc<RwLock<MemoryStore>>>) -> impl IntoResponse {
-    let memory_store = memory_store.read();
-    serde_json::json!({
-            "process": memory_store.processes(),
-            "logs": 0,
-            "spans": 0,
-    })
-    .to_string()
-}
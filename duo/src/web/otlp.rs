@@ -0,0 +1,444 @@
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
+
+use anyhow::{anyhow, Context, Result};
+use serde_json::{json, Map, Value as JsonValue};
+use time::OffsetDateTime;
+use tracing::Level;
+
+use crate::{Log, Process, Span, TraceExt};
+
+use super::transfer::Imported;
+
+const SERVICE_NAME: &str = "service.name";
+const SERVICE_INSTANCE_ID: &str = "service.instance.id";
+const CODE_FILEPATH: &str = "code.filepath";
+const CODE_LINENO: &str = "code.lineno";
+const SCOPE_NAME: &str = "duo";
+const SPAN_KIND_INTERNAL: i32 = 1;
+const STATUS_CODE_ERROR: i32 = 2;
+
+/// Encode the trace as an OTLP JSON `ExportTraceServiceRequest`,
+/// the correlated logs become span events.
+pub(super) fn encode_trace(trace: &TraceExt) -> JsonValue {
+    let mut processes = BTreeMap::<&str, Vec<JsonValue>>::new();
+    for span in &trace.spans {
+        processes
+            .entry(&span.process_id)
+            .or_default()
+            .push(encode_span(span));
+    }
+    let resource_spans = processes
+        .into_iter()
+        .map(|(process_id, spans)| {
+            json!({
+                "resource": resource(process_id, trace.processes.get(process_id)),
+                "scopeSpans": [{ "scope": { "name": SCOPE_NAME }, "spans": spans }],
+            })
+        })
+        .collect::<Vec<_>>();
+    json!({ "resourceSpans": resource_spans })
+}
+
+/// Encode the logs as an OTLP JSON `ExportLogsServiceRequest`.
+pub(super) fn encode_logs(logs: &[Log], processes: &HashMap<String, Process>) -> JsonValue {
+    let mut records = BTreeMap::<&str, Vec<JsonValue>>::new();
+    for log in logs {
+        records
+            .entry(&log.process_id)
+            .or_default()
+            .push(encode_log_record(log));
+    }
+    let resource_logs = records
+        .into_iter()
+        .map(|(process_id, records)| {
+            json!({
+                "resource": resource(process_id, processes.get(process_id)),
+                "scopeLogs": [{ "scope": { "name": SCOPE_NAME }, "logRecords": records }],
+            })
+        })
+        .collect::<Vec<_>>();
+    json!({ "resourceLogs": resource_logs })
+}
+
+fn resource(process_id: &str, process: Option<&Process>) -> JsonValue {
+    let mut attributes = vec![key_value(SERVICE_INSTANCE_ID, &process_id.into())];
+    if let Some(process) = process {
+        attributes.push(key_value(
+            SERVICE_NAME,
+            &process.service_name.as_str().into(),
+        ));
+        attributes.extend(encode_attributes(&process.tags));
+    }
+    json!({ "attributes": attributes })
+}
+
+fn encode_span(span: &Span) -> JsonValue {
+    let mut value = json!({
+        "traceId": format!("{:032x}", span.trace_id),
+        "spanId": format!("{:016x}", span.id),
+        "name": span.name,
+        "kind": SPAN_KIND_INTERNAL,
+        "startTimeUnixNano": encode_time(span.start),
+        "attributes": encode_attributes(&span.tags),
+        "events": span.logs.iter().map(encode_event).collect::<Vec<_>>(),
+    });
+    // Keep the unfinished spans open.
+    if let Some(end) = span.end {
+        value["endTimeUnixNano"] = encode_time(end).into();
+    }
+    if let Some(parent_id) = span.parent_id {
+        value["parentSpanId"] = format!("{parent_id:016x}").into();
+    }
+    if span.logs.iter().any(|log| log.level == Level::ERROR) {
+        value["status"] = json!({ "code": STATUS_CODE_ERROR });
+    }
+    value
+}
+
+fn encode_event(log: &Log) -> JsonValue {
+    let mut attributes = vec![key_value("level", &log.level.as_str().into())];
+    attributes.extend(log_attributes(log));
+    json!({
+        "timeUnixNano": encode_time(log.time),
+        "name": log.message,
+        "attributes": attributes,
+    })
+}
+
+fn encode_log_record(log: &Log) -> JsonValue {
+    let mut value = json!({
+        "timeUnixNano": encode_time(log.time),
+        "severityNumber": severity_number(log.level),
+        "severityText": log.level.as_str(),
+        "body": { "stringValue": log.message },
+        "attributes": log_attributes(log),
+    });
+    if let Some(trace_id) = log.trace_id {
+        value["traceId"] = format!("{trace_id:032x}").into();
+    }
+    if let Some(span_id) = log.span_id {
+        value["spanId"] = format!("{span_id:016x}").into();
+    }
+    value
+}
+
+fn log_attributes(log: &Log) -> Vec<JsonValue> {
+    let mut attributes = vec![key_value("target", &log.target.as_str().into())];
+    if let Some(file) = &log.file {
+        attributes.push(key_value(CODE_FILEPATH, &file.as_str().into()));
+    }
+    if let Some(line) = log.line {
+        attributes.push(key_value(CODE_LINENO, &line.into()));
+    }
+    attributes.extend(encode_attributes(&log.fields));
+    attributes
+}
+
+fn severity_number(level: Level) -> i32 {
+    match level {
+        Level::TRACE => 1,
+        Level::DEBUG => 5,
+        Level::INFO => 9,
+        Level::WARN => 13,
+        Level::ERROR => 17,
+    }
+}
+
+fn encode_time(time: OffsetDateTime) -> String {
+    time.unix_timestamp_nanos().to_string()
+}
+
+/// The attributes sorted by key.
+fn encode_attributes(map: &HashMap<String, JsonValue>) -> Vec<JsonValue> {
+    let sorted = map.iter().collect::<BTreeMap<_, _>>();
+    sorted
+        .into_iter()
+        .map(|(key, value)| key_value(key, value))
+        .collect()
+}
+
+fn key_value(key: &str, value: &JsonValue) -> JsonValue {
+    json!({ "key": key, "value": any_value(value) })
+}
+
+fn any_value(value: &JsonValue) -> JsonValue {
+    match value {
+        JsonValue::String(value) => json!({ "stringValue": value }),
+        JsonValue::Bool(value) => json!({ "boolValue": value }),
+        // The 64-bit integers are strings in OTLP JSON.
+        JsonValue::Number(value) if !value.is_f64() => json!({ "intValue": value.to_string() }),
+        JsonValue::Number(value) => json!({ "doubleValue": value }),
+        JsonValue::Array(values) => {
+            json!({ "arrayValue": { "values": values.iter().map(any_value).collect::<Vec<_>>() } })
+        }
+        JsonValue::Object(map) => json!({
+            "kvlistValue": {
+                "values": map.iter().map(|(key, value)| key_value(key, value)).collect::<Vec<_>>()
+            }
+        }),
+        JsonValue::Null => json!({}),
+    }
+}
+
+/// Decode an OTLP JSON trace or logs request.
+pub(super) fn decode(value: &JsonValue, imported: &mut Imported) -> Result<()> {
+    for resource_spans in array(value, "resourceSpans") {
+        let process_id = decode_resource(resource_spans, imported);
+        for scope_spans in array(resource_spans, "scopeSpans") {
+            for span in array(scope_spans, "spans") {
+                let span = decode_span(span, &process_id, imported)
+                    .with_context(|| format!("Invalid OTLP span: {span}"))?;
+                imported.spans.push(span);
+            }
+        }
+    }
+    for resource_logs in array(value, "resourceLogs") {
+        let process_id = decode_resource(resource_logs, imported);
+        for scope_logs in array(resource_logs, "scopeLogs") {
+            for record in array(scope_logs, "logRecords") {
+                let log = decode_log_record(record, &process_id)
+                    .with_context(|| format!("Invalid OTLP log record: {record}"))?;
+                imported.logs.push(log);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn array<'a>(value: &'a JsonValue, key: &str) -> &'a [JsonValue] {
+    value
+        .get(key)
+        .and_then(JsonValue::as_array)
+        .map(Vec::as_slice)
+        .unwrap_or_default()
+}
+
+/// Register the process of the resource, return its id.
+fn decode_resource(value: &JsonValue, imported: &mut Imported) -> String {
+    let mut attributes = decode_attributes(value.get("resource").unwrap_or(&JsonValue::Null));
+    let id = attributes
+        .remove(SERVICE_INSTANCE_ID)
+        .and_then(|id| id.as_str().map(String::from));
+    let service_name = attributes
+        .remove(SERVICE_NAME)
+        .and_then(|name| name.as_str().map(String::from))
+        .unwrap_or_else(|| "unknown_service".to_string());
+    imported.add_process(id, service_name, attributes)
+}
+
+fn decode_span(value: &JsonValue, process_id: &str, imported: &mut Imported) -> Result<Span> {
+    let trace_id = decode_id(value.get("traceId"))?.context("traceId missing")?;
+    let id = decode_id(value.get("spanId"))?.context("spanId missing")?;
+    for event in array(value, "events") {
+        let mut attributes = decode_attributes(event);
+        let level = attributes
+            .remove("level")
+            .and_then(|level| level.as_str().and_then(|level| Level::from_str(level).ok()))
+            .unwrap_or(Level::INFO);
+        imported.logs.push(decode_log(
+            attributes,
+            process_id,
+            Some(trace_id),
+            Some(id),
+            level,
+            decode_time(event.get("timeUnixNano"))?,
+            event
+                .get("name")
+                .and_then(JsonValue::as_str)
+                .unwrap_or_default()
+                .to_string(),
+        ));
+    }
+    Ok(Span {
+        id,
+        trace_id,
+        parent_id: decode_id(value.get("parentSpanId"))?,
+        process_id: process_id.to_string(),
+        name: value
+            .get("name")
+            .and_then(JsonValue::as_str)
+            .unwrap_or_default()
+            .to_string(),
+        start: decode_time(value.get("startTimeUnixNano"))?,
+        end: match value.get("endTimeUnixNano") {
+            Some(end) => Some(decode_time(Some(end))?),
+            None => None,
+        },
+        tags: decode_attributes(value),
+        logs: Vec::new(),
+    })
+}
+
+fn decode_log_record(value: &JsonValue, process_id: &str) -> Result<Log> {
+    let level = value
+        .get("severityText")
+        .and_then(JsonValue::as_str)
+        .and_then(|level| Level::from_str(level).ok())
+        .or_else(|| {
+            let number = value.get("severityNumber").and_then(JsonValue::as_i64)?;
+            Some(match number {
+                ..=4 => Level::TRACE,
+                5..=8 => Level::DEBUG,
+                9..=12 => Level::INFO,
+                13..=16 => Level::WARN,
+                _ => Level::ERROR,
+            })
+        })
+        .unwrap_or(Level::INFO);
+    // The time is optional, zero if unknown.
+    let time = match decode_time(value.get("timeUnixNano")) {
+        Ok(time) if time != OffsetDateTime::UNIX_EPOCH => time,
+        _ => decode_time(value.get("observedTimeUnixNano"))?,
+    };
+    let message = match value.get("body").map(from_any_value) {
+        Some(JsonValue::String(message)) => message,
+        Some(JsonValue::Null) | None => String::new(),
+        Some(body) => body.to_string(),
+    };
+    Ok(decode_log(
+        decode_attributes(value),
+        process_id,
+        decode_id(value.get("traceId"))?,
+        decode_id(value.get("spanId"))?,
+        level,
+        time,
+        message,
+    ))
+}
+
+fn decode_log(
+    mut attributes: HashMap<String, JsonValue>,
+    process_id: &str,
+    trace_id: Option<u64>,
+    span_id: Option<u64>,
+    level: Level,
+    time: OffsetDateTime,
+    message: String,
+) -> Log {
+    let mut take_str = |key: &str| match attributes.remove(key) {
+        Some(JsonValue::String(value)) => Some(value),
+        Some(value) => Some(value.to_string()),
+        None => None,
+    };
+    let target = take_str("target").unwrap_or_default();
+    let file = take_str(CODE_FILEPATH);
+    let line = take_str(CODE_LINENO).and_then(|line| line.parse().ok());
+    Log {
+        process_id: process_id.to_string(),
+        span_id,
+        trace_id,
+        level,
+        target,
+        file,
+        line,
+        time,
+        message,
+        fields: attributes,
+    }
+}
+
+/// The lowest 64 bits of the hex id, `None` if absent or empty.
+fn decode_id(value: Option<&JsonValue>) -> Result<Option<u64>> {
+    match value.and_then(JsonValue::as_str) {
+        None | Some("") => Ok(None),
+        Some(hex) => {
+            let id = u128::from_str_radix(hex, 16).with_context(|| format!("Invalid id {hex}"))?;
+            Ok(Some(id as u64))
+        }
+    }
+}
+
+fn decode_time(value: Option<&JsonValue>) -> Result<OffsetDateTime> {
+    let nanos = match value {
+        Some(JsonValue::String(nanos)) => nanos.parse::<i128>()?,
+        Some(JsonValue::Number(nanos)) => nanos
+            .as_u64()
+            .ok_or_else(|| anyhow!("Invalid time {nanos}"))?
+            as i128,
+        _ => return Err(anyhow!("Time missing")),
+    };
+    Ok(OffsetDateTime::from_unix_timestamp_nanos(nanos)?)
+}
+
+fn decode_attributes(value: &JsonValue) -> HashMap<String, JsonValue> {
+    array(value, "attributes")
+        .iter()
+        .filter_map(|attribute| {
+            let key = attribute.get("key")?.as_str()?;
+            let value = from_any_value(attribute.get("value")?);
+            Some((key.to_string(), value))
+        })
+        .collect()
+}
+
+fn from_any_value(value: &JsonValue) -> JsonValue {
+    let Some((kind, inner)) = value.as_object().and_then(|map| map.iter().next()) else {
+        return JsonValue::Null;
+    };
+    match (kind.as_str(), inner) {
+        ("intValue", JsonValue::String(int)) => int
+            .parse::<i64>()
+            .map(JsonValue::from)
+            .unwrap_or_else(|_| inner.clone()),
+        ("arrayValue", _) => {
+            JsonValue::Array(array(inner, "values").iter().map(from_any_value).collect())
+        }
+        ("kvlistValue", _) => JsonValue::Object(
+            array(inner, "values")
+                .iter()
+                .filter_map(|pair| {
+                    Some((
+                        pair.get("key")?.as_str()?.to_string(),
+                        from_any_value(pair.get("value")?),
+                    ))
+                })
+                .collect::<Map<_, _>>(),
+        ),
+        _ => inner.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn test_any_value() {
+        for value in [
+            json!("text"),
+            json!(true),
+            json!(42),
+            json!(1.5),
+            json!([1, "a"]),
+            json!({ "a": 1 }),
+        ] {
+            assert_eq!(from_any_value(&any_value(&value)), value);
+        }
+        assert_eq!(any_value(&json!(42)), json!({ "intValue": "42" }));
+    }
+
+    #[test]
+    fn test_decode_id() {
+        let trace_id = json!(format!("{:032x}", u64::MAX - 1));
+        assert_eq!(decode_id(Some(&trace_id)).unwrap(), Some(u64::MAX - 1));
+        assert_eq!(decode_id(Some(&json!(""))).unwrap(), None);
+        assert!(decode_id(Some(&json!("xyz"))).is_err());
+    }
+
+    #[test]
+    fn test_encode_incomplete_span() {
+        // Evicted before closed.
+        let span = Span {
+            tags: HashMap::from([(String::from("incomplete"), JsonValue::Bool(true))]),
+            ..testing::span(2, None, 0, None).named("get").by("api-1")
+        };
+        let value = encode_span(&span);
+        assert!(value.get("endTimeUnixNano").is_none());
+
+        let decoded = decode_span(&value, "api-1", &mut Imported::default()).unwrap();
+        assert_eq!(decoded.end, None);
+        assert_eq!(decoded.tags["incomplete"], json!(true));
+    }
+}
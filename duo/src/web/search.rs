@@ -0,0 +1,128 @@
+use std::collections::BTreeMap;
+
+use datafusion::prelude::*;
+use serde_json::Value as JsonValue;
+
+/// Parse the `tags` of the trace search, either a JSON object as sent by
+/// Jaeger UI, or logfmt such as `http.status_code=500 user="a b"`.
+pub(super) fn parse_tags(raw: &str) -> Result<BTreeMap<String, String>, String> {
+    let raw = raw.trim();
+    if raw.starts_with('{') {
+        let object = serde_json::from_str::<serde_json::Map<String, JsonValue>>(raw)
+            .map_err(|err| format!("Invalid tags: {err}"))?;
+        return Ok(object
+            .into_iter()
+            .map(|(key, value)| match value {
+                JsonValue::String(value) => (key, value),
+                value => (key, value.to_string()),
+            })
+            .collect());
+    }
+
+    let mut tags = BTreeMap::new();
+    let mut chars = raw.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        if chars.peek().is_none() {
+            return Ok(tags);
+        }
+        let mut key = String::new();
+        let mut has_value = false;
+        for c in chars.by_ref() {
+            if c == '=' {
+                has_value = true;
+                break;
+            }
+            key.push(c);
+        }
+        if !has_value || key.is_empty() || key.contains(char::is_whitespace) {
+            return Err(format!("Invalid tags: expect key=value, got `{key}`"));
+        }
+        let value = if chars.next_if_eq(&'"').is_some() {
+            let value = chars.by_ref().take_while(|&c| c != '"').collect();
+            if chars.next_if(|c| !c.is_whitespace()).is_some() {
+                return Err(format!("Invalid tags: unexpected text after `{key}`"));
+            }
+            value
+        } else {
+            chars.by_ref().take_while(|c| !c.is_whitespace()).collect()
+        };
+        tags.insert(key, value);
+    }
+}
+
+/// Match the span having the tag, on the JSON encoded `tags` column.
+pub(super) fn tag_expr(key: &str, value: &str) -> Expr {
+    tag_patterns(key, value)
+        .into_iter()
+        .map(|pattern| col("tags").like(lit(pattern)))
+        .reduce(Expr::or)
+        .expect("at least one pattern")
+}
+
+/// The LIKE patterns of the tag, the value may be stored as a string,
+/// or as a number or boolean.
+fn tag_patterns(key: &str, value: &str) -> Vec<String> {
+    let key = escape_like(&JsonValue::from(key).to_string());
+    let mut patterns = vec![format!(
+        "%{key}:{}%",
+        escape_like(&JsonValue::from(value).to_string())
+    )];
+    if let Ok(value @ (JsonValue::Number(_) | JsonValue::Bool(_))) =
+        serde_json::from_str::<JsonValue>(value)
+    {
+        let value = escape_like(&value.to_string());
+        // Followed by the next tag or the end of the object.
+        patterns.push(format!("%{key}:{value},%"));
+        patterns.push(format!("%{key}:{value}}}%"));
+    }
+    patterns
+}
+
+fn escape_like(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_tags() {
+        let expected = BTreeMap::from([
+            ("http.status_code".to_string(), "500".to_string()),
+            ("user".to_string(), "a b".to_string()),
+        ]);
+        assert_eq!(
+            parse_tags(r#"{"http.status_code": 500, "user": "a b"}"#),
+            Ok(expected.clone())
+        );
+        assert_eq!(
+            parse_tags(r#" http.status_code=500  user="a b" "#),
+            Ok(expected)
+        );
+        assert_eq!(parse_tags(""), Ok(BTreeMap::new()));
+        assert!(parse_tags("user").is_err());
+        assert!(parse_tags(r#"user="a"b"#).is_err());
+    }
+
+    #[test]
+    fn test_tag_patterns() {
+        assert_eq!(
+            tag_patterns("user_id", "42"),
+            vec![
+                r#"%"user\_id":"42"%"#,
+                r#"%"user\_id":42,%"#,
+                r#"%"user\_id":42}%"#
+            ]
+        );
+        assert_eq!(tag_patterns("name", "100%"), vec![r#"%"name":"100\%"%"#]);
+    }
+}
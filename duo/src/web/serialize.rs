@@ -2,6 +2,7 @@ use std::collections::HashMap;
 
 use serde::{ser::SerializeMap, Serialize, Serializer};
 use serde_json::Value;
+use time::OffsetDateTime;
 
 use crate::{Log, Process, Span, TraceExt};
 
@@ -50,6 +51,10 @@ impl<'a> Serialize for JaegerField<'a> {
                 map.serialize_entry("type", "bool")?;
                 map.serialize_entry("value", v)?
             }
+            Value::Number(v) if v.is_f64() => {
+                map.serialize_entry("type", "float64")?;
+                map.serialize_entry("value", v)?
+            }
             Value::Number(v) => {
                 map.serialize_entry("type", "int64")?;
                 map.serialize_entry("value", v)?
@@ -88,14 +93,16 @@ impl<'a> Serialize for SpanExt<'a> {
         map.serialize_entry("references", &references)?;
 
         map.serialize_entry("spanID", &span.id.to_string())?;
-        if span.is_intact() {
+        if span.is_intact() || span.is_running() {
             map.serialize_entry("operationName", &span.name)?;
         } else {
             // The span isn't intact, add * to the operationName for indication.
             map.serialize_entry("operationName", &format!("{}*", span.name))?;
         }
         map.serialize_entry("startTime", &span.start_as_micros())?;
-        map.serialize_entry("duration", &span.duration().whole_microseconds())?;
+        // The running spans show the duration so far.
+        let duration = span.duration_until(OffsetDateTime::now_utc());
+        map.serialize_entry("duration", &duration.whole_microseconds())?;
         let tags: Vec<_> = span.tags.iter().map(JaegerField).collect();
         map.serialize_entry("tags", &tags)?;
         map.serialize_entry("logs", &span.logs.iter().map(JaegerLog).collect::<Vec<_>>())?;
@@ -1,110 +1,313 @@
+use crate::cluster;
+use crate::dependency::{self, DependencyLink, SpanNode};
+use crate::flamegraph::{self, CallNode};
+use crate::partition::PartitionQuery;
 use crate::query::QueryEngine;
-use crate::{Log, MemoryStore, Span, TraceExt};
+use crate::tenant::Tenant;
+use crate::{arrow::serialize_record_batches, Log, Span, TraceExt};
+use datafusion::functions_aggregate::sum::sum;
 use datafusion::prelude::*;
-use parking_lot::RwLock;
 use serde::Deserialize;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::sync::Arc;
+use time::OffsetDateTime;
+use tracing::warn;
 
 use super::trace::QueryParameters;
+use super::{logs, search};
 
 const DEFAUT_TRACE_LIMIT: usize = 20;
 
+pub(super) fn service_process_ids(tenant: &Tenant, service: &str) -> HashSet<String> {
+    tenant
+        .memory_store
+        .read()
+        .process_ids(service)
+        .into_iter()
+        .collect()
+}
+
+/// Match the spans or logs of the service exactly,
+/// the service `api` never matches the processes of `api-gateway`.
+pub(super) fn service_expr(process_ids: &HashSet<String>) -> Expr {
+    if process_ids.is_empty() {
+        return lit(false);
+    }
+    col("process_id").in_list(
+        process_ids.iter().map(|id| lit(id.as_str())).collect(),
+        false,
+    )
+}
+
+/// Search the traces by their root span of the service, the latest first.
+/// Only the spans and logs of the found traces are fetched.
 pub(super) async fn filter_traces(
-    memory_store: Arc<RwLock<MemoryStore>>,
+    tenant: &Tenant,
     p: QueryParameters,
+    tags: BTreeMap<String, String>,
 ) -> Vec<TraceExt> {
-    let process_prefix = p.service;
     let limit = p.limit.unwrap_or(DEFAUT_TRACE_LIMIT);
-    // <trace_id, spans>
-    let mut traces = HashMap::<u64, Vec<Span>>::new();
-
-    let expr = col("process_id").like(lit(format!("{process_prefix}%")));
+    let process_ids = service_process_ids(tenant, &p.service);
+    let query_engine = QueryEngine::new(Arc::clone(&tenant.memory_store));
+    let without_errors = tags.get("error").is_some_and(|value| value == "false");
+    let matched_ids =
+        search_trace_ids(tenant, &query_engine, &service_expr(&process_ids), &p, tags).await;
+    if matched_ids.as_ref().is_some_and(HashSet::is_empty) {
+        return Vec::new();
+    }
 
-    let processes = { memory_store.read().processes() };
-    let query_engine = QueryEngine::new(memory_store);
-    let total_spans = query_engine
-        .query_span(expr.clone())
+    let mut expr = service_expr(&process_ids)
+        .and(col("parent_id").is_null())
+        .and(time_expr("start", p.start, p.end));
+    if let Some(operation) = &p.operation {
+        expr = expr.and(col("name").eq(lit(operation.as_str())));
+    }
+    let duration = col("end") - col("start");
+    if let Some(min) = p.min_duration {
+        expr = expr.and(duration.clone().gt_eq(lit(min.whole_microseconds() as i64)));
+    }
+    if let Some(max) = p.max_duration {
+        expr = expr.and(duration.lt_eq(lit(max.whole_microseconds() as i64)));
+    }
+    if let Some(trace_ids) = &matched_ids {
+        expr = expr.and(trace_ids_expr(trace_ids));
+    }
+    if without_errors {
+        let error_ids = error_trace_ids(&query_engine, &p).await;
+        if !error_ids.is_empty() {
+            expr = expr.and(!trace_ids_expr(&error_ids));
+        }
+    }
+    let mut roots = query_engine
+        .query_span(expr)
         .range(p.start, p.end)
+        .sort(vec![
+            col("start").sort(false, false),
+            col("trace_id").sort(true, false),
+        ])
+        .limit(0, Some(limit))
         .collect::<Span>()
         .await
-        .unwrap_or_default();
+        .unwrap_or_else(|err| {
+            warn!("Search root spans failed: {err}");
+            Vec::new()
+        });
 
-    for span in total_spans {
-        if traces.contains_key(&span.trace_id) {
-            traces.entry(span.trace_id).or_default().push(span);
-            continue;
-        }
-
-        // Filter the root span, the child spans will be added in above.
-        if let Some(span_name) = p.operation.as_ref() {
-            if &span.name != span_name {
-                continue;
-            }
-        }
+    // The running traces are matched by their unfinished root spans,
+    // which have no duration yet.
+    if p.min_duration.is_none() {
+        let ongoing_roots = tenant.aggregator.read().ongoing_roots(|span| {
+            process_ids.contains(&span.process_id)
+                && p.operation.as_ref().map_or(true, |name| &span.name == name)
+                && matched_ids
+                    .as_ref()
+                    .map_or(true, |ids| ids.contains(&span.trace_id))
+        });
+        roots.extend(ongoing_roots.into_iter().filter(|span| {
+            p.start.map_or(true, |start| span.start >= start)
+                && p.end.map_or(true, |end| span.start <= end)
+        }));
+    }
+    roots.sort_by(|a, b| b.start.cmp(&a.start).then(a.trace_id.cmp(&b.trace_id)));
+    let mut seen = HashSet::new();
+    roots.retain(|span| seen.insert(span.trace_id));
+    roots.truncate(limit);
+    let Some(earliest) = roots.iter().map(|span| span.start).min() else {
+        return Vec::new();
+    };
 
-        if span.parent_id.is_some() {
-            continue;
+    let trace_ids = roots.iter().map(|span| span.trace_id).collect::<Vec<_>>();
+    let mut traces = HashMap::<u64, Vec<Span>>::new();
+    let spans = query_engine
+        .query_span(trace_ids_expr(&trace_ids))
+        .range(Some(earliest), None)
+        .collect::<Span>()
+        .await
+        .unwrap_or_else(|err| {
+            warn!("Query trace spans failed: {err}");
+            Vec::new()
+        });
+    for span in spans {
+        traces.entry(span.trace_id).or_default().push(span);
+    }
+    let mut trace_logs = HashMap::<u64, Vec<Log>>::new();
+    let logs = query_engine
+        .query_log(trace_ids_expr(&trace_ids))
+        .range(Some(earliest), None)
+        .sort(vec![col("time").sort(true, false)])
+        .collect::<Log>()
+        .await
+        .unwrap_or_else(|err| {
+            warn!("Query trace logs failed: {err}");
+            Vec::new()
+        });
+    for log in logs {
+        if let Some(trace_id) = log.trace_id {
+            trace_logs.entry(trace_id).or_default().push(log);
         }
+    }
 
-        match (p.start, p.end) {
-            (Some(start), None) if span.start < start => continue,
-            (None, Some(end)) if span.start > end => continue,
-            (Some(start), Some(end)) if span.start < start || span.start > end => continue,
-            _ => {}
-        }
+    let processes = { tenant.memory_store.read().processes() };
+    let aggregator = tenant.aggregator.read();
+    trace_ids
+        .into_iter()
+        .map(|trace_id| {
+            let mut spans = traces.remove(&trace_id).unwrap_or_default();
+            // Merge the unfinished spans, including the ongoing root.
+            merge_ongoing_spans(&mut spans, aggregator.ongoing_spans(trace_id));
+            spans.sort_by_key(|span| (span.start, span.id));
+            let logs = trace_logs.remove(&trace_id).unwrap_or_default();
+            for span in &mut spans {
+                span.correlate_span_logs(&logs);
+            }
+            TraceExt {
+                trace_id,
+                processes: processes.clone(),
+                spans,
+            }
+        })
+        .collect()
+}
 
-        let duration = span.duration();
-        match (p.min_duration, p.max_duration) {
-            (Some(min), None) if duration < min => continue,
-            (None, Some(max)) if duration > max => continue,
-            (Some(min), Some(max)) if duration < min || duration > max => continue,
-            _ => {}
-        }
+fn trace_ids_expr<'a>(trace_ids: impl IntoIterator<Item = &'a u64>) -> Expr {
+    col("trace_id").in_list(trace_ids.into_iter().map(|id| lit(*id)).collect(), false)
+}
 
-        traces.entry(span.trace_id).or_default().push(span);
+/// The ids of the traces with an error log, from any service of the trace.
+async fn error_trace_ids(query_engine: &QueryEngine, p: &QueryParameters) -> HashSet<u64> {
+    #[derive(Deserialize)]
+    struct TraceId {
+        trace_id: Option<u64>,
     }
 
-    let trace_ids = traces.keys().collect::<Vec<_>>();
-
-    let expr = col("trace_id").in_list(trace_ids.into_iter().map(|id| lit(*id)).collect(), false);
-    let trace_logs = query_engine
-        .query_log(expr.clone())
+    let expr = col("trace_id")
+        .is_not_null()
+        .and(col("level").eq(lit("ERROR")))
+        .and(time_expr("time", p.start, p.end));
+    query_engine
+        .query_log(expr)
         .range(p.start, p.end)
-        .collect::<Log>()
+        .aggregate(vec![col("trace_id")], vec![])
+        .collect::<TraceId>()
         .await
-        .unwrap_or_default();
-
-    traces
-        .into_iter()
-        .take(limit)
-        .map(|(trace_id, spans)| TraceExt {
-            trace_id,
-            processes: processes.clone(),
-            spans: spans
-                .iter()
-                .map(|span| {
-                    let mut span = span.clone();
-                    span.correlate_span_logs(&trace_logs);
-                    span
-                })
-                .collect(),
+        .unwrap_or_else(|err| {
+            warn!("Search error logs failed: {err}");
+            Vec::new()
         })
+        .into_iter()
+        .filter_map(|id| id.trace_id)
         .collect()
 }
 
-pub(super) async fn get_trace_by_id(
-    memory_store: Arc<RwLock<MemoryStore>>,
-    trace_id: u64,
-) -> Option<TraceExt> {
+/// The ids of the traces matching the span tags and the log filters,
+/// `None` if neither is given. The tags must all be on one span of the service,
+/// the logs may be from any service of the trace.
+async fn search_trace_ids(
+    tenant: &Tenant,
+    query_engine: &QueryEngine,
+    service_expr: &Expr,
+    p: &QueryParameters,
+    mut tags: BTreeMap<String, String>,
+) -> Option<HashSet<u64>> {
+    #[derive(Deserialize)]
+    struct TraceId {
+        trace_id: Option<u64>,
+    }
+
+    // Jaeger UI searches the failed traces by `error=true`,
+    // the error tag is derived from the error logs. The traces
+    // without errors (`error=false`) are filtered by the caller.
+    let errors_only = tags.remove("error").is_some_and(|value| value == "true");
+    let log_level = p
+        .log_level
+        .as_ref()
+        .map(|level| level.to_uppercase())
+        .or_else(|| errors_only.then(|| "ERROR".to_string()));
+
+    let mut trace_ids = None::<HashSet<u64>>;
+    let mut intersect = |ids: Vec<TraceId>| {
+        let ids = ids.into_iter().filter_map(|id| id.trace_id);
+        trace_ids = Some(match trace_ids.take() {
+            Some(matched) => ids.filter(|id| matched.contains(id)).collect(),
+            None => ids.collect(),
+        });
+    };
+
+    if !tags.is_empty() {
+        let expr = tags.iter().fold(
+            service_expr.clone().and(time_expr("start", p.start, p.end)),
+            |expr, (key, value)| expr.and(search::tag_expr(key, value)),
+        );
+        let ids = query_engine
+            .query_span(expr)
+            .range(p.start, p.end)
+            .aggregate(vec![col("trace_id")], vec![])
+            .collect::<TraceId>()
+            .await
+            .unwrap_or_else(|err| {
+                warn!("Search span tags failed: {err}");
+                Vec::new()
+            });
+        intersect(ids);
+    }
+
+    let mut log_exprs = Vec::new();
+    if let Some(level) = log_level {
+        log_exprs.push(col("level").eq(lit(level)));
+    }
+    if let Some(text) = p.log_text.as_ref().filter(|text| !text.is_empty()) {
+        log_exprs.push(col("message").ilike(lit(format!("%{text}%"))));
+    }
+    if let Some(sql_expr) = p.log_expr.as_ref().filter(|expr| !expr.is_empty()) {
+        log_exprs.push(logs::parse_expr(&tenant.id, sql_expr));
+    }
+    if !log_exprs.is_empty() {
+        let expr = log_exprs.into_iter().fold(
+            col("trace_id")
+                .is_not_null()
+                .and(time_expr("time", p.start, p.end)),
+            Expr::and,
+        );
+        let ids = query_engine
+            .query_log(expr)
+            .range(p.start, p.end)
+            .aggregate(vec![col("trace_id")], vec![])
+            .collect::<TraceId>()
+            .await
+            .unwrap_or_else(|err| {
+                warn!("Search logs failed: {err}");
+                Vec::new()
+            });
+        intersect(ids);
+    }
+    trace_ids
+}
+
+/// Filter the time column to the range, the range of the query
+/// only prunes the partitions.
+fn time_expr(column: &str, start: Option<OffsetDateTime>, end: Option<OffsetDateTime>) -> Expr {
+    let micros = |time: OffsetDateTime| lit((time.unix_timestamp_nanos() / 1000) as i64);
+    match (start, end) {
+        (Some(start), Some(end)) => col(column).between(micros(start), micros(end)),
+        (Some(start), None) => col(column).gt_eq(micros(start)),
+        (None, Some(end)) => col(column).lt_eq(micros(end)),
+        (None, None) => lit(true),
+    }
+}
+
+pub(super) async fn get_trace_by_id(tenant: &Tenant, trace_id: u64) -> Option<TraceExt> {
     let expr = col("trace_id").eq(lit(trace_id));
-    let processes = { memory_store.read().processes() };
-    let query_engine = QueryEngine::new(memory_store);
-    let trace_spans = query_engine
+    let processes = { tenant.memory_store.read().processes() };
+    let query_engine = QueryEngine::new(Arc::clone(&tenant.memory_store));
+    let mut trace_spans = query_engine
         .query_span(expr.clone())
         .collect::<Span>()
         .await
         .unwrap_or_default();
+    merge_ongoing_spans(
+        &mut trace_spans,
+        tenant.aggregator.read().ongoing_spans(trace_id),
+    );
 
     if trace_spans.is_empty() {
         None
@@ -129,17 +332,75 @@ pub(super) async fn get_trace_by_id(
     }
 }
 
-pub(super) async fn aggregate_span_names(
-    memory_store: Arc<RwLock<MemoryStore>>,
+/// Merge the spans of the operation started within the time range, and their
+/// descendants, into a call tree. At most `limit` spans of the operation,
+/// the latest first.
+pub(super) async fn aggregate_call_tree(
+    tenant: &Tenant,
     service: &str,
-) -> HashSet<String> {
+    operation: &str,
+    start: OffsetDateTime,
+    end: OffsetDateTime,
+    limit: usize,
+) -> Option<CallNode> {
+    let process_ids = service_process_ids(tenant, service);
+    let query_engine = QueryEngine::new(Arc::clone(&tenant.memory_store));
+    let expr = service_expr(&process_ids)
+        .and(col("name").eq(lit(operation)))
+        .and(time_expr("start", Some(start), Some(end)));
+    let matched = query_engine
+        .query_span(expr)
+        .range(Some(start), Some(end))
+        .sort(vec![
+            col("start").sort(false, false),
+            col("trace_id").sort(true, false),
+        ])
+        .limit(0, Some(limit))
+        .collect::<Span>()
+        .await
+        .unwrap_or_else(|err| {
+            warn!("Query operation spans failed: {err}");
+            Vec::new()
+        });
+    let earliest = matched.iter().map(|span| span.start).min()?;
+
+    let trace_ids = matched
+        .iter()
+        .map(|span| span.trace_id)
+        .collect::<HashSet<_>>();
+    let spans = query_engine
+        .query_span(trace_ids_expr(&trace_ids))
+        .range(Some(earliest), None)
+        .collect::<Span>()
+        .await
+        .unwrap_or_else(|err| {
+            warn!("Query trace spans failed: {err}");
+            Vec::new()
+        });
+    let roots = matched
+        .iter()
+        .map(|span| (span.trace_id, span.id))
+        .collect::<HashSet<_>>();
+    let processes = { tenant.memory_store.read().processes() };
+    flamegraph::merge_call_tree(&spans, &processes, |span| {
+        roots.contains(&(span.trace_id, span.id))
+    })
+}
+
+/// Append the unfinished spans which are not in the query result.
+fn merge_ongoing_spans(spans: &mut Vec<Span>, ongoing: Vec<Span>) {
+    let ids = spans.iter().map(|span| span.id).collect::<HashSet<_>>();
+    spans.extend(ongoing.into_iter().filter(|span| !ids.contains(&span.id)));
+}
+
+pub(super) async fn aggregate_span_names(tenant: &Tenant, service: &str) -> HashSet<String> {
     #[derive(Deserialize)]
     struct SpanName {
         name: String,
     }
 
-    let query_engine = QueryEngine::new(memory_store);
-    let expr = col("process_id").like(lit(format!("{service}%")));
+    let query_engine = QueryEngine::new(Arc::clone(&tenant.memory_store));
+    let expr = service_expr(&service_process_ids(tenant, service));
     let batches = query_engine
         .aggregate_span_names(expr)
         .collect::<SpanName>()
@@ -151,3 +412,69 @@ pub(super) async fn aggregate_span_names(
         .map(|item| item.name)
         .collect::<HashSet<_>>()
 }
+
+/// The service links of the spans started within the time range. The links are
+/// read from the partitions pre-aggregated, and computed from the data not
+/// persisted yet, together with the written spans tracked by every node.
+/// With `on_demand`, all of them are computed from the spans.
+pub(super) async fn dependencies(
+    tenant: &Tenant,
+    start: OffsetDateTime,
+    end: OffsetDateTime,
+    on_demand: bool,
+) -> Vec<DependencyLink> {
+    let range = (
+        (start.unix_timestamp_nanos() / 1000) as i64,
+        (end.unix_timestamp_nanos() / 1000) as i64,
+    );
+    let expr = col("start").between(lit(range.0), lit(range.1));
+    let query_engine = QueryEngine::new(Arc::clone(&tenant.memory_store));
+    let mut query = query_engine.query_span(expr).range(Some(start), Some(end));
+    if !on_demand {
+        query = query.memory_only();
+    }
+    let spans = query.collect::<SpanNode>().await.unwrap_or_else(|err| {
+        warn!("Query span nodes failed: {err}");
+        Vec::new()
+    });
+    let processes = { tenant.memory_store.read().processes() };
+    let links = dependency::service_links(dependency::process_calls(&spans), &processes);
+    if on_demand || crate::is_memory_mode() {
+        return links;
+    }
+
+    // The unwritten spans linked to the written ones, not pre-aggregated yet.
+    let tracked_links = dependency::memory_links(tenant, range.0, range.1).unwrap_or_else(|err| {
+        warn!("Resolve memory links failed: {err}");
+        Vec::new()
+    });
+    let peer_links = cluster::peer_links(&tenant.id, range.0, range.1).await;
+    let partition_links = partition_dependencies(&tenant.id, start, end)
+        .await
+        .unwrap_or_else(|err| {
+            warn!("Query dependencies failed: {err}");
+            Vec::new()
+        });
+    dependency::merge_links(
+        links
+            .into_iter()
+            .chain(tracked_links)
+            .chain(peer_links)
+            .chain(partition_links),
+    )
+}
+
+async fn partition_dependencies(
+    tenant: &str,
+    start: OffsetDateTime,
+    end: OffsetDateTime,
+) -> anyhow::Result<Vec<DependencyLink>> {
+    let df = PartitionQuery::new(tenant, start, end)?
+        .df("dependency")
+        .await?
+        .aggregate(
+            vec![col("parent"), col("child")],
+            vec![sum(col("call_count")).alias("callCount")],
+        )?;
+    serialize_record_batches(&df.collect().await?)
+}
@@ -0,0 +1,314 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+use axum::extract::Query;
+use axum::response::IntoResponse;
+use axum::Json;
+use datafusion::functions_aggregate::count::count;
+use datafusion::functions_aggregate::sum::sum;
+use datafusion::prelude::*;
+use serde::{Deserialize, Serialize};
+use time::{Duration, OffsetDateTime};
+use tracing::warn;
+
+use crate::query::QueryEngine;
+use crate::tenant::Tenant;
+use crate::utils::now_micros;
+use crate::Process;
+
+use super::{deser, CurrentTenant};
+
+/// The default window of the activity counts.
+const DEFAULT_WINDOW: Duration = Duration::hours(1);
+
+#[derive(Debug, Deserialize)]
+pub(super) struct QueryParameters {
+    #[serde(default, deserialize_with = "deser::option_miscrosecond")]
+    start: Option<OffsetDateTime>,
+    #[serde(default, deserialize_with = "deser::option_miscrosecond")]
+    end: Option<OffsetDateTime>,
+    /// List every process ever registered, not only the ones
+    /// seen within the window.
+    #[serde(default)]
+    all: bool,
+}
+
+impl QueryParameters {
+    fn window(&self) -> (OffsetDateTime, OffsetDateTime) {
+        let end = self.end.unwrap_or_else(OffsetDateTime::now_utc);
+        let start = self.start.unwrap_or(end - DEFAULT_WINDOW);
+        (start, end)
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+struct Activity {
+    spans: i64,
+    logs: i64,
+    errors: i64,
+}
+
+impl Activity {
+    fn add(&mut self, other: Activity) {
+        self.spans += other.spans;
+        self.logs += other.logs;
+        self.errors += other.errors;
+    }
+}
+
+#[derive(Serialize)]
+struct ProcessStats {
+    #[serde(flatten)]
+    process: Process,
+    live: bool,
+    #[serde(flatten)]
+    activity: Activity,
+}
+
+#[derive(Serialize)]
+struct ServiceStats {
+    name: String,
+    #[serde(rename = "firstSeen")]
+    first_seen: Option<i64>,
+    #[serde(rename = "lastSeen")]
+    last_seen: Option<i64>,
+    live: bool,
+    #[serde(flatten)]
+    activity: Activity,
+    processes: Vec<ProcessStats>,
+}
+
+/// The services with their processes and the activity within the window.
+#[tracing::instrument]
+pub(super) async fn services(
+    Query(p): Query<QueryParameters>,
+    CurrentTenant(tenant): CurrentTenant,
+) -> impl IntoResponse {
+    Json(group_by_service(process_stats(&tenant, &p).await))
+}
+
+/// Sum up the processes of each service, ordered by the service name.
+fn group_by_service(processes: Vec<ProcessStats>) -> Vec<ServiceStats> {
+    let mut services = BTreeMap::<String, Vec<ProcessStats>>::new();
+    for process in processes {
+        services
+            .entry(process.process.service_name.clone())
+            .or_default()
+            .push(process);
+    }
+
+    services
+        .into_iter()
+        .map(|(name, processes)| {
+            let mut activity = Activity::default();
+            processes.iter().for_each(|p| activity.add(p.activity));
+            ServiceStats {
+                name,
+                first_seen: processes.iter().filter_map(|p| p.process.start_time).min(),
+                last_seen: processes.iter().filter_map(|p| p.process.last_seen).max(),
+                live: processes.iter().any(|p| p.live),
+                activity,
+                processes,
+            }
+        })
+        .collect()
+}
+
+/// The processes and the activity within the window.
+#[tracing::instrument]
+pub(super) async fn processes(
+    Query(p): Query<QueryParameters>,
+    CurrentTenant(tenant): CurrentTenant,
+) -> impl IntoResponse {
+    Json(process_stats(&tenant, &p).await)
+}
+
+/// The processes seen or active within the window, unless all asked.
+/// The latest started processes come first.
+async fn process_stats(tenant: &Tenant, p: &QueryParameters) -> Vec<ProcessStats> {
+    let processes = { tenant.memory_store.read().processes() };
+    let mut activities = count_activities(tenant, p).await;
+
+    let (start, end) = p.window();
+    let window = (
+        (start.unix_timestamp_nanos() / 1000) as i64,
+        (end.unix_timestamp_nanos() / 1000) as i64,
+    );
+    let now = now_micros();
+    let mut stats = processes
+        .into_values()
+        .filter_map(|process| {
+            let activity = activities.remove(&process.id);
+            (p.all || activity.is_some() || is_seen(&process, window)).then(|| ProcessStats {
+                live: process.is_live(now),
+                activity: activity.unwrap_or_default(),
+                process,
+            })
+        })
+        .collect::<Vec<_>>();
+    stats.sort_by(|a, b| {
+        b.process
+            .start_time
+            .cmp(&a.process.start_time)
+            .then_with(|| a.process.id.cmp(&b.process.id))
+    });
+    stats
+}
+
+/// Whether the process ran within the window (in microseconds), the ones
+/// registered before the heartbeats are only known by their activity.
+fn is_seen(process: &Process, (start, end): (i64, i64)) -> bool {
+    process
+        .last_seen
+        .is_some_and(|last_seen| last_seen >= start)
+        && !process
+            .start_time
+            .is_some_and(|start_time| start_time > end)
+}
+
+/// Count the spans, logs and error logs of each process within the window,
+/// from the memory, the peers and the partitions.
+async fn count_activities(tenant: &Tenant, p: &QueryParameters) -> HashMap<String, Activity> {
+    #[derive(Deserialize)]
+    struct SpanCount {
+        process_id: String,
+        spans: i64,
+    }
+
+    #[derive(Deserialize)]
+    struct LogCount {
+        process_id: String,
+        logs: i64,
+        errors: Option<i64>,
+    }
+
+    let (start, end) = p.window();
+    // The range only prunes the partitions, the memory data
+    // is filtered by the time column.
+    let in_window = |column: &str| {
+        col(column).between(
+            lit((start.unix_timestamp_nanos() / 1000) as i64),
+            lit((end.unix_timestamp_nanos() / 1000) as i64),
+        )
+    };
+
+    let query_engine = QueryEngine::new(Arc::clone(&tenant.memory_store));
+    let span_counts = query_engine
+        .query_span(in_window("start"))
+        .range(Some(start), Some(end))
+        .aggregate(
+            vec![col("process_id")],
+            vec![count(col("id")).alias("spans")],
+        )
+        .collect::<SpanCount>()
+        .await
+        .unwrap_or_else(|err| {
+            warn!("Count spans failed: {err}");
+            Vec::new()
+        });
+    let is_error = when(col("level").eq(lit("ERROR")), lit(1i64))
+        .otherwise(lit(0i64))
+        .expect("valid case expr");
+    let log_counts = query_engine
+        .query_log(in_window("time"))
+        .range(Some(start), Some(end))
+        .aggregate(
+            vec![col("process_id")],
+            vec![
+                count(col("time")).alias("logs"),
+                sum(is_error).alias("errors"),
+            ],
+        )
+        .collect::<LogCount>()
+        .await
+        .unwrap_or_else(|err| {
+            warn!("Count logs failed: {err}");
+            Vec::new()
+        });
+
+    let mut activities = HashMap::<String, Activity>::new();
+    for count in span_counts {
+        activities.entry(count.process_id).or_default().spans += count.spans;
+    }
+    for count in log_counts {
+        let activity = activities.entry(count.process_id).or_default();
+        activity.logs += count.logs;
+        activity.errors += count.errors.unwrap_or_default();
+    }
+    activities
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::process;
+
+    fn stats(id: &str, service: &str, times: (i64, i64), live: bool) -> ProcessStats {
+        ProcessStats {
+            process: Process {
+                start_time: Some(times.0),
+                last_seen: Some(times.1),
+                ..process(id, service)
+            },
+            live,
+            activity: Activity {
+                spans: 10,
+                logs: 5,
+                errors: 1,
+            },
+        }
+    }
+
+    #[test]
+    fn test_is_seen() {
+        let window = (300, 500);
+        assert!(is_seen(
+            &stats("a", "api", (100, 400), true).process,
+            window
+        ));
+        assert!(is_seen(
+            &stats("a", "api", (400, 600), true).process,
+            window
+        ));
+        // Stopped before or started after the window.
+        assert!(!is_seen(
+            &stats("a", "api", (100, 200), false).process,
+            window
+        ));
+        assert!(!is_seen(
+            &stats("a", "api", (600, 700), true).process,
+            window
+        ));
+
+        let mut legacy = stats("a", "api", (100, 400), false).process;
+        legacy.last_seen = None;
+        assert!(!is_seen(&legacy, window));
+    }
+
+    #[test]
+    fn test_group_by_service() {
+        let services = group_by_service(vec![
+            stats("web-2", "web", (300, 400), false),
+            stats("api-1", "api", (200, 500), true),
+            stats("web-1", "web", (100, 600), true),
+        ]);
+
+        assert_eq!(
+            services.iter().map(|s| s.name.as_str()).collect::<Vec<_>>(),
+            vec!["api", "web"]
+        );
+        let web = &services[1];
+        assert_eq!((web.first_seen, web.last_seen), (Some(100), Some(600)));
+        assert!(web.live);
+        assert_eq!(
+            (web.activity.spans, web.activity.logs, web.activity.errors),
+            (20, 10, 2)
+        );
+        assert_eq!(web.processes.len(), 2);
+
+        let json = serde_json::to_value(web).unwrap();
+        assert_eq!(json["spans"], 20);
+        assert_eq!(json["processes"][0]["id"], "web-2");
+        assert_eq!(json["processes"][0]["errors"], 1);
+    }
+}
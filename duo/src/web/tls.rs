@@ -0,0 +1,63 @@
+use std::{io::BufReader, sync::Arc};
+
+use anyhow::{Context, Result};
+use rustls::{
+    crypto::ring,
+    pki_types::{CertificateDer, PrivateKeyDer},
+    server::WebPkiClientVerifier,
+    RootCertStore, ServerConfig,
+};
+
+use crate::config::TlsConfig;
+
+/// Build the rustls config of the web server, verify the client
+/// certificates if `client_ca` configured.
+pub(super) fn server_config(tls: &TlsConfig) -> Result<ServerConfig> {
+    let (cert, key, client_ca) = tls.read()?;
+    let certs = parse_certs(&cert).context("Invalid certificate")?;
+    let key: PrivateKeyDer<'static> =
+        rustls_pemfile::private_key(&mut BufReader::new(&*key))?.context("No private key found")?;
+
+    let provider = Arc::new(ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(Arc::clone(&provider))
+        .with_safe_default_protocol_versions()?;
+    let builder = match client_ca {
+        Some(client_ca) => {
+            let mut roots = RootCertStore::empty();
+            for cert in parse_certs(&client_ca).context("Invalid client CA")? {
+                roots.add(cert)?;
+            }
+            let verifier =
+                WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider).build()?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let mut config = builder.with_single_cert(certs, key)?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(config)
+}
+
+fn parse_certs(pem: &[u8]) -> Result<Vec<CertificateDer<'static>>> {
+    Ok(rustls_pemfile::certs(&mut BufReader::new(pem)).collect::<Result<Vec<_>, _>>()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_server_config_without_key() {
+        // Readable files without any PEM section.
+        let file = concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml");
+        let tls = TlsConfig {
+            cert: file.to_string(),
+            key: file.to_string(),
+            client_ca: None,
+        };
+        assert!(parse_certs(b"not a pem").unwrap().is_empty());
+        let err = server_config(&tls).unwrap_err();
+        assert_eq!(err.to_string(), "No private key found");
+    }
+}
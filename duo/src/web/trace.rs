@@ -1,18 +1,23 @@
-use std::sync::Arc;
+use std::collections::BTreeMap;
 
-use axum::extract::{Extension, Path, Query};
+use axum::extract::{Path, Query};
 use axum::http::StatusCode;
-use axum::response::IntoResponse;
+use axum::response::{IntoResponse, Response};
 use axum::Json;
-use parking_lot::RwLock;
 use serde::Deserialize;
 use time::{Duration, OffsetDateTime};
 
-use crate::{MemoryStore, TraceExt};
+use crate::{analysis, diff, flamegraph, TraceExt};
 
-use super::deser;
-use super::services::{aggregate_span_names, filter_traces, get_trace_by_id};
-use super::JaegerData;
+use super::services::{
+    aggregate_call_tree, aggregate_span_names, dependencies, filter_traces, get_trace_by_id,
+};
+use super::{deser, search};
+use super::{CurrentTenant, JaegerData};
+
+const DEFAULT_LOOKBACK: Duration = Duration::days(1);
+const DEFAULT_FLAME_GRAPH_WINDOW: Duration = Duration::hours(1);
+const DEFAULT_FLAME_GRAPH_LIMIT: usize = 1000;
 
 #[derive(Debug, Deserialize)]
 pub(super) struct QueryParameters {
@@ -30,43 +35,92 @@ pub(super) struct QueryParameters {
     #[serde(rename = "minDuration")]
     #[serde(default, deserialize_with = "deser::option_duration")]
     pub min_duration: Option<Duration>,
+    /// The span tags, as JSON or logfmt.
+    pub tags: Option<String>,
+    /// The traces having a log of the level.
+    #[serde(rename = "logLevel")]
+    pub log_level: Option<String>,
+    /// The traces having a log with the text in its message.
+    #[serde(rename = "logText")]
+    pub log_text: Option<String>,
+    /// The traces having a log matching the SQL expression.
+    #[serde(rename = "logExpr")]
+    pub log_expr: Option<String>,
+}
+
+/// The parameters of Jaeger's dependencies API, in milliseconds.
+#[derive(Debug, Deserialize)]
+pub(super) struct DependencyParameters {
+    #[serde(rename = "endTs")]
+    #[serde(default, deserialize_with = "deser::option_ignore_error")]
+    end_ts: Option<i64>,
+    #[serde(default, deserialize_with = "deser::option_ignore_error")]
+    lookback: Option<i64>,
+    #[serde(rename = "onDemand", default)]
+    on_demand: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct FlameGraphParameters {
+    service: String,
+    operation: String,
+    #[serde(default, deserialize_with = "deser::option_miscrosecond")]
+    start: Option<OffsetDateTime>,
+    #[serde(default, deserialize_with = "deser::option_miscrosecond")]
+    end: Option<OffsetDateTime>,
+    /// The maximum number of the operation spans to merge.
+    #[serde(default, deserialize_with = "deser::option_ignore_error")]
+    limit: Option<usize>,
+    /// `tree` for the JSON call tree, or `folded` for the folded stacks.
+    format: Option<String>,
 }
 
 #[tracing::instrument]
 pub(super) async fn list(
     Query(parameters): Query<QueryParameters>,
-    Extension(memory_store): Extension<Arc<RwLock<MemoryStore>>>,
+    CurrentTenant(tenant): CurrentTenant,
 ) -> impl IntoResponse {
-    Json(JaegerData(filter_traces(memory_store, parameters).await))
+    let tags = match parameters.tags.as_deref().map(search::parse_tags) {
+        Some(Ok(tags)) => tags,
+        Some(Err(err)) => return (StatusCode::BAD_REQUEST, err).into_response(),
+        None => BTreeMap::new(),
+    };
+    if let Some(value) = tags
+        .get("error")
+        .filter(|value| *value != "true" && *value != "false")
+    {
+        return (
+            StatusCode::BAD_REQUEST,
+            format!("Invalid error tag {value}, expect true or false"),
+        )
+            .into_response();
+    }
+    Json(JaegerData(filter_traces(&tenant, parameters, tags).await)).into_response()
 }
 
 #[tracing::instrument]
-pub(super) async fn services(
-    Extension(memory_store): Extension<Arc<RwLock<MemoryStore>>>,
-) -> impl IntoResponse {
-    let memory_store = memory_store.read();
+pub(super) async fn services(CurrentTenant(tenant): CurrentTenant) -> impl IntoResponse {
+    let memory_store = tenant.memory_store.read();
     Json(JaegerData(memory_store.service_names()))
 }
 
 #[tracing::instrument]
 pub(super) async fn operations(
     Path(service): Path<String>,
-    Extension(memory_store): Extension<Arc<RwLock<MemoryStore>>>,
+    CurrentTenant(tenant): CurrentTenant,
 ) -> impl IntoResponse {
-    Json(JaegerData(
-        aggregate_span_names(memory_store, &service).await,
-    ))
+    Json(JaegerData(aggregate_span_names(&tenant, &service).await))
 }
 
 #[tracing::instrument]
 pub(super) async fn get_by_id(
     Path(id): Path<String>,
-    Extension(memory_store): Extension<Arc<RwLock<MemoryStore>>>,
+    CurrentTenant(tenant): CurrentTenant,
 ) -> impl IntoResponse {
     let trace_id = id.parse::<u64>().ok();
     match trace_id {
         Some(trace_id) => {
-            if let Some(trace) = get_trace_by_id(memory_store, trace_id).await {
+            if let Some(trace) = get_trace_by_id(&tenant, trace_id).await {
                 Json(JaegerData(vec![trace])).into_response()
             } else {
                 Json(JaegerData(Vec::<TraceExt>::new())).into_response()
@@ -75,3 +129,92 @@ pub(super) async fn get_by_id(
         None => (StatusCode::NOT_FOUND, format!("trace {} not found", id)).into_response(),
     }
 }
+
+#[tracing::instrument]
+pub(super) async fn dependency_links(
+    Query(p): Query<DependencyParameters>,
+    CurrentTenant(tenant): CurrentTenant,
+) -> impl IntoResponse {
+    let end = p
+        .end_ts
+        .and_then(|ms| OffsetDateTime::from_unix_timestamp_nanos(ms as i128 * 1_000_000).ok())
+        .unwrap_or_else(OffsetDateTime::now_utc);
+    let lookback = p
+        .lookback
+        .map(Duration::milliseconds)
+        .unwrap_or(DEFAULT_LOOKBACK);
+    Json(JaegerData(
+        dependencies(&tenant, end - lookback, end, p.on_demand).await,
+    ))
+}
+
+/// Where the time of the trace went: the self time of the spans,
+/// the critical path and the time per operation and service.
+#[tracing::instrument]
+pub(super) async fn analyze(
+    Path(id): Path<String>,
+    CurrentTenant(tenant): CurrentTenant,
+) -> impl IntoResponse {
+    let trace = match id.parse::<u64>() {
+        Ok(trace_id) => get_trace_by_id(&tenant, trace_id).await,
+        Err(_) => None,
+    };
+    match trace {
+        Some(trace) => Json(analysis::analyze(&trace)).into_response(),
+        None => (StatusCode::NOT_FOUND, format!("trace {} not found", id)).into_response(),
+    }
+}
+
+/// Compare the other trace to the trace, such as a slow execution to a fast one.
+#[tracing::instrument]
+pub(super) async fn diff(
+    Path((id, other_id)): Path<(String, String)>,
+    CurrentTenant(tenant): CurrentTenant,
+) -> impl IntoResponse {
+    let mut traces = Vec::with_capacity(2);
+    for id in [&id, &other_id] {
+        let trace = match id.parse::<u64>() {
+            Ok(trace_id) => get_trace_by_id(&tenant, trace_id).await,
+            Err(_) => None,
+        };
+        match trace {
+            Some(trace) => traces.push(trace),
+            None => {
+                return (StatusCode::NOT_FOUND, format!("trace {} not found", id)).into_response()
+            }
+        }
+    }
+    Json(diff::diff(&traces[0], &traces[1])).into_response()
+}
+
+/// The call tree merged from the spans of the operation within the window.
+#[tracing::instrument]
+pub(super) async fn flame_graph(
+    Query(p): Query<FlameGraphParameters>,
+    CurrentTenant(tenant): CurrentTenant,
+) -> Response {
+    let folded = match p.format.as_deref() {
+        None | Some("tree") => false,
+        Some("folded") => true,
+        Some(format) => {
+            return (StatusCode::BAD_REQUEST, format!("Unknown format {format}")).into_response()
+        }
+    };
+    let end = p.end.unwrap_or_else(OffsetDateTime::now_utc);
+    let start = p.start.unwrap_or(end - DEFAULT_FLAME_GRAPH_WINDOW);
+    let limit = p.limit.unwrap_or(DEFAULT_FLAME_GRAPH_LIMIT);
+    let Some(root) =
+        aggregate_call_tree(&tenant, &p.service, &p.operation, start, end, limit).await
+    else {
+        return (
+            StatusCode::NOT_FOUND,
+            format!("No span of {} {} in the window", p.service, p.operation),
+        )
+            .into_response();
+    };
+    if folded {
+        flamegraph::folded_stacks(&root).into_response()
+    } else {
+        Json(root).into_response()
+    }
+}
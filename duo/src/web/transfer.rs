@@ -0,0 +1,815 @@
+use std::collections::{BTreeMap, HashMap};
+use std::mem;
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Result};
+use axum::body::Bytes;
+use axum::extract::{Path, Query};
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+use time::{Duration, OffsetDateTime};
+use tracing::Level;
+
+use crate::aggregator::RUNNING_TAG;
+use crate::arrow::{convert_log_to_record_batch, convert_span_to_record_batch};
+use crate::dependency::{self, SpanNode};
+use crate::memory::new_process_id;
+use crate::partition::PartitionWriter;
+use crate::tenant::Tenant;
+use crate::{registry, schema, Log, Process, Span, TraceExt};
+
+use super::services::get_trace_by_id;
+use super::{logs, otlp, CurrentTenant, JaegerData};
+
+/// The maximum size of an imported file.
+pub(super) const IMPORT_BODY_LIMIT: usize = 64 * 1024 * 1024;
+/// The maximum number of logs exported, unless limited by the query.
+const EXPORT_LOG_LIMIT: usize = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Jaeger,
+    Otlp,
+    Ndjson,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "jaeger" => Ok(Format::Jaeger),
+            "otlp" => Ok(Format::Otlp),
+            "ndjson" => Ok(Format::Ndjson),
+            _ => Err(format!("Unknown format {s}, expect jaeger, otlp or ndjson")),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct FormatParameter {
+    format: Option<String>,
+}
+
+impl FormatParameter {
+    fn format(&self, default: Option<Format>) -> Result<Option<Format>, String> {
+        match &self.format {
+            Some(format) => format.parse().map(Some),
+            None => Ok(default),
+        }
+    }
+}
+
+/// A line of the NDJSON files, the processes come before their spans and logs.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum Record {
+    Process(Process),
+    Span(SpanRecord),
+    Log(Log),
+}
+
+/// The span without the correlated logs, the times are in microseconds.
+#[derive(Serialize, Deserialize)]
+struct SpanRecord {
+    id: u64,
+    trace_id: u64,
+    parent_id: Option<u64>,
+    process_id: String,
+    name: String,
+    start: i64,
+    end: Option<i64>,
+    #[serde(default)]
+    tags: HashMap<String, JsonValue>,
+}
+
+impl From<&Span> for SpanRecord {
+    fn from(span: &Span) -> Self {
+        SpanRecord {
+            id: span.id,
+            trace_id: span.trace_id,
+            parent_id: span.parent_id,
+            process_id: span.process_id.clone(),
+            name: span.name.clone(),
+            start: span.start_as_micros(),
+            end: span.end_as_micros(),
+            tags: span.tags.clone(),
+        }
+    }
+}
+
+impl TryFrom<SpanRecord> for Span {
+    type Error = anyhow::Error;
+
+    fn try_from(record: SpanRecord) -> Result<Self> {
+        Ok(Span {
+            id: record.id,
+            trace_id: record.trace_id,
+            parent_id: record.parent_id,
+            process_id: record.process_id,
+            name: record.name,
+            start: from_micros(record.start)?,
+            end: record.end.map(from_micros).transpose()?,
+            tags: record.tags,
+            logs: Vec::new(),
+        })
+    }
+}
+
+fn from_micros(micros: i64) -> Result<OffsetDateTime> {
+    Ok(OffsetDateTime::from_unix_timestamp_nanos(
+        micros as i128 * 1000,
+    )?)
+}
+
+/// The data of an imported file.
+#[derive(Default)]
+pub(super) struct Imported {
+    processes: HashMap<String, Process>,
+    pub spans: Vec<Span>,
+    pub logs: Vec<Log>,
+}
+
+impl Imported {
+    /// Add the process unless known, the id is generated if absent.
+    /// Return the process id.
+    pub fn add_process(
+        &mut self,
+        id: Option<String>,
+        service_name: String,
+        tags: HashMap<String, JsonValue>,
+    ) -> String {
+        let id = id.unwrap_or_else(|| new_process_id(&service_name));
+        self.processes.entry(id.clone()).or_insert_with(|| Process {
+            id: id.clone(),
+            service_name,
+            tags,
+            start_time: None,
+            last_seen: None,
+            stop_time: None,
+        });
+        id
+    }
+
+    /// Give the processes and traces fresh ids, the ids in the file may be
+    /// of the tenant's own processes and traces, such as exported from it.
+    /// Return the new id of each trace.
+    fn remap_ids(&mut self) -> BTreeMap<u64, u64> {
+        let mut ids = HashMap::with_capacity(self.processes.len());
+        self.processes = mem::take(&mut self.processes)
+            .into_values()
+            .map(|mut process| {
+                let id = new_process_id(&process.service_name);
+                ids.insert(mem::replace(&mut process.id, id.clone()), id.clone());
+                (id, process)
+            })
+            .collect();
+
+        let remap = |process_id: &mut String| {
+            if let Some(id) = ids.get(process_id) {
+                process_id.clone_from(id);
+            }
+        };
+        let mut trace_ids = BTreeMap::new();
+        let mut remap_trace = |trace_id: &mut u64| {
+            *trace_id = *trace_ids
+                .entry(*trace_id)
+                .or_insert_with(rand::random::<u64>);
+        };
+        for span in &mut self.spans {
+            remap(&mut span.process_id);
+            remap_trace(&mut span.trace_id);
+        }
+        for log in &mut self.logs {
+            remap(&mut log.process_id);
+            if let Some(trace_id) = &mut log.trace_id {
+                remap_trace(trace_id);
+            }
+        }
+        trace_ids
+    }
+
+    /// Fail if the type of a log field conflicts with the tenant's.
+    fn check_log_fields(&self, tenant: &str) -> Result<()> {
+        if !self.logs.is_empty() {
+            convert_log_to_record_batch(tenant, self.logs.clone())?;
+        }
+        Ok(())
+    }
+
+    fn parse(data: &[u8], format: Option<Format>) -> Result<Self> {
+        let format = match format {
+            Some(format) => format,
+            None => detect_format(data),
+        };
+        let mut imported = Imported::default();
+        match format {
+            Format::Jaeger => imported.parse_jaeger(&serde_json::from_slice(data)?)?,
+            Format::Otlp => otlp::decode(&serde_json::from_slice(data)?, &mut imported)?,
+            Format::Ndjson => imported.parse_ndjson(data)?,
+        }
+        Ok(imported)
+    }
+
+    fn parse_ndjson(&mut self, data: &[u8]) -> Result<()> {
+        for (n, line) in data.split(|&b| b == b'\n').enumerate() {
+            if line.iter().all(u8::is_ascii_whitespace) {
+                continue;
+            }
+            let record = serde_json::from_slice::<Record>(line)
+                .with_context(|| format!("Invalid NDJSON line {}", n + 1))?;
+            match record {
+                Record::Process(process) => {
+                    self.processes.insert(process.id.clone(), process);
+                }
+                Record::Span(span) => self.spans.push(span.try_into()?),
+                Record::Log(log) => self.logs.push(log),
+            }
+        }
+        Ok(())
+    }
+
+    /// Parse the Jaeger JSON, as served by the Jaeger API and exported by
+    /// Jaeger UI. The ids are decimal in duo's files, hex in Jaeger's.
+    fn parse_jaeger(&mut self, value: &JsonValue) -> Result<()> {
+        let Some(traces) = value.get("data").and_then(JsonValue::as_array) else {
+            bail!("Invalid Jaeger JSON: data missing");
+        };
+        for trace in traces {
+            let empty = serde_json::Map::new();
+            let processes = trace
+                .get("processes")
+                .and_then(JsonValue::as_object)
+                .unwrap_or(&empty);
+            // Only duo has the id in the processes.
+            let from_duo = processes
+                .values()
+                .any(|process| process.get("id").is_some());
+            let parse_id = |value: Option<&JsonValue>| -> Result<Option<u64>> {
+                let Some(id) = value.and_then(JsonValue::as_str) else {
+                    return Ok(None);
+                };
+                let id = if from_duo {
+                    id.parse::<u64>()?
+                } else {
+                    u128::from_str_radix(id, 16)? as u64
+                };
+                Ok(Some(id))
+            };
+
+            let mut process_ids = HashMap::new();
+            for (key, process) in processes {
+                let id = process
+                    .get("id")
+                    .and_then(JsonValue::as_str)
+                    .map(String::from);
+                let service_name = process
+                    .get("serviceName")
+                    .and_then(JsonValue::as_str)
+                    .unwrap_or("unknown_service")
+                    .to_string();
+                let tags = jaeger_fields(process.get("tags"));
+                process_ids.insert(key.as_str(), self.add_process(id, service_name, tags));
+            }
+
+            for span in trace
+                .get("spans")
+                .and_then(JsonValue::as_array)
+                .into_iter()
+                .flatten()
+            {
+                let span = self
+                    .parse_jaeger_span(span, &process_ids, from_duo, &parse_id)
+                    .with_context(|| format!("Invalid Jaeger span: {span}"))?;
+                self.spans.push(span);
+            }
+        }
+        Ok(())
+    }
+
+    fn parse_jaeger_span(
+        &mut self,
+        value: &JsonValue,
+        process_ids: &HashMap<&str, String>,
+        from_duo: bool,
+        parse_id: &impl Fn(Option<&JsonValue>) -> Result<Option<u64>>,
+    ) -> Result<Span> {
+        let trace_id = parse_id(value.get("traceID"))?.context("traceID missing")?;
+        let id = parse_id(value.get("spanID"))?.context("spanID missing")?;
+        let parent_id = match value
+            .get("references")
+            .and_then(JsonValue::as_array)
+            .and_then(|references| references.first())
+        {
+            Some(reference) => parse_id(reference.get("spanID"))?,
+            None => None,
+        };
+        let process_id = value
+            .get("processID")
+            .and_then(JsonValue::as_str)
+            .and_then(|id| process_ids.get(id))
+            .context("processID unknown")?
+            .clone();
+        let start = value
+            .get("startTime")
+            .and_then(JsonValue::as_i64)
+            .context("startTime missing")?;
+        let duration = value
+            .get("duration")
+            .and_then(JsonValue::as_i64)
+            .unwrap_or_default();
+        let mut name = value
+            .get("operationName")
+            .and_then(JsonValue::as_str)
+            .unwrap_or_default()
+            .to_string();
+        let tags = jaeger_fields(value.get("tags"));
+        // duo marks the unfinished spans by a `*` suffix,
+        // except the running ones which have the duration so far.
+        let unfinished =
+            from_duo && ((duration == 0 && name.ends_with('*')) || tags.contains_key(RUNNING_TAG));
+        if unfinished && name.ends_with('*') {
+            name.pop();
+        }
+
+        for log in value
+            .get("logs")
+            .and_then(JsonValue::as_array)
+            .into_iter()
+            .flatten()
+        {
+            let mut fields = jaeger_fields(log.get("fields"));
+            let mut take_str = |key: &str| match fields.remove(key) {
+                Some(JsonValue::String(value)) => Some(value),
+                Some(value) => Some(value.to_string()),
+                None => None,
+            };
+            let level = take_str("level")
+                .and_then(|level| Level::from_str(&level).ok())
+                .unwrap_or(Level::INFO);
+            let message = take_str("message").unwrap_or_default();
+            let target = take_str("target").unwrap_or_default();
+            // duo exports the file and line as `file:line`.
+            let (file, line) = match take_str("file") {
+                Some(file) => match file.rsplit_once(':') {
+                    Some((file, line)) if line.parse::<u32>().is_ok() => {
+                        (Some(file.to_string()), line.parse().ok())
+                    }
+                    _ => (Some(file), None),
+                },
+                None => (None, None),
+            };
+            self.logs.push(Log {
+                process_id: process_id.clone(),
+                span_id: Some(id),
+                trace_id: Some(trace_id),
+                level,
+                target,
+                file,
+                line,
+                time: from_micros(
+                    log.get("timestamp")
+                        .and_then(JsonValue::as_i64)
+                        .context("log timestamp missing")?,
+                )?,
+                message,
+                fields,
+            });
+        }
+
+        let start = from_micros(start)?;
+        Ok(Span {
+            id,
+            trace_id,
+            parent_id,
+            process_id,
+            name,
+            start,
+            end: (!unfinished).then(|| start + Duration::microseconds(duration)),
+            tags,
+            logs: Vec::new(),
+        })
+    }
+
+    /// Load the data into the tenant. In the memory mode it's merged into the
+    /// memory store, otherwise it's written to the partitions of its own time,
+    /// so the searches by time find it.
+    async fn store(self, tenant: &Tenant) -> Result<()> {
+        let processes = self.processes.into_values().collect::<Vec<_>>();
+        if !crate::is_memory_mode() {
+            for process in &processes {
+                registry::save_process(&tenant.id, process).await?;
+            }
+        }
+        tenant.memory_store.write().merge_processes(processes);
+
+        if crate::is_memory_mode() {
+            let mut memory_store = tenant.memory_store.write();
+            if !self.spans.is_empty() {
+                memory_store.merge_spans(self.spans);
+            }
+            if !self.logs.is_empty() {
+                memory_store.merge_logs(self.logs)?;
+            }
+            return Ok(());
+        }
+
+        if let Some(earliest) = self.spans.iter().map(|span| span.start).min() {
+            let nodes = self
+                .spans
+                .iter()
+                .map(|span| SpanNode {
+                    id: span.id,
+                    trace_id: span.trace_id,
+                    parent_id: span.parent_id,
+                    process_id: span.process_id.clone(),
+                })
+                .collect::<Vec<_>>();
+            let processes = { tenant.memory_store.read().processes() };
+            let links = dependency::service_links(dependency::process_calls(&nodes), &processes);
+            if !links.is_empty() {
+                PartitionWriter::at(&tenant.id, earliest)?
+                    .write_partition(
+                        "dependency",
+                        &[dependency::convert_links_to_record_batch(&links)?],
+                    )
+                    .await?;
+            }
+        }
+        for (minute, spans) in group_by_minute(self.spans, |span| span.start) {
+            let batch = convert_span_to_record_batch(spans)?;
+            PartitionWriter::at(&tenant.id, minute)?
+                .write_partition("span", &[batch])
+                .await?;
+        }
+        let mut has_logs = false;
+        for (minute, logs) in group_by_minute(self.logs, |log| log.time) {
+            let batch = convert_log_to_record_batch(&tenant.id, logs)?;
+            schema::merge_log_schema(&tenant.id, batch.schema())?;
+            PartitionWriter::at(&tenant.id, minute)?
+                .write_partition("log", &[batch])
+                .await?;
+            has_logs = true;
+        }
+        if has_logs {
+            schema::persit_log_schema(&tenant.id).await?;
+        }
+        Ok(())
+    }
+}
+
+fn detect_format(data: &[u8]) -> Format {
+    // A JSON document of the other formats spans multiple lines.
+    match serde_json::from_slice::<JsonValue>(data) {
+        Ok(value) if value.get("data").is_some() => Format::Jaeger,
+        Ok(value)
+            if value.get("resourceSpans").is_some() || value.get("resourceLogs").is_some() =>
+        {
+            Format::Otlp
+        }
+        _ => Format::Ndjson,
+    }
+}
+
+fn group_by_minute<T>(
+    items: Vec<T>,
+    time: impl Fn(&T) -> OffsetDateTime,
+) -> Vec<(OffsetDateTime, Vec<T>)> {
+    let mut minutes = BTreeMap::<i64, Vec<T>>::new();
+    for item in items {
+        minutes
+            .entry(time(&item).unix_timestamp().div_euclid(60))
+            .or_default()
+            .push(item);
+    }
+    minutes
+        .into_iter()
+        .filter_map(|(minute, items)| {
+            Some((
+                OffsetDateTime::from_unix_timestamp(minute * 60).ok()?,
+                items,
+            ))
+        })
+        .collect()
+}
+
+/// The Jaeger tags or log fields, `[{key, type, value}]`.
+fn jaeger_fields(value: Option<&JsonValue>) -> HashMap<String, JsonValue> {
+    value
+        .and_then(JsonValue::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(|field| {
+            let key = field.get("key")?.as_str()?;
+            Some((key.to_string(), field.get("value")?.clone()))
+        })
+        .collect()
+}
+
+fn ndjson(records: impl IntoIterator<Item = Record>) -> Result<Vec<u8>> {
+    let mut output = Vec::new();
+    for record in records {
+        serde_json::to_writer(&mut output, &record)?;
+        output.push(b'\n');
+    }
+    Ok(output)
+}
+
+/// The processes of the spans or logs, sorted by id.
+fn used_processes<'a>(
+    processes: &HashMap<String, Process>,
+    process_ids: impl Iterator<Item = &'a String>,
+) -> Vec<Process> {
+    process_ids
+        .filter_map(|id| Some((id, processes.get(id)?)))
+        .collect::<BTreeMap<_, _>>()
+        .into_values()
+        .cloned()
+        .collect()
+}
+
+fn download(data: Result<Vec<u8>>, format: Format, name: &str) -> Response {
+    let data = match data {
+        Ok(data) => data,
+        Err(err) => return (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+    };
+    let (content_type, extension) = match format {
+        Format::Ndjson => ("application/x-ndjson", "ndjson"),
+        Format::Jaeger | Format::Otlp => ("application/json", "json"),
+    };
+    (
+        [
+            (header::CONTENT_TYPE, content_type.to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{name}.{extension}\""),
+            ),
+        ],
+        data,
+    )
+        .into_response()
+}
+
+/// Download the trace with its correlated logs, in Jaeger JSON by default.
+#[tracing::instrument]
+pub(super) async fn export_trace(
+    Path(id): Path<String>,
+    Query(p): Query<FormatParameter>,
+    CurrentTenant(tenant): CurrentTenant,
+) -> Response {
+    let format = match p.format(Some(Format::Jaeger)) {
+        Ok(format) => format.unwrap_or(Format::Jaeger),
+        Err(err) => return (StatusCode::BAD_REQUEST, err).into_response(),
+    };
+    let trace = match id.parse::<u64>() {
+        Ok(trace_id) => get_trace_by_id(&tenant, trace_id).await,
+        Err(_) => None,
+    };
+    let Some(trace) = trace else {
+        return (StatusCode::NOT_FOUND, format!("trace {} not found", id)).into_response();
+    };
+
+    let data = match format {
+        Format::Jaeger => serde_json::to_vec(&JaegerData(vec![&trace])).map_err(Into::into),
+        Format::Otlp => serde_json::to_vec(&otlp::encode_trace(&trace)).map_err(Into::into),
+        Format::Ndjson => ndjson(trace_records(&trace)),
+    };
+    download(data, format, &format!("trace-{id}"))
+}
+
+fn trace_records(trace: &TraceExt) -> Vec<Record> {
+    let processes = used_processes(
+        &trace.processes,
+        trace.spans.iter().map(|span| &span.process_id),
+    );
+    let mut logs = trace
+        .spans
+        .iter()
+        .flat_map(|span| span.logs.iter().cloned())
+        .collect::<Vec<_>>();
+    logs.sort_by_key(|log| log.time);
+    processes
+        .into_iter()
+        .map(Record::Process)
+        .chain(
+            trace
+                .spans
+                .iter()
+                .map(|span| Record::Span(SpanRecord::from(span))),
+        )
+        .chain(logs.into_iter().map(Record::Log))
+        .collect()
+}
+
+/// Download the result of a log query, in NDJSON by default.
+#[tracing::instrument]
+pub(super) async fn export_logs(
+    Query(p): Query<logs::QueryParameters>,
+    Query(f): Query<FormatParameter>,
+    CurrentTenant(tenant): CurrentTenant,
+) -> Response {
+    let format = match f.format(Some(Format::Ndjson)) {
+        Ok(format) => format.unwrap_or(Format::Ndjson),
+        Err(err) => return (StatusCode::BAD_REQUEST, err).into_response(),
+    };
+    if format == Format::Jaeger {
+        return (
+            StatusCode::BAD_REQUEST,
+            "Jaeger JSON has no logs, use otlp or ndjson",
+        )
+            .into_response();
+    }
+
+    let logs = logs::query_logs(&tenant, &p, EXPORT_LOG_LIMIT).await;
+    let processes = { tenant.memory_store.read().processes() };
+    let data = match format {
+        Format::Otlp => {
+            serde_json::to_vec(&otlp::encode_logs(&logs, &processes)).map_err(Into::into)
+        }
+        _ => {
+            let used = used_processes(&processes, logs.iter().map(|log| &log.process_id));
+            ndjson(
+                used.into_iter()
+                    .map(Record::Process)
+                    .chain(logs.into_iter().map(Record::Log)),
+            )
+        }
+    };
+    download(data, format, "logs")
+}
+
+/// Load an exported file, the format is detected if not given.
+///
+/// The processes and traces get new ids, so an import never mixes with the
+/// tenant's own data, such as the trace it was exported from. The response
+/// maps the trace ids of the file to the new ones.
+#[tracing::instrument(skip(body))]
+pub(super) async fn import(
+    Query(p): Query<FormatParameter>,
+    CurrentTenant(tenant): CurrentTenant,
+    body: Bytes,
+) -> Response {
+    let format = match p.format(None) {
+        Ok(format) => format,
+        Err(err) => return (StatusCode::BAD_REQUEST, err).into_response(),
+    };
+    let mut imported = match Imported::parse(&body, format) {
+        Ok(imported) => imported,
+        Err(err) => return (StatusCode::BAD_REQUEST, format!("{err:#}")).into_response(),
+    };
+    if let Err(err) = imported.check_log_fields(&tenant.id) {
+        return (
+            StatusCode::BAD_REQUEST,
+            format!("Invalid log fields: {err:#}"),
+        )
+            .into_response();
+    }
+
+    let trace_ids = imported.remap_ids();
+    let summary = serde_json::json!({
+        "processes": imported.processes.len(),
+        "spans": imported.spans.len(),
+        "logs": imported.logs.len(),
+        "traces": trace_ids
+            .into_iter()
+            .map(|(old, new)| (old.to_string(), new.to_string()))
+            .collect::<BTreeMap<_, _>>(),
+    });
+    match imported.store(&tenant).await {
+        Ok(()) => Json(summary).into_response(),
+        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, format!("{err:#}")).into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn test_ndjson_round_trip() {
+        let data = concat!(
+            r#"{"type":"process","id":"api-1","serviceName":"api","tags":{}}"#,
+            "\n",
+            r#"{"type":"span","id":2,"trace_id":1,"parent_id":null,"process_id":"api-1","name":"get","start":10,"end":null}"#,
+            "\n\n",
+        );
+        let imported = Imported::parse(data.as_bytes(), None).unwrap();
+        assert_eq!(imported.processes["api-1"].service_name, "api");
+        assert_eq!(imported.spans[0].start_as_micros(), 10);
+        assert!(imported.spans[0].end.is_none());
+
+        let output = ndjson(
+            imported
+                .spans
+                .iter()
+                .map(|span| Record::Span(SpanRecord::from(span))),
+        )
+        .unwrap();
+        let line = serde_json::from_slice::<JsonValue>(&output).unwrap();
+        assert_eq!(line["type"], "span");
+        assert_eq!(line["start"], 10);
+    }
+
+    #[test]
+    fn test_remap_ids() {
+        let data = concat!(
+            r#"{"type":"process","id":"api-1","serviceName":"api","tags":{}}"#,
+            "\n",
+            r#"{"type":"span","id":2,"trace_id":1,"parent_id":null,"process_id":"api-1","name":"get","start":10,"end":20}"#,
+            "\n",
+            r#"{"type":"log","process_id":"api-1","trace_id":1,"time":15,"level":"INFO","target":"api","message":"hi"}"#,
+        );
+        let mut imported = Imported::parse(data.as_bytes(), None).unwrap();
+        let trace_ids = imported.remap_ids();
+
+        let id = imported.processes.keys().next().unwrap().clone();
+        assert_ne!(id, "api-1");
+        assert!(id.starts_with("api-"));
+        assert_eq!(imported.processes[&id].id, id);
+        assert_eq!(imported.spans[0].process_id, id);
+        assert_eq!(imported.logs[0].process_id, id);
+        assert_ne!(trace_ids[&1], 1);
+        assert_eq!(imported.spans[0].trace_id, trace_ids[&1]);
+        assert_eq!(imported.logs[0].trace_id, Some(trace_ids[&1]));
+    }
+
+    #[test]
+    fn test_jaeger_round_trip_running_span() {
+        let start = OffsetDateTime::now_utc() - Duration::seconds(1);
+        let span = Span {
+            start,
+            tags: HashMap::from([(RUNNING_TAG.to_string(), JsonValue::Bool(true))]),
+            ..testing::span(2, None, 0, None).named("get").by("api-1")
+        };
+        let mut imported = Imported::default();
+        imported.add_process(
+            Some(String::from("api-1")),
+            String::from("api"),
+            HashMap::new(),
+        );
+        let trace = TraceExt {
+            trace_id: 1,
+            spans: vec![span],
+            processes: imported.processes,
+        };
+
+        let data = serde_json::to_vec(&JaegerData(vec![&trace])).unwrap();
+        let value = serde_json::from_slice::<JsonValue>(&data).unwrap();
+        // Served with the duration so far.
+        assert_eq!(value["data"][0]["spans"][0]["operationName"], "get");
+        assert!(value["data"][0]["spans"][0]["duration"].as_i64().unwrap() >= 1_000_000);
+
+        let imported = Imported::parse(&data, Some(Format::Jaeger)).unwrap();
+        assert_eq!(imported.spans[0].name, "get");
+        assert!(imported.spans[0].is_running());
+    }
+
+    #[test]
+    fn test_parse_jaeger() {
+        let data = json!({
+            "data": [{
+                "traceID": "00000000000000ff",
+                "spans": [{
+                    "traceID": "00000000000000ff",
+                    "spanID": "0a",
+                    "references": [{"refType": "CHILD_OF", "traceID": "ff", "spanID": "01"}],
+                    "operationName": "query",
+                    "startTime": 100,
+                    "duration": 20,
+                    "tags": [{"key": "rows", "type": "int64", "value": 3}],
+                    "logs": [{
+                        "timestamp": 110,
+                        "fields": [
+                            {"key": "message", "type": "string", "value": "done"},
+                            {"key": "level", "type": "string", "value": "WARN"},
+                            {"key": "file", "type": "string", "value": "src/db.rs:42"},
+                        ],
+                    }],
+                    "processID": "p1",
+                }],
+                "processes": {"p1": {"serviceName": "db", "tags": []}},
+            }],
+        });
+        let imported =
+            Imported::parse(&serde_json::to_vec(&data).unwrap(), Some(Format::Jaeger)).unwrap();
+        let span = &imported.spans[0];
+        assert_eq!((span.trace_id, span.id, span.parent_id), (255, 10, Some(1)));
+        assert_eq!(span.end_as_micros(), Some(120));
+        assert_eq!(span.tags["rows"], json!(3));
+        assert_eq!(imported.processes[&span.process_id].service_name, "db");
+
+        let log = &imported.logs[0];
+        assert_eq!((log.level, log.message.as_str()), (Level::WARN, "done"));
+        assert_eq!(
+            (log.file.as_deref(), log.line),
+            (Some("src/db.rs"), Some(42))
+        );
+        assert_eq!(log.span_id, Some(10));
+    }
+}